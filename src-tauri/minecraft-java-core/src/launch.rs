@@ -2,60 +2,231 @@ mod minecraft;
 mod downloader;
 mod utils;
 mod loaders;
+mod instances;
+mod http;
+mod endpoints;
+mod auth;
+mod accounts;
+mod vanilla;
+mod config;
+mod history;
+#[cfg(feature = "progress-bar")]
+mod progress;
 
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use minecraft::java::get_java_files;
-use minecraft::libraries::{get_assets, get_libraries, get_natives};
-use crate::launch::downloader::download_multiple_files;
-use crate::launch::loaders::Loader;
-use crate::launch::minecraft::arguments::{ArgumentsOptions, get_arguments, JvmMemory};
-use crate::launch::minecraft::assets::get_game_assets;
-use crate::launch::minecraft::bundle::check_bundle;
-use crate::launch::minecraft::java::JavaFilesMetadata;
-use crate::launch::minecraft::json::PackageInfo;
+use std::time::{Duration, Instant};
+use minecraft::java::{check_java_compatibility, get_java_files};
+use minecraft::libraries::{get_assets, get_libraries, get_natives, get_server_jar, server_jar_path};
+use crate::launch::downloader::{download_multiple_files, DownloadMultipleFilesOptions, file_exists_and_matches_sha1};
+use crate::launch::loaders::{find_installed, install, merge_loader_profile, runs_installer};
+use crate::launch::minecraft::arguments::{ArgumentsOptions, get_arguments, get_dock_icon, JvmMemory, merge_jvm_arguments, validate_arguments};
+use crate::launch::minecraft::assets::{AssetFilter, AssetsMetadataOptions, get_game_assets};
+use crate::launch::downloader::FileDownloadMetadata;
+use crate::launch::minecraft::bundle::{check_bundle, diff_bundle, export_bundle, read_bundle};
+use crate::launch::minecraft::java::{JavaFilesMetadata, JavaMetadataOptions};
+use crate::launch::minecraft::json::{InfoMetadata, PackageInfo, VersionMetadataOptions};
+use crate::launch::utils::{get_default_memory, get_os_name};
+pub use crate::launch::utils::natives_dir;
+pub use crate::launch::vanilla::{import_vanilla, ImportReport};
+pub use crate::launch::downloader::ProgressCallback;
+#[cfg(feature = "progress-bar")]
+pub use crate::launch::progress::progress_bar;
+pub use crate::launch::endpoints::Endpoints;
+pub use crate::launch::http::{HttpConfig, RetryPolicy, set_http_config};
+pub use crate::launch::instances::{InstanceHandle, InstanceKind, is_running, kill_instance, running_instances, stop_instance};
+pub use crate::launch::loaders::{install as install_loader, InstalledLoader, Loader, LoaderError};
+pub use crate::launch::accounts::AccountStore;
+pub use crate::launch::config::LauncherConfig;
+pub use crate::launch::history::{get_play_history, PlayRecord};
+pub use crate::launch::auth::{Authenticator, check_entitlement, forget_profile_textures, get_profile_textures, Meta, NoProfile, NotEntitled, ProfileTextures, refresh, Texture, validate, XboxAccount};
+pub use crate::launch::minecraft::arguments::{MalformedArguments, ServerAddress};
+pub use crate::launch::minecraft::java::{InstalledRuntime, JavaRuntimeUnavailable, list_installed_runtimes, resolve_java_executable, RuntimeScope};
+pub use crate::launch::minecraft::json::{ArtifactDownload, get_version_list, VersionInfo, VersionNotFound};
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug, Clone)]
 pub struct Java {
-    pub(crate) path: Option<PathBuf>,
-    pub(crate) version: Option<String>,
-    pub(crate) type_: Option<String>,
+    pub path: Option<PathBuf>,
+    pub version: Option<String>,
+    pub type_: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Screen {
-    pub(crate) width: Option<u32>,
-    pub(crate) height: Option<u32>,
-    pub(crate) fullscreen: Option<bool>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fullscreen: Option<bool>,
     // pub(crate) resizable: Option<bool>,
     // pub(crate) title: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Memory {
-    pub(crate) min: Option<String>,
-    pub(crate) max: Option<String>,
+    pub min: Option<String>,
+    pub max: Option<String>,
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        let (min, max) = get_default_memory();
+        Self {
+            min: Some(format!("{}M", min)),
+            max: Some(format!("{}M", max)),
+        }
+    }
+}
+
+/// Named set of JVM flags, merged over the default ones
 #[derive(Debug, Clone)]
+pub struct ArgProfile {
+    pub name: String,
+    pub jvm_arguments: Vec<String>,
+}
+
+/// Called with the instance name once the game window is created
+pub type WindowReadyCallback = Arc<dyn Fn(&str) + Send + Sync>;
+/// Called for every line the game writes, as soon as it is read
+pub type LogCallback = Arc<dyn Fn(LogLine) + Send + Sync>;
+/// Called with the account once its expired session was refreshed before the launch
+pub type SessionRefreshedCallback = Arc<dyn Fn(&Authenticator) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub instance: String,
+    pub stream: LogStream,
+    pub line: String,
+}
+
+#[derive(Clone)]
 pub struct LaunchMetadata {
-    pub(crate) path: PathBuf,
-    pub(crate) version: String,
-    pub(crate) instance_name: Option<String>,
-    pub(crate) loader: Option<Loader>,
-    pub(crate) java: Option<Java>,
-    pub(crate) screen: Option<Screen>,
-    pub(crate) memory: Memory,
+    /// Install folder, anywhere on disk, relative paths are resolved from the working directory
+    pub path: PathBuf,
+    /// Holds saves and configs and is used as working directory, the instance path when unset
+    pub game_directory: Option<PathBuf>,
+    /// Overrides `--assetsDir`, the instance `assets` folder (`resources` for legacy versions) when unset
+    pub assets_directory: Option<PathBuf>,
+    pub version: String,
+    pub instance_name: Option<String>,
+    /// Server instances run the dedicated server jar without a session and are stopped through their console
+    pub kind: InstanceKind,
+    /// Session used by the game, an offline account is used when unset
+    pub authenticator: Option<Authenticator>,
+    pub loader: Option<Loader>,
+    pub java: Option<Java>,
+    /// Downloads the java runtime for this instance only instead of sharing it
+    pub runtime_scope: RuntimeScope,
+    pub screen: Option<Screen>,
+    pub memory: Memory,
+    pub arg_profile: Option<ArgProfile>,
+    /// Patched jars keyed by maven coordinate, e.g. a fixed `org.apache.logging.log4j:log4j-core:2.8.1`
+    pub artifact_overrides: HashMap<String, ArtifactDownload>,
+    /// Extra JVM flags, overriding the default and profile ones sharing the same key
+    pub jvm_arguments: Option<String>,
+    /// Jars appended to the class path after the libraries and the client jar, e.g. a profiler
+    pub extra_classpath: Vec<PathBuf>,
+    /// Java agents with their options, each one passed as `-javaagent:<path>=<options>`
+    pub agents: Vec<(PathBuf, Option<String>)>,
+    /// Started instead of the main class of the version or its loader, for custom client distributions
+    pub main_class_override: Option<String>,
+    /// Server joined once the game is loaded, see `connect_to`
+    pub server: Option<ServerAddress>,
+    /// Launches with `java.exe` instead of `javaw.exe` on Windows, keeping a console for debugging
+    pub show_console: bool,
+    /// Fails the launch with `StartupTimedOut` when the game hasn't started within this delay, the instance is killed
+    pub startup_timeout: Option<Duration>,
+    /// Game output lines marking the window creation, they vary between versions
+    pub window_ready_patterns: Vec<String>,
+    pub on_window_ready: Option<WindowReadyCallback>,
+    pub on_log: Option<LogCallback>,
+    /// Called with the downloaded and total bytes while the game files are downloaded
+    pub on_progress: Option<ProgressCallback>,
+    /// Microsoft rotates the refresh token on every refresh, the account has to be stored again or its next refresh fails
+    pub on_session_refreshed: Option<SessionRefreshedCallback>,
+    /// Adds the G1GC tuning flags, disable it to pick another collector through `jvm_arguments`
+    pub use_default_gc_flags: bool,
+    pub endpoints: Endpoints,
+    pub max_concurrent_downloads: usize,
+    pub reqwest_timeout: Duration,
+    /// Wipes the natives folder before extracting them again
+    pub clean_natives: bool,
+    /// Runs from the instance path and only passes paths relative to it, so the whole folder can be moved
+    pub portable: bool,
+    /// Only downloads the asset index, objects are fetched on demand with `download_assets`.
+    /// The game starts quicker but has no sounds, music or other languages until they are fetched
+    pub lazy_assets: bool,
+    /// Hashes the asset objects already on disk, when false their presence and size are enough
+    pub verify_assets: bool,
+    /// Keeps the server `Last-Modified` time on the downloaded files, for sync tools comparing mtimes
+    pub preserve_timestamps: bool,
+    /// Lists the installed files in `versions/<id>/download_manifest.json`, `uninstall_loader` needs it
+    pub export_manifest: bool,
+}
+
+// The callbacks can't be printed and are left out
+impl fmt::Debug for LaunchMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LaunchMetadata")
+            .field("path", &self.path)
+            .field("game_directory", &self.game_directory)
+            .field("assets_directory", &self.assets_directory)
+            .field("version", &self.version)
+            .field("instance_name", &self.instance_name)
+            .field("kind", &self.kind)
+            .field("authenticator", &self.authenticator)
+            .field("loader", &self.loader)
+            .field("java", &self.java)
+            .field("runtime_scope", &self.runtime_scope)
+            .field("screen", &self.screen)
+            .field("memory", &self.memory)
+            .field("arg_profile", &self.arg_profile)
+            .field("artifact_overrides", &self.artifact_overrides)
+            .field("jvm_arguments", &self.jvm_arguments)
+            .field("extra_classpath", &self.extra_classpath)
+            .field("agents", &self.agents)
+            .field("main_class_override", &self.main_class_override)
+            .field("server", &self.server)
+            .field("show_console", &self.show_console)
+            .field("startup_timeout", &self.startup_timeout)
+            .field("window_ready_patterns", &self.window_ready_patterns)
+            .field("use_default_gc_flags", &self.use_default_gc_flags)
+            .field("endpoints", &self.endpoints)
+            .field("max_concurrent_downloads", &self.max_concurrent_downloads)
+            .field("reqwest_timeout", &self.reqwest_timeout)
+            .field("clean_natives", &self.clean_natives)
+            .field("portable", &self.portable)
+            .field("lazy_assets", &self.lazy_assets)
+            .field("verify_assets", &self.verify_assets)
+            .field("preserve_timestamps", &self.preserve_timestamps)
+            .field("export_manifest", &self.export_manifest)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Default for LaunchMetadata {
     fn default() -> Self {
         Self {
             path: "instances".to_owned().parse().unwrap(),
+            game_directory: None,
+            assets_directory: None,
             version: "latest_release".to_owned(),
             instance_name: None,
+            kind: InstanceKind::Client,
+            authenticator: None,
             loader: {
                 Some(Loader {
                     type_: "neoforge".to_owned(),
@@ -72,30 +243,165 @@ impl Default for LaunchMetadata {
                     type_: Option::from("jre".to_owned()),
                 })
             },
+            runtime_scope: RuntimeScope::Global,
             screen: {
                 Some(Screen {
-                    width: Option::from(None),
-                    height: Option::from(None),
+                    width: None,
+                    height: None,
                     fullscreen: Option::from(false),
                 })
             },
-            memory: Memory {
-                min: Option::from("2G".to_owned()),
-                max: Option::from("4G".to_owned()),
-            },
+            memory: Memory::default(),
+            arg_profile: None,
+            artifact_overrides: HashMap::new(),
+            jvm_arguments: None,
+            extra_classpath: vec![],
+            agents: vec![],
+            main_class_override: None,
+            server: None,
+            show_console: false,
+            startup_timeout: None,
+            window_ready_patterns: vec![
+                "Backend library: LWJGL".to_string(),
+                "LWJGL Version:".to_string(),
+            ],
+            on_window_ready: None,
+            on_log: None,
+            on_progress: None,
+            on_session_refreshed: None,
+            use_default_gc_flags: true,
+            endpoints: Endpoints::default(),
+            max_concurrent_downloads: 75,
+            reqwest_timeout: Duration::from_secs(10),
+            clean_natives: true,
+            portable: false,
+            lazy_assets: false,
+            verify_assets: true,
+            preserve_timestamps: false,
+            export_manifest: false,
         }
     }
 }
 
-pub async fn launch_minecraft(mut options: Option<LaunchMetadata>) {
+impl LaunchMetadata {
+    /// Joins the server on startup, through Quick Play when the version supports it
+    pub fn connect_to(mut self, host: &str, port: u16) -> Self {
+        self.server = Some(ServerAddress {
+            host: host.to_string(),
+            port,
+        });
+        self
+    }
+}
+
+fn get_instance_path(options: &LaunchMetadata) -> PathBuf {
+    if options.path.is_absolute() {
+        return options.path.clone();
+    }
+    std::env::current_dir().unwrap().join(&options.path)
+}
+
+fn get_game_directory(path: &Path, options: &LaunchMetadata) -> PathBuf {
+    match &options.game_directory {
+        Some(directory) if directory.is_absolute() => directory.clone(),
+        Some(directory) => std::env::current_dir().unwrap().join(directory),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Path relative to the instance root, paths outside of it are kept as they are
+fn portable_path(root: &Path, target: PathBuf) -> PathBuf {
+    match target.strip_prefix(root) {
+        Ok(relative) if relative.as_os_str().is_empty() => PathBuf::from("."),
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => target,
+    }
+}
+
+pub async fn install_minecraft(mut options: Option<LaunchMetadata>) -> Result<(), Box<dyn Error>> {
+    options = options.or(Some(LaunchMetadata::default()));
+
+    let path = get_instance_path(options.as_ref().unwrap());
+    download_minecraft(&path, options.unwrap()).await?;
+    Ok(())
+}
+
+pub async fn launch_minecraft(mut options: Option<LaunchMetadata>) -> Result<InstanceHandle, Box<dyn Error>> {
     options = options.or(Some(LaunchMetadata::default()));
 
-    let mut path = std::env::current_dir().unwrap();
-    path.push("instances");
-    path.push("miratest2");
+    // A dedicated server doesn't log in
+    let authenticator = options.as_ref().unwrap().authenticator.clone().filter(|_| options.as_ref().unwrap().kind == InstanceKind::Client);
+    if let Some(cached) = authenticator {
+        let mut authenticator = auth::ensure_valid(cached.clone()).await?;
+        if authenticator.is_msa() {
+            authenticator = check_entitlement(authenticator).await?;
+        }
+        notify_session_refreshed(&cached, &authenticator, options.as_ref().unwrap().on_session_refreshed.as_ref());
+        options.as_mut().unwrap().authenticator = Some(authenticator);
+    }
+
+    let path = get_instance_path(options.as_ref().unwrap());
+    let data = download_minecraft(&path, options.clone().unwrap()).await?;
+    play_minecraft(&path, data, options.clone().unwrap()).await
+}
+
+/// Hands the account back when its tokens changed, the caller only holds the ones it launched with
+fn notify_session_refreshed(cached: &Authenticator, authenticator: &Authenticator, on_session_refreshed: Option<&SessionRefreshedCallback>) {
+    let refreshed = cached.access_token != authenticator.access_token || cached.refresh_token != authenticator.refresh_token;
+    if let (true, Some(on_session_refreshed)) = (refreshed, on_session_refreshed) {
+        on_session_refreshed(authenticator);
+    }
+}
 
-    let data = download_minecraft(&path, options.clone().unwrap()).await;
-    play_minecraft(&path, data, options.clone().unwrap()).await;
+/// Files the game can't start without that failed to download
+#[derive(Debug)]
+pub struct DownloadFailed {
+    pub files: Vec<String>,
+}
+
+impl fmt::Display for DownloadFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} required files failed to download: {}", self.files.len(), self.files.join(", "))
+    }
+}
+
+impl std::error::Error for DownloadFailed {}
+
+/// Only the asset objects can be missing, the game starts without a sound or a language
+fn check_downloads(failed: Vec<FileDownloadMetadata>) -> Result<(), DownloadFailed> {
+    let (optional, required): (Vec<FileDownloadMetadata>, Vec<FileDownloadMetadata>) = failed.into_iter()
+        .partition(|file| file.type_ == "Assets");
+    if !optional.is_empty() {
+        warn!("{} assets failed to download, they will be retried on the next launch", optional.len());
+    }
+    if required.is_empty() {
+        return Ok(());
+    }
+    Err(DownloadFailed { files: required.into_iter().map(|file| file.path).collect() })
+}
+
+fn get_download_options(options: &LaunchMetadata) -> DownloadMultipleFilesOptions {
+    DownloadMultipleFilesOptions {
+        reqwest_timeout: Some(options.reqwest_timeout),
+        max_concurrent_downloads: options.max_concurrent_downloads,
+        on_progress: options.on_progress.clone(),
+        verify_assets: options.verify_assets,
+        preserve_timestamps: options.preserve_timestamps,
+        ..Default::default()
+    }
+}
+
+fn get_java_options(options: &LaunchMetadata) -> JavaMetadataOptions {
+    JavaMetadataOptions {
+        reqwest_timeout: Some(options.reqwest_timeout),
+        component: options.java.as_ref().and_then(|java| java.version.clone()),
+        endpoints: options.endpoints.clone(),
+        show_console: options.show_console,
+        instance: match options.runtime_scope {
+            RuntimeScope::Global => None,
+            RuntimeScope::Instance => Some(options.instance_name.clone().unwrap_or(options.version.clone())),
+        },
+    }
 }
 
 struct DownloadedData {
@@ -103,55 +409,402 @@ struct DownloadedData {
     package: PackageInfo,
     // loader: None,
     java: JavaFilesMetadata,
+    loader_jvm_arguments: Vec<String>,
     has_natives: bool,
 }
 
-async fn download_minecraft(path: &PathBuf, options: LaunchMetadata) -> DownloadedData {
-    let version_metadata = minecraft::json::get_version_metadata(options.version.as_str(), None).await.unwrap().clone();
-    let libraries = get_libraries(&version_metadata.package).await.unwrap();
-    let assets = get_assets("https://gist.githubusercontent.com/tacxou/fb1135d15a4772e28d5cf4223553f5fe/raw/cc1b41c2b1e954f32a0ac7b715c80b10e30cb590/assets_manifest.json".to_owned(), None).await.unwrap();
-    let game_assets = get_game_assets(&version_metadata.package, None).await.unwrap();
-    let java_files = get_java_files(&version_metadata.package, None).await.unwrap().clone();
+/// The loader to launch with, `enable: Some(false)` keeps it configured without using it
+fn get_enabled_loader(options: &LaunchMetadata) -> Option<Loader> {
+    options.loader.clone().filter(|loader| loader.enable != Some(false))
+}
+
+/// How `get_version_bundle` gets the profile of the enabled loader
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LoaderResolution {
+    /// Installs it first, running its installer when it has one
+    Install,
+    /// Reads the profile already installed, nothing is downloaded nor written
+    Installed,
+}
+
+struct VersionBundle {
+    /// Package merged with the loader profile when a loader is enabled
+    metadata: InfoMetadata,
+    libraries: Vec<FileDownloadMetadata>,
+    java: JavaFilesMetadata,
+    /// Everything to download, java runtime included
+    files: Vec<FileDownloadMetadata>,
+    loader_jvm_arguments: Vec<String>,
+}
+
+fn get_version_options(options: &LaunchMetadata) -> VersionMetadataOptions {
+    VersionMetadataOptions {
+        reqwest_timeout: Some(options.reqwest_timeout),
+        endpoints: options.endpoints.clone(),
+    }
+}
+
+async fn get_version_bundle(version: &str, options: &LaunchMetadata, resolution: LoaderResolution) -> Result<VersionBundle, Box<dyn Error>> {
+    let mut version_metadata = minecraft::json::get_version_metadata(version, Some(get_version_options(options))).await?;
+    // Resolved from the vanilla package, the loader profiles don't change the runtime
+    let java_files = get_java_files(&version_metadata.package, Some(get_java_options(options))).await?;
+    if options.kind == InstanceKind::Server {
+        return get_server_bundle(version_metadata, java_files, options);
+    }
+    let mut libraries = get_libraries(&version_metadata.package, &options.endpoints, true, &options.artifact_overrides).await?;
+    let mut loader_jvm_arguments = vec![];
+    if let Some(loader) = get_enabled_loader(options) {
+        let loader = Loader {
+            version: version_metadata.package.id.clone(),
+            ..loader
+        };
+        let installed = match resolution {
+            LoaderResolution::Install => {
+                // The installer runs with the runtime of the version, there may be no java on the PATH
+                let java = match runs_installer(&loader) {
+                    true => {
+                        check_downloads(download_multiple_files(get_instance_path(options), &java_files.list, Some(get_download_options(options))).await?)?;
+                        Some(get_instance_path(options).join(&java_files.path))
+                    },
+                    false => None,
+                };
+                install(get_instance_path(options), loader, java).await?
+            },
+            LoaderResolution::Installed => find_installed(&get_instance_path(options), &loader)?,
+        };
+        let merged = merge_loader_profile(&version_metadata.package, &installed.profile);
 
-    // println!("game_assets: {:?}", game_assets.len());
+        // The vanilla json stays untouched on disk, only the loader libraries are added
+        let vanilla_files: HashSet<String> = libraries.iter().map(|file| file.path.clone()).collect();
+        let merged_libraries = get_libraries(&merged, &options.endpoints, true, &options.artifact_overrides).await?;
+        libraries.extend(merged_libraries.into_iter().filter(|file| !vanilla_files.contains(&file.path)));
+        loader_jvm_arguments = installed.profile.arguments.as_ref()
+            .map_or(vec![], |arguments| arguments.jvm.iter().filter_map(|argument| argument.as_str().map(String::from)).collect());
+        version_metadata.package = merged;
+    }
+    let assets = get_assets(options.endpoints.assets_manifest.clone(), None).await?;
+    let game_assets = get_game_assets(&version_metadata.package, Some(AssetsMetadataOptions {
+        reqwest_timeout: Some(options.reqwest_timeout),
+        endpoints: options.endpoints.clone(),
+        path: Some(get_instance_path(options)),
+        filter: options.lazy_assets.then(|| Arc::new(|_: &str| false) as AssetFilter),
+    })).await?;
 
     let mut bundle = Vec::new();
     bundle.extend(libraries.clone());
     bundle.extend(assets.data.clone());
     bundle.extend(game_assets.clone());
-    // bundle.extend(java_files.list.clone());
-    bundle = check_bundle(bundle);
+    bundle.extend(java_files.list.clone());
 
-    println!("bundle: {:?}", bundle);
+    Ok(VersionBundle {
+        metadata: version_metadata,
+        libraries,
+        java: java_files,
+        files: check_bundle(bundle),
+        loader_jvm_arguments,
+    })
+}
 
-    download_multiple_files(path.clone(), &bundle, None).await;
+/// The server jar and the runtime, the server needs neither assets nor natives
+fn get_server_bundle(version_metadata: InfoMetadata, java_files: JavaFilesMetadata, options: &LaunchMetadata) -> Result<VersionBundle, Box<dyn Error>> {
+    if get_enabled_loader(options).is_some() {
+        return Err("Loaders can't be installed on a server instance".into());
+    }
+    let server_jar = get_server_jar(&version_metadata.package, &options.endpoints)
+        .ok_or_else(|| format!("Version {} has no dedicated server", version_metadata.package.id))?;
 
-    let natives = get_natives(path.clone(), &version_metadata.package, libraries);
-    let has_natives = natives.len() > 0;
-    println!("{:?}", natives);
+    let mut bundle = vec![server_jar.clone()];
+    bundle.extend(java_files.list.clone());
+    Ok(VersionBundle {
+        metadata: version_metadata,
+        libraries: vec![server_jar],
+        java: java_files,
+        files: check_bundle(bundle),
+        loader_jvm_arguments: vec![],
+    })
+}
 
-    return DownloadedData {
+async fn download_minecraft(path: &Path, options: LaunchMetadata) -> Result<DownloadedData, Box<dyn Error>> {
+    let version_bundle = get_version_bundle(options.version.as_str(), &options, LoaderResolution::Install).await?;
+    let version_metadata = version_bundle.metadata;
+
+    if options.export_manifest {
+        let manifest_path = path.join(format!("versions/{}/download_manifest.json", version_metadata.package.id));
+        if let Err(e) = export_bundle(&manifest_path, &version_metadata.version, &version_bundle.files).await {
+            warn!("Unable to export the download manifest: {}", e);
+        }
+    }
+
+    info!("Downloading {} files...", version_bundle.files.len());
+
+    check_downloads(download_multiple_files(path.to_path_buf(), &version_bundle.files, Some(get_download_options(&options))).await?)?;
+
+    let natives = get_natives(path.to_path_buf(), &version_metadata.package, version_bundle.libraries, options.clean_natives)?;
+    let has_natives = !natives.is_empty();
+    debug!("{} native files extracted", natives.len());
+
+    Ok(DownloadedData {
         version: version_metadata.version,
         package: version_metadata.package,
         // loader: None,
-        java: java_files,
+        java: version_bundle.java,
+        loader_jvm_arguments: version_bundle.loader_jvm_arguments,
         has_natives,
+    })
+}
+
+pub async fn upgrade_minecraft(from_version: &str, mut options: Option<LaunchMetadata>) -> Result<(), Box<dyn Error>> {
+    options = options.or(Some(LaunchMetadata::default()));
+    let options = options.unwrap();
+
+    let path = get_instance_path(&options);
+    // Only compared against, the loader of the previous version is never installed
+    let previous = match get_version_bundle(from_version, &options, LoaderResolution::Installed).await {
+        Err(e) if matches!(e.downcast_ref::<LoaderError>(), Some(LoaderError::NotInstalled { .. })) => {
+            debug!("{}, comparing against the vanilla files of {}", e, from_version);
+            let vanilla = LaunchMetadata { loader: None, ..options.clone() };
+            get_version_bundle(from_version, &vanilla, LoaderResolution::Installed).await?
+        },
+        previous => previous?,
+    };
+    let next = get_version_bundle(options.version.as_str(), &options, LoaderResolution::Install).await?;
+    let bundle = diff_bundle(&previous.files, &next.files);
+
+    info!("Upgrading {} to {}: {} of {} files changed", from_version, next.metadata.version, bundle.len(), next.files.len());
+    check_downloads(download_multiple_files(path.clone(), &bundle, Some(get_download_options(&options))).await?)?;
+    get_natives(path, &next.metadata.package, next.libraries, options.clean_natives)?;
+    Ok(())
+}
+
+/// Writes every file resolved for `options.version` on this platform to `output`, without downloading them.
+/// An enabled loader is read from its installed profile
+pub async fn export_download_manifest(output: &Path, mut options: Option<LaunchMetadata>) -> Result<(), Box<dyn Error>> {
+    options = options.or(Some(LaunchMetadata::default()));
+    let options = options.unwrap();
+
+    let version_bundle = get_version_bundle(options.version.as_str(), &options, LoaderResolution::Installed).await?;
+    export_bundle(output, &version_bundle.metadata.version, &version_bundle.files).await
+}
+
+/// Bytes a fresh install of `version` downloads, from the metadata only
+pub async fn estimate_download_size(version: &str, mut options: Option<LaunchMetadata>) -> Result<u64, Box<dyn Error>> {
+    options = options.or(Some(LaunchMetadata::default()));
+    let options = options.unwrap();
+
+    let version_metadata = minecraft::json::get_version_metadata(version, Some(get_version_options(&options))).await?;
+    let package = &version_metadata.package;
+    let java_files = get_java_files(package, Some(get_java_options(&options))).await?;
+    if options.kind == InstanceKind::Server {
+        let server_jar = get_server_jar(package, &options.endpoints).ok_or_else(|| format!("Version {} has no dedicated server", package.id))?;
+        return Ok(java_files.list.iter().chain([&server_jar]).filter_map(|file| file.size).sum());
+    }
+    // Includes the client jar
+    let libraries = get_libraries(package, &options.endpoints, true, &options.artifact_overrides).await?;
+
+    let files_size: u64 = libraries.iter()
+        .chain(java_files.list.iter())
+        .filter_map(|file| file.size)
+        .sum();
+    Ok(files_size + package.asset_index.size + package.asset_index.total_size)
+}
+
+/// Whether `version` can be played offline from `path`, `verify` checks the sha1 of the client jar and asset index too
+pub async fn is_version_installed(path: &Path, version: &str, verify: bool) -> bool {
+    let package_path = path.join(format!("versions/{}/{}.json", version, version));
+    let package = match tokio::fs::read(&package_path).await.ok().and_then(|content| serde_json::from_slice::<PackageInfo>(&content).ok()) {
+        Some(package) => package,
+        None => return false,
     };
+
+    let files = [
+        (path.join(format!("versions/{}/{}.jar", package.id, package.id)), &package.downloads.client.sha1),
+        (path.join(format!("assets/indexes/{}.json", package.asset_index.id)), &package.asset_index.sha1),
+    ];
+    for (file_path, sha1) in files {
+        let installed = match verify {
+            true => file_exists_and_matches_sha1(&file_path, sha1).await,
+            false => file_path.is_file(),
+        };
+        if !installed {
+            debug!("Version {} is not installed, {:?} is missing", version, file_path);
+            return false;
+        }
+    }
+    true
+}
+
+/// Client jar missing or not matching the sha1 of its version json, see `verify_client_jar`
+#[derive(Debug)]
+pub struct CorruptClientJar {
+    pub path: PathBuf,
+    pub expected_sha1: String,
+    pub missing: bool,
+}
+
+impl fmt::Display for CorruptClientJar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.missing {
+            true => write!(f, "Client jar {:?} is missing", self.path),
+            false => write!(f, "Client jar {:?} doesn't match its sha1 {}", self.path, self.expected_sha1),
+        }
+    }
+}
+
+impl std::error::Error for CorruptClientJar {}
+
+/// Checks only the client jar of an installed `version`, to tell a corrupt jar apart when the game won't start
+pub async fn verify_client_jar(path: &Path, version: &str) -> Result<(), Box<dyn Error>> {
+    let package_path = path.join(format!("versions/{}/{}.json", version, version));
+    let package: PackageInfo = serde_json::from_slice(&tokio::fs::read(&package_path).await?)?;
+    let jar_path = path.join(format!("versions/{}/{}.jar", package.id, package.id));
+
+    let missing = !jar_path.is_file();
+    if missing || !file_exists_and_matches_sha1(&jar_path, &package.downloads.client.sha1).await {
+        return Err(Box::new(CorruptClientJar {
+            path: jar_path,
+            expected_sha1: package.downloads.client.sha1,
+            missing,
+        }));
+    }
+    Ok(())
+}
+
+/// Without the list of installed files, the loader ones can't be told apart from the vanilla ones
+#[derive(Debug)]
+pub struct MissingDownloadManifest {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for MissingDownloadManifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No download manifest at {:?}, the version has to be installed with `export_manifest` first", self.path)
+    }
+}
+
+impl std::error::Error for MissingDownloadManifest {}
+
+/// Brings an instance back to vanilla `version`, removing the libraries and profiles its loader added.
+/// The download manifest of the last install, see `export_manifest`, tells which files belong to the loader, the vanilla json is never modified.
+/// Returns the removed files, the next launch can then use another loader or none.
+pub async fn uninstall_loader(instance_path: &Path, version: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let version_path = instance_path.join("versions").join(version);
+    let package: PackageInfo = serde_json::from_slice(&tokio::fs::read(version_path.join(format!("{}.json", version))).await?)?;
+    let manifest_path = version_path.join("download_manifest.json");
+    if !manifest_path.exists() {
+        return Err(Box::new(MissingDownloadManifest { path: manifest_path }));
+    }
+    let manifest = read_bundle(&manifest_path).await?;
+
+    let vanilla_files: HashSet<String> = get_libraries(&package, &Endpoints::default(), true, &HashMap::new()).await?
+        .into_iter()
+        .map(|file| file.path)
+        .collect();
+    let (loader_files, files): (Vec<FileDownloadMetadata>, Vec<FileDownloadMetadata>) = manifest.files.into_iter()
+        .partition(|file| matches!(file.type_.as_str(), "Libraries" | "Natives") && !vanilla_files.contains(&file.path));
+
+    let mut removed = vec![];
+    for file in loader_files {
+        let file_path = instance_path.join(&file.path);
+        match tokio::fs::remove_file(&file_path).await {
+            Ok(_) => removed.push(file_path),
+            Err(e) => debug!("Loader library {:?} not removed: {}", file_path, e),
+        }
+    }
+
+    // Loader profiles are stored as versions inheriting from the vanilla one
+    let mut versions = tokio::fs::read_dir(instance_path.join("versions")).await?;
+    while let Some(entry) = versions.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let profile_path = entry.path().join(format!("{}.json", name));
+        let inherits_from = tokio::fs::read(&profile_path).await.ok()
+            .and_then(|content| serde_json::from_slice::<serde_json::Value>(&content).ok())
+            .and_then(|profile| profile.get("inheritsFrom").and_then(|v| v.as_str()).map(String::from));
+        if inherits_from.as_deref() == Some(version) {
+            tokio::fs::remove_dir_all(entry.path()).await?;
+            removed.push(entry.path());
+        }
+    }
+
+    export_bundle(&manifest_path, &manifest.version, &files).await?;
+    info!("Loader uninstalled from {}, {} files removed", version, removed.len());
+    Ok(removed)
 }
 
-async fn play_minecraft(path: &PathBuf, data: DownloadedData, options: LaunchMetadata) {
-    println!("Playing Minecraft...");
+/// Downloads the asset objects of `options.version` matching `filter`, e.g. `|name| name.starts_with("minecraft/lang/")`,
+/// to complete an instance installed with `lazy_assets`
+pub async fn download_assets(filter: impl Fn(&str) -> bool + Send + Sync + 'static, mut options: Option<LaunchMetadata>) -> Result<(), Box<dyn Error>> {
+    options = options.or(Some(LaunchMetadata::default()));
+    let options = options.unwrap();
+
+    let path = get_instance_path(&options);
+    let version_metadata = minecraft::json::get_version_metadata(options.version.as_str(), Some(get_version_options(&options))).await?;
+    let game_assets = get_game_assets(&version_metadata.package, Some(AssetsMetadataOptions {
+        reqwest_timeout: Some(options.reqwest_timeout),
+        endpoints: options.endpoints.clone(),
+        path: Some(path.clone()),
+        filter: Some(Arc::new(filter)),
+    })).await?;
+
+    info!("Downloading {} assets...", game_assets.len());
+    check_downloads(download_multiple_files(path, &game_assets, Some(get_download_options(&options))).await?)?;
+    Ok(())
+}
 
-    let minecraft_arguments = get_arguments(path, data.package, &ArgumentsOptions {
+/// Java executable and arguments `launch_minecraft` would spawn, nothing is downloaded nor written.
+/// An enabled loader has to be installed already, `LoaderError::NotInstalled` is returned otherwise
+pub async fn build_launch_command(path: &Path, version: &str, mut options: Option<LaunchMetadata>) -> Result<(PathBuf, Vec<String>), Box<dyn Error>> {
+    options = options.or(Some(LaunchMetadata::default()));
+    let mut options = options.unwrap();
+    options.version = version.to_string();
+
+    let version_bundle = get_version_bundle(options.version.as_str(), &options, LoaderResolution::Installed).await?;
+
+    let data = DownloadedData {
+        version: version_bundle.metadata.version,
+        package: version_bundle.metadata.package,
+        java: version_bundle.java,
+        loader_jvm_arguments: version_bundle.loader_jvm_arguments,
+        has_natives: version_bundle.libraries.iter().any(|lib| lib.type_ == "Natives"),
+    };
+    get_launch_command(path, data, &options).await
+}
+
+async fn get_launch_command(path: &Path, data: DownloadedData, options: &LaunchMetadata) -> Result<(PathBuf, Vec<String>), Box<dyn Error>> {
+    if options.kind == InstanceKind::Server {
+        return Ok(get_server_command(path, &data, options));
+    }
+    let dock_icon = match get_os_name() {
+        "osx" => get_dock_icon(path, &data.package).await,
+        _ => None,
+    };
+    let relative = |target: PathBuf| match options.portable {
+        true => portable_path(path, target),
+        false => target,
+    };
+    let root = match options.portable {
+        true => PathBuf::new(),
+        false => path.to_path_buf(),
+    };
+    let authenticator = options.authenticator.clone().unwrap_or_else(|| Authenticator::offline("Player"));
+    let minecraft_arguments = get_arguments(&root, data.package.clone(), &authenticator, &ArgumentsOptions {
         has_natives: data.has_natives,
-        memory: JvmMemory {
-            min: options.memory.min.unwrap_or("2G".to_owned()),
-            max: options.memory.max.unwrap_or("4G".to_owned()),
-        },
+        natives_dir: relative(natives_dir(path, &data.package.id)),
+        game_directory: relative(get_game_directory(path, options)),
+        assets_directory: options.assets_directory.clone().map(relative),
+        dock_icon: dock_icon.map(|icon| relative(PathBuf::from(icon)).to_string_lossy().to_string()),
+        memory: get_jvm_memory(options),
+        loader_jvm_arguments: data.loader_jvm_arguments.clone(),
+        profile_arguments: options.arg_profile.as_ref().map_or(vec![], |profile| profile.jvm_arguments.clone()),
+        use_default_gc_flags: options.use_default_gc_flags,
+        server: options.server.clone(),
         game_arguments: None,
-        jvm_arguments: None,
-    }).await;
-    println!("{:?}", minecraft_arguments);
+        jvm_arguments: options.jvm_arguments.clone(),
+        extra_classpath: options.extra_classpath.iter().cloned().map(relative).collect(),
+        agents: options.agents.iter().map(|(agent, agent_options)| (relative(agent.clone()), agent_options.clone())).collect(),
+        main_class_override: options.main_class_override.clone(),
+    });
+    debug!("{:?}", minecraft_arguments);
+    validate_arguments(&minecraft_arguments)?;
 
     let mut arguments: Vec<String> = Vec::new();
     arguments.extend(minecraft_arguments.jvm.iter().map(|s| s.to_string()));
@@ -161,47 +814,471 @@ async fn play_minecraft(path: &PathBuf, data: DownloadedData, options: LaunchMet
     arguments.extend(minecraft_arguments.game.iter().map(|s| s.to_string()));
     // arguments.extend(loader_arguments.game.iter().map(|s| s.to_string()));
 
-    println!("path.clone(): {:?}", path.clone());
-    println!("data.java.path: {:?}", data.java.path);
-    let mut exec_process = path.clone();
+    debug!("java path: {:?}", data.java.path);
+    let mut exec_process = path.to_path_buf();
     let java_path = PathBuf::from(data.java.path.clone());
     exec_process.push(java_path.as_os_str());
-    println!("exec_process: {:?}", exec_process.to_str().unwrap().to_string());
-    println!("arguments: {:?}", arguments.join(" "));
-    let mut child = Command::new(exec_process.to_str().unwrap().to_string() + ".exe")
+
+    Ok((exec_process, arguments))
+}
+
+fn get_jvm_memory(options: &LaunchMetadata) -> JvmMemory {
+    let default_memory = Memory::default();
+    JvmMemory {
+        min: options.memory.min.clone().or(default_memory.min).unwrap(),
+        max: options.memory.max.clone().or(default_memory.max).unwrap(),
+    }
+}
+
+/// `java -jar server.jar nogui`, the server has no session nor window to set up
+fn get_server_command(path: &Path, data: &DownloadedData, options: &LaunchMetadata) -> (PathBuf, Vec<String>) {
+    let memory = get_jvm_memory(options);
+    let user_arguments = options.jvm_arguments.clone().unwrap_or_default().split_whitespace().map(String::from).collect();
+    let mut arguments = merge_jvm_arguments(vec![format!("-Xms{}", memory.min), format!("-Xmx{}", memory.max)], user_arguments);
+
+    let server_jar = PathBuf::from(server_jar_path(&data.package.id));
+    let server_jar = match options.portable {
+        true => server_jar,
+        false => path.join(server_jar),
+    };
+    arguments.extend(["-jar".to_string(), server_jar.to_string_lossy().to_string(), "nogui".to_string()]);
+    (path.join(&data.java.path), arguments)
+}
+
+/// Linux client launched without an X11 or Wayland session, GLFW would fail to create the window
+#[derive(Debug)]
+pub struct NoDisplayAvailable;
+
+impl fmt::Display for NoDisplayAvailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No display available, set DISPLAY or WAYLAND_DISPLAY or launch a server instance")
+    }
+}
+
+impl std::error::Error for NoDisplayAvailable {}
+
+/// `lookup` reads an environment variable, `std::env::var_os` outside of the tests
+fn check_display(kind: InstanceKind, lookup: impl Fn(&str) -> Option<OsString>) -> Result<(), NoDisplayAvailable> {
+    if get_os_name() != "linux" || kind != InstanceKind::Client {
+        return Ok(());
+    }
+    let is_display_set = |name: &str| lookup(name).is_some_and(|value| !value.is_empty());
+    match is_display_set("DISPLAY") || is_display_set("WAYLAND_DISPLAY") {
+        true => Ok(()),
+        false => Err(NoDisplayAvailable),
+    }
+}
+
+/// Game output lines telling the game went past its startup, lowercase as the casing varies between versions
+const STARTUP_MARKERS: [&str; 2] = ["setting user", "lwjgl version"];
+
+fn is_startup_line(line: &str) -> bool {
+    let line = line.to_lowercase();
+    STARTUP_MARKERS.iter().any(|marker| line.contains(marker))
+}
+
+#[derive(Debug)]
+pub struct StartupTimedOut {
+    pub name: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for StartupTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instance {} did not start within {:?}", self.name, self.timeout)
+    }
+}
+
+impl std::error::Error for StartupTimedOut {}
+
+/// Flags the session token is given with, older versions also take it as a bare argument
+const TOKEN_FLAGS: [&str; 2] = ["--accessToken", "--session"];
+
+/// Command line fit for the logs, without the session token
+fn redact_arguments(arguments: &[String], access_token: Option<&str>) -> Vec<String> {
+    let mut redacted = Vec::with_capacity(arguments.len());
+    for (index, argument) in arguments.iter().enumerate() {
+        let after_flag = index > 0 && TOKEN_FLAGS.contains(&arguments[index - 1].as_str());
+        if after_flag || access_token == Some(argument.as_str()) {
+            redacted.push("<redacted>".to_string());
+        } else {
+            redacted.push(argument.clone());
+        }
+    }
+    redacted
+}
+
+/// Adds the session to the play history once the instance has exited, killed or stopped included.
+/// Waits on the process itself, a relaunch under the same name is another session
+fn record_session_on_exit(path: PathBuf, name: String, version: String) {
+    let process = match instances::get_process(&name) {
+        Some(process) => process,
+        None => return,
+    };
+    let started_at = chrono::Utc::now();
+    let started = Instant::now();
+    thread::spawn(move || {
+        if let Err(e) = process.wait() {
+            warn!("Unable to wait for the exit of {}, its session isn't recorded: {}", name, e);
+            return;
+        }
+        if let Err(e) = history::record_session(&path, &name, &version, started_at, started.elapsed()) {
+            warn!("Unable to record the session of {}: {}", name, e);
+        }
+    });
+}
+
+async fn wait_for_startup(name: &str, started: Arc<AtomicBool>, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let deadline = Instant::now() + timeout;
+    while !started.load(Ordering::Relaxed) {
+        if !is_running(name) {
+            return Err(format!("Instance {} exited during startup", name).into());
+        }
+        if Instant::now() >= deadline {
+            // Nobody holds a handle to a launch that failed, it would be left running unseen
+            warn!("Instance {} did not start within {:?}, killing it", name, timeout);
+            if let Err(e) = kill_instance(name) {
+                warn!("Unable to kill instance {}: {}", name, e);
+            }
+            return Err(Box::new(StartupTimedOut { name: name.to_string(), timeout }));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+/// Same as `BufRead::lines`, but invalid UTF-8 some mods print is replaced instead of dropping the line
+struct LossyLines<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> Iterator for LossyLines<R> {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buffer = Vec::new();
+        match self.reader.read_until(b'\n', &mut buffer) {
+            Ok(0) => None,
+            Ok(_) => {
+                if buffer.ends_with(b"\n") {
+                    buffer.pop();
+                    if buffer.ends_with(b"\r") {
+                        buffer.pop();
+                    }
+                }
+                Some(Ok(String::from_utf8_lossy(&buffer).into_owned()))
+            },
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Echoes a game stream line by line and hands each line to `on_log`, `inspect` sees it first
+fn forward_output<R: Read>(output: R, instance: &str, stream: LogStream, on_log: Option<LogCallback>, mut inspect: impl FnMut(&str)) {
+    for line in (LossyLines { reader: BufReader::new(output) }) {
+        match line {
+            Ok(line) => {
+                inspect(&line);
+                match stream {
+                    LogStream::Stdout => println!("{}", line),
+                    LogStream::Stderr => eprintln!("{}", line),
+                }
+                if let Some(on_log) = &on_log {
+                    on_log(LogLine { instance: instance.to_string(), stream, line });
+                }
+            },
+            Err(e) => error!("Erreur lors de la lecture de {:?}: {}", stream, e),
+        }
+    }
+}
+
+async fn play_minecraft(path: &Path, data: DownloadedData, options: LaunchMetadata) -> Result<InstanceHandle, Box<dyn Error>> {
+    let instance_name = options.instance_name.clone().unwrap_or(data.version.clone());
+    if is_running(&instance_name) {
+        return Err(format!("Instance {} is already running", instance_name).into());
+    }
+    check_java_compatibility(&data.package, &data.java.version)?;
+    check_display(options.kind, |name| std::env::var_os(name))?;
+    info!("Playing Minecraft...");
+
+    let game_directory = get_game_directory(path, &options);
+    tokio::fs::create_dir_all(&game_directory).await?;
+    // Portable arguments are relative to the instance
+    let working_directory = match options.portable {
+        true => path.to_path_buf(),
+        false => game_directory,
+    };
+    let version = data.version.clone();
+    let (exec_process, arguments) = get_launch_command(path, data, &options).await?;
+    debug!("exec_process: {:?}", exec_process);
+    debug!("arguments: {:?}", redact_arguments(&arguments, options.authenticator.as_ref().map(|authenticator| authenticator.access_token.as_str())).join(" "));
+    let mut child = Command::new(exec_process)
         .args(&arguments)
-        .current_dir(path.clone())
+        .current_dir(working_directory)
+        .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()
-        .expect("failed to start minecraft process");
+        .spawn()?;
 
     let stdout = child.stdout.take().expect("failed to capture stdout");
     let stderr = child.stderr.take().expect("failed to capture stderr");
+    let started = Arc::new(AtomicBool::new(false));
 
-
-    let stdout_thread = thread::spawn(move || {
-        let reader = BufReader::new(stdout);
-        for line in reader.lines() {
-            match line {
-                Ok(line) => println!("Sortie stdout: {}", line),
-                Err(e) => eprintln!("Erreur lors de la lecture de stdout: {}", e),
+    let stdout_started = started.clone();
+    let stdout_name = instance_name.clone();
+    let window_ready_patterns = options.window_ready_patterns.clone();
+    let on_window_ready = options.on_window_ready.clone();
+    let stdout_on_log = options.on_log.clone();
+    thread::spawn(move || {
+        let mut window_ready = false;
+        forward_output(stdout, &stdout_name, LogStream::Stdout, stdout_on_log, |line| {
+            if is_startup_line(line) {
+                stdout_started.store(true, Ordering::Relaxed);
             }
-        }
+            if !window_ready && window_ready_patterns.iter().any(|pattern| line.contains(pattern.as_str())) {
+                window_ready = true;
+                stdout_started.store(true, Ordering::Relaxed);
+                debug!("Instance {} window is ready", stdout_name);
+                if let Some(on_window_ready) = &on_window_ready {
+                    on_window_ready(&stdout_name);
+                }
+            }
+        });
     });
 
-    let stderr_thread = thread::spawn(move || {
-        let reader = BufReader::new(stderr);
-        for line in reader.lines() {
-            match line {
-                Ok(line) => println!("Sortie stderr: {}", line),
-                Err(e) => eprintln!("Erreur lors de la lecture de stderr: {}", e),
-            }
+    let stderr_name = instance_name.clone();
+    let stderr_on_log = options.on_log.clone();
+    thread::spawn(move || forward_output(stderr, &stderr_name, LogStream::Stderr, stderr_on_log, |_| {}));
+
+    let handle = instances::register_instance(&instance_name, child, options.kind)?;
+    record_session_on_exit(path.to_path_buf(), instance_name.clone(), version);
+    if let Some(timeout) = options.startup_timeout {
+        wait_for_startup(&instance_name, started, timeout).await?;
+    }
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed_file(type_: &str, path: &str) -> FileDownloadMetadata {
+        FileDownloadMetadata {
+            type_: type_.to_string(),
+            path: path.to_string(),
+            executable: Some(false),
+            sha1: None,
+            size: None,
+            url: None,
+            content: None,
         }
-    });
+    }
+
+    #[test]
+    fn only_missing_assets_are_tolerated() {
+        assert!(check_downloads(vec![failed_file("Assets", "assets/objects/aa/aa")]).is_ok());
+
+        let error = check_downloads(vec![
+            failed_file("Assets", "assets/objects/aa/aa"),
+            failed_file("Libraries", "libraries/com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar"),
+            failed_file("Jar", "versions/1.20.1/1.20.1.jar"),
+        ]).unwrap_err();
+        assert_eq!(error.files, vec!["libraries/com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar", "versions/1.20.1/1.20.1.jar"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn headless_client_launch_is_refused() {
+        let headless = |name: &str| (name == "DISPLAY").then(OsString::new);
+        assert!(check_display(InstanceKind::Client, headless).is_err());
+        assert!(check_display(InstanceKind::Server, headless).is_ok());
+
+        let wayland = |name: &str| (name == "WAYLAND_DISPLAY").then(|| OsString::from("wayland-0"));
+        assert!(check_display(InstanceKind::Client, wayland).is_ok());
+    }
+
+    #[test]
+    fn portable_paths_are_relative_to_the_root() {
+        let root = Path::new("/media/usb/instance");
+        assert_eq!(portable_path(root, root.join("versions/1.20.1/natives")), PathBuf::from("versions/1.20.1/natives"));
+        assert_eq!(portable_path(root, root.to_path_buf()), PathBuf::from("."));
+        assert_eq!(portable_path(root, PathBuf::from("/opt/agent.jar")), PathBuf::from("/opt/agent.jar"));
+    }
+
+    async fn command_line(root: &Path, options: LaunchMetadata) -> Vec<String> {
+        let data = DownloadedData {
+            version: "1.20.1".to_string(),
+            package: serde_json::from_str(include_str!("../tests/fixtures/1.20.1.json")).unwrap(),
+            java: JavaFilesMetadata {
+                path: "runtime/jre-17.0.8-linux/bin/java".to_string(),
+                version: "17.0.8".to_string(),
+                list: vec![],
+            },
+            loader_jvm_arguments: vec![],
+            has_natives: true,
+        };
+        get_launch_command(root, data, &options).await.unwrap().1
+    }
+
+    async fn portable_command_line(root: &Path) -> Vec<String> {
+        let options = LaunchMetadata {
+            path: root.to_path_buf(),
+            portable: true,
+            authenticator: Some(Authenticator {
+                uuid: "f84c6a790a4e45e0879bcd49ebd4c4e2".to_string(),
+                ..Authenticator::offline("Player")
+            }),
+            memory: Memory {
+                min: Some("1G".to_string()),
+                max: Some("2G".to_string()),
+            },
+            ..Default::default()
+        };
+        command_line(root, options).await
+    }
 
-    stdout_thread.join().expect("Le thread de stdout a paniqué");
-    stderr_thread.join().expect("Le thread de stderr a paniqué");
+    #[test]
+    fn launch_metadata_is_printed_without_its_callbacks() {
+        let options = LaunchMetadata {
+            version: "1.20.1".to_string(),
+            authenticator: Some(Authenticator {
+                access_token: "secret-access-token".to_string(),
+                refresh_token: Some("secret-refresh-token".to_string()),
+                client_token: Some("secret-client-token".to_string()),
+                ..Authenticator::offline("Player")
+            }),
+            on_log: Some(Arc::new(|_| {})),
+            ..Default::default()
+        };
+        let printed = format!("{:?}", options);
+        assert!(printed.starts_with("LaunchMetadata {"));
+        assert!(printed.contains("version: \"1.20.1\""));
+        assert!(printed.contains("name: \"Player\""));
+        assert!(!printed.contains("on_log"));
+        assert!(!printed.contains("secret"));
+    }
+
+    #[test]
+    fn only_refreshed_sessions_are_handed_back() {
+        let refreshed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let store = refreshed.clone();
+        let on_session_refreshed: SessionRefreshedCallback = Arc::new(move |authenticator: &Authenticator| {
+            store.lock().unwrap().push(authenticator.refresh_token.clone());
+        });
+        let cached = Authenticator { refresh_token: Some("old".to_string()), ..Authenticator::offline("Player") };
+
+        notify_session_refreshed(&cached, &cached.clone(), Some(&on_session_refreshed));
+        let rotated = Authenticator { access_token: "new".to_string(), refresh_token: Some("new".to_string()), ..cached.clone() };
+        notify_session_refreshed(&cached, &rotated, Some(&on_session_refreshed));
+        assert_eq!(*refreshed.lock().unwrap(), vec![Some("new".to_string())]);
+    }
+
+    #[test]
+    fn session_token_is_not_logged() {
+        let arguments: Vec<String> = ["--username", "Player", "--accessToken", "secret-token", "--version", "1.20.1"]
+            .iter().map(|argument| argument.to_string()).collect();
+        assert_eq!(redact_arguments(&arguments, None), ["--username", "Player", "--accessToken", "<redacted>", "--version", "1.20.1"]);
+
+        // Beta versions take the token as a bare argument
+        let legacy = vec!["Player".to_string(), "secret-token".to_string()];
+        assert_eq!(redact_arguments(&legacy, Some("secret-token")), ["Player", "<redacted>"]);
+    }
+
+    #[test]
+    fn startup_markers_ignore_the_casing() {
+        assert!(is_startup_line("[12:00:01] [Render thread/INFO]: Setting user: Player"));
+        assert!(is_startup_line("[12:00:02] [Render thread/INFO]: Backend library: LWJGL version 3.3.1 build 7"));
+        assert!(!is_startup_line("[12:00:00] [main/INFO]: Loading tweak class name"));
+    }
+
+    #[test]
+    fn every_line_is_handed_to_on_log() {
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let collected = lines.clone();
+        let on_log: LogCallback = Arc::new(move |line| collected.lock().unwrap().push(line));
+        let mut inspected = 0;
+
+        forward_output(&b"[main/INFO]: Loading\n[main/WARN]: Missing sound\n"[..], "survival", LogStream::Stderr, Some(on_log), |_| inspected += 1);
+        assert_eq!(inspected, 2);
+        let lines = lines.lock().unwrap();
+        let received: Vec<(&str, LogStream, &str)> = lines.iter().map(|line| (line.instance.as_str(), line.stream, line.line.as_str())).collect();
+        assert_eq!(received, vec![
+            ("survival", LogStream::Stderr, "[main/INFO]: Loading"),
+            ("survival", LogStream::Stderr, "[main/WARN]: Missing sound"),
+        ]);
+    }
 
-    // let _ = child.wait().expect("Échec de l'attente du processus enfant");
+    #[test]
+    fn invalid_utf8_lines_are_kept() {
+        let output = b"[main/INFO]: Loaded \xff\xfe mod\r\nplain\n\nno trailing newline";
+        let lines: Vec<String> = LossyLines { reader: &output[..] }.map(Result::unwrap).collect();
+        assert_eq!(lines, vec!["[main/INFO]: Loaded \u{FFFD}\u{FFFD} mod", "plain", "", "no trailing newline"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn instance_is_killed_when_its_startup_times_out() {
+        let child = Command::new("sleep").arg("30").spawn().unwrap();
+        instances::register_instance("startup-timeout", child, InstanceKind::Client).unwrap();
+
+        let error = wait_for_startup("startup-timeout", Arc::new(AtomicBool::new(false)), Duration::from_millis(200)).await.unwrap_err();
+        assert!(error.downcast_ref::<StartupTimedOut>().is_some());
+        assert!(!is_running("startup-timeout"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn started_instance_is_left_running() {
+        let child = Command::new("sleep").arg("30").spawn().unwrap();
+        instances::register_instance("startup-done", child, InstanceKind::Client).unwrap();
+
+        wait_for_startup("startup-done", Arc::new(AtomicBool::new(true)), Duration::from_millis(200)).await.unwrap();
+        assert!(is_running("startup-done"));
+        kill_instance("startup-done").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn session_ends_with_its_own_process() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().to_path_buf();
+        let first = Command::new("true").spawn().unwrap();
+        instances::register_instance("relaunched", first, InstanceKind::Client).unwrap();
+        record_session_on_exit(path.clone(), "relaunched".to_string(), "1.20.1".to_string());
+        instances::get_process("relaunched").unwrap().wait().unwrap();
+
+        // Still running under the same name, the first session is recorded anyway
+        let second = Command::new("sleep").arg("30").spawn().unwrap();
+        instances::register_instance("relaunched", second, InstanceKind::Client).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while get_play_history(&path).is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(get_play_history(&path).len(), 1);
+        assert!(is_running("relaunched"));
+        kill_instance("relaunched").unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_to_joins_through_quick_play() {
+        let root = Path::new("/instance");
+        let options = LaunchMetadata {
+            path: root.to_path_buf(),
+            authenticator: Some(Authenticator::offline("Player")),
+            ..Default::default()
+        }.connect_to("play.example.net", 25565);
+
+        let arguments = command_line(root, options).await;
+        let position = arguments.iter().position(|argument| argument == "--quickPlayMultiplayer").expect("quick play argument");
+        assert_eq!(arguments[position + 1], "play.example.net:25565");
+        assert!(!arguments.contains(&"--server".to_string()));
+    }
+
+    #[tokio::test]
+    async fn portable_arguments_are_the_same_from_any_parent() {
+        let usb = portable_command_line(Path::new("/media/usb/instance")).await;
+        let home = portable_command_line(Path::new("/home/player/backup/instance")).await;
+        assert_eq!(usb, home);
+        assert!(usb.iter().all(|argument| !argument.contains("/media/usb")));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,274 @@
+use std::error::Error;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, Payload};
+use hmac::Hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+use crate::launch::auth::Authenticator;
+use tracing::debug;
+
+const MAGIC: &[u8; 4] = b"RMA2";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + NONCE_LEN;
+const PBKDF2_ROUNDS: u32 = 100_000;
+const KEYRING_SERVICE: &str = "rustmine";
+
+/// Errors crossing `spawn_blocking`
+type BlockingError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct StoredAccounts {
+    accounts: Vec<Authenticator>,
+    selected: Option<String>,
+}
+
+/// Where the key encrypting the file comes from
+#[derive(Clone)]
+enum Secret {
+    /// Stretched with PBKDF2, the salt is renewed on every save
+    Passphrase(String),
+    /// Random key kept in the OS keyring, used as is
+    Key([u8; 32]),
+}
+
+impl Secret {
+    fn derive_key(&self, salt: &[u8]) -> [u8; 32] {
+        match self {
+            Secret::Passphrase(passphrase) => {
+                let mut key = [0u8; 32];
+                pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+                key
+            },
+            Secret::Key(key) => *key,
+        }
+    }
+}
+
+/// Accounts saved to disk, encrypted with AES-256-GCM
+pub struct AccountStore {
+    path: PathBuf,
+    secret: Secret,
+    data: StoredAccounts,
+}
+
+impl AccountStore {
+    /// Opens the store at `path` with a key derived from the user passphrase, starting empty when the file doesn't exist yet
+    pub async fn open(path: PathBuf, passphrase: &str) -> Result<Self, Box<dyn Error>> {
+        Self::open_with(path, Secret::Passphrase(passphrase.to_string())).await
+    }
+
+    /// Opens the store at `path` with a key kept in the OS keyring, created along with the store
+    pub async fn open_with_keyring(path: PathBuf) -> Result<Self, Box<dyn Error>> {
+        let user = path.to_string_lossy().to_string();
+        let key = spawn_blocking(move || get_keyring_key(&user)).await?.map_err(|e| e as Box<dyn Error>)?;
+        Self::open_with(path, Secret::Key(key)).await
+    }
+
+    async fn open_with(path: PathBuf, secret: Secret) -> Result<Self, Box<dyn Error>> {
+        let (file, file_secret) = (path.clone(), secret.clone());
+        let data = spawn_blocking(move || -> Result<StoredAccounts, BlockingError> {
+            match fs::read(&file) {
+                Ok(content) => Ok(serde_json::from_slice(&decrypt(&content, &file_secret)?)?),
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(StoredAccounts::default()),
+                Err(e) => Err(e.into()),
+            }
+        }).await?.map_err(|e| e as Box<dyn Error>)?;
+        debug!("{} accounts loaded from {:?}", data.accounts.len(), path);
+
+        Ok(Self {
+            path,
+            secret,
+            data,
+        })
+    }
+
+    pub async fn save(&self) -> Result<(), Box<dyn Error>> {
+        let (path, secret) = (self.path.clone(), self.secret.clone());
+        let plain = serde_json::to_vec(&self.data)?;
+        spawn_blocking(move || -> Result<(), BlockingError> {
+            write_private(&path, &encrypt(&plain, &secret)?)?;
+            Ok(())
+        }).await?.map_err(|e| e as Box<dyn Error>)?;
+        Ok(())
+    }
+
+    /// Adds the account, replacing the one sharing its uuid
+    pub fn add(&mut self, authenticator: Authenticator) {
+        self.data.accounts.retain(|account| account.uuid != authenticator.uuid);
+        self.data.accounts.push(authenticator);
+    }
+
+    pub fn remove(&mut self, uuid: &str) -> Option<Authenticator> {
+        let index = self.data.accounts.iter().position(|account| account.uuid == uuid)?;
+        if self.data.selected.as_deref() == Some(uuid) {
+            self.data.selected = None;
+        }
+        Some(self.data.accounts.remove(index))
+    }
+
+    pub fn list(&self) -> &Vec<Authenticator> {
+        &self.data.accounts
+    }
+
+    pub fn select(&mut self, uuid: &str) -> Result<(), Box<dyn Error>> {
+        if !self.data.accounts.iter().any(|account| account.uuid == uuid) {
+            return Err(format!("Account {} not found", uuid).into());
+        }
+        self.data.selected = Some(uuid.to_string());
+        Ok(())
+    }
+
+    pub fn selected(&self) -> Option<&Authenticator> {
+        let uuid = self.data.selected.as_ref()?;
+        self.data.accounts.iter().find(|account| &account.uuid == uuid)
+    }
+}
+
+/// Key of the store saved as `user`, generated the first time
+fn get_keyring_key(user: &str) -> Result<[u8; 32], BlockingError> {
+    let entry = keyring::Entry::new(KEYRING_SERVICE, user)?;
+    match entry.get_secret() {
+        Ok(key) => <[u8; 32]>::try_from(key).map_err(|_| "Invalid accounts key in the keyring".into()),
+        Err(keyring::Error::NoEntry) => {
+            let mut key = [0u8; 32];
+            getrandom::getrandom(&mut key)?;
+            entry.set_secret(&key)?;
+            Ok(key)
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes next to `path` then renames, an interrupted save keeps the previous file.
+/// Only the current user can read it
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp_name = format!(".{}.{}.part", path.file_name().unwrap_or_default().to_string_lossy(), Uuid::new_v4());
+    let temp_file = path.with_file_name(temp_name);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let result = options.open(&temp_file)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_file, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_file);
+    }
+    result
+}
+
+fn encrypt(plain: &[u8], secret: &Secret) -> Result<Vec<u8>, BlockingError> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut salt)?;
+    getrandom::getrandom(&mut nonce)?;
+
+    let mut content = Vec::with_capacity(HEADER_LEN + plain.len() + TAG_LEN);
+    content.extend_from_slice(MAGIC);
+    content.extend_from_slice(&salt);
+    content.extend_from_slice(&nonce);
+    let cipher = Aes256Gcm::new(&secret.derive_key(&salt).into());
+    // The header is authenticated along with the accounts
+    let sealed = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plain, aad: &content })
+        .map_err(|_| "Unable to encrypt the accounts")?;
+    content.extend_from_slice(&sealed);
+    Ok(content)
+}
+
+fn decrypt(content: &[u8], secret: &Secret) -> Result<Vec<u8>, BlockingError> {
+    if content.len() < HEADER_LEN + TAG_LEN || &content[..MAGIC.len()] != MAGIC {
+        return Err("Invalid accounts file".into());
+    }
+    let (header, sealed) = content.split_at(HEADER_LEN);
+    let salt = &header[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let nonce = &header[MAGIC.len() + SALT_LEN..];
+
+    let cipher = Aes256Gcm::new(&secret.derive_key(salt).into());
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: header })
+        .map_err(|_| "Wrong passphrase or corrupted accounts file".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PBKDF2 is slow without optimizations, only the passphrase test pays for it
+    const KEY: Secret = Secret::Key([7; 32]);
+
+    async fn saved_store(path: &Path, secret: Secret) -> AccountStore {
+        let mut store = AccountStore::open_with(path.to_path_buf(), secret).await.unwrap();
+        let account = Authenticator::offline("Player");
+        let uuid = account.uuid.clone();
+        store.add(account);
+        store.select(&uuid).unwrap();
+        store.save().await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn accounts_are_read_back() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.dat");
+        let saved = saved_store(&path, KEY).await;
+
+        let store = AccountStore::open_with(path, KEY).await.unwrap();
+        assert_eq!(store.list().len(), 1);
+        assert_eq!(store.selected().map(|account| &account.uuid), saved.selected().map(|account| &account.uuid));
+        // Nothing left behind by the atomic write
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_passphrase_is_refused() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.dat");
+        saved_store(&path, Secret::Passphrase("correct horse".to_string())).await;
+
+        assert!(AccountStore::open(path.clone(), "wrong horse").await.is_err());
+        assert_eq!(AccountStore::open(path, "correct horse").await.unwrap().list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tampered_file_is_refused() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.dat");
+        saved_store(&path, KEY).await;
+
+        for index in [MAGIC.len(), HEADER_LEN + 1] {
+            let mut content = fs::read(&path).unwrap();
+            content[index] ^= 1;
+            let tampered = directory.path().join("tampered.dat");
+            fs::write(&tampered, content).unwrap();
+            assert!(AccountStore::open_with(tampered, KEY).await.is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.dat");
+        saved_store(&path, KEY).await;
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
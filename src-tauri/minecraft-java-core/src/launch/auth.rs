@@ -0,0 +1,566 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use md5::{Digest, Md5};
+use serde_json::json;
+use crate::launch::http::get_client;
+use tracing::{debug, info, warn};
+
+const LIVE_TOKEN_URL: &str = "https://login.live.com/oauth20_token.srf";
+const LIVE_REDIRECT_URL: &str = "https://login.live.com/oauth20_desktop.srf";
+const XBL_AUTH_URL: &str = "https://user.auth.xboxlive.com/user/authenticate";
+const XSTS_AUTH_URL: &str = "https://xsts.auth.xboxlive.com/xsts/authorize";
+const MINECRAFT_LOGIN_URL: &str = "https://api.minecraftservices.com/authentication/login_with_xbox";
+const MINECRAFT_PROFILE_URL: &str = "https://api.minecraftservices.com/minecraft/profile";
+const MINECRAFT_ENTITLEMENTS_URL: &str = "https://api.minecraftservices.com/entitlements/mcstore";
+/// Azure application of the official launcher, used when the account doesn't carry its own
+const DEFAULT_CLIENT_ID: &str = "00000000402b5328";
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+const TEXTURES_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct XboxAccount {
+    pub xuid: Option<String>,
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Meta {
+    pub type_: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Authenticator {
+    pub access_token: String,
+    pub name: String,
+    pub uuid: String,
+    pub xbox_account: Option<XboxAccount>,
+    pub meta: Option<Meta>,
+    pub user_properties: String,
+    /// Passed to the game as `${clientid}`
+    pub client_id: Option<String>,
+    pub client_token: Option<String>,
+    /// Microsoft refresh token, used to mint a new access token once it expires
+    pub refresh_token: Option<String>,
+    /// Azure application the refresh token was issued to, the official launcher one when unset
+    pub oauth_client_id: Option<String>,
+}
+
+// The tokens are left out, a refresh token stays usable for months
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator")
+            .field("access_token", &"<redacted>")
+            .field("name", &self.name)
+            .field("uuid", &self.uuid)
+            .field("xbox_account", &self.xbox_account)
+            .field("meta", &self.meta)
+            .field("user_properties", &self.user_properties)
+            .field("client_id", &self.client_id)
+            .field("client_token", &self.client_token.as_ref().map(|_| "<redacted>"))
+            .field("refresh_token", &self.refresh_token.as_ref().map(|_| "<redacted>"))
+            .field("oauth_client_id", &self.oauth_client_id)
+            .finish()
+    }
+}
+
+impl Authenticator {
+    /// Account without any session, only playable on offline mode servers.
+    /// Its uuid is the one those servers give the name, so the saves and inventories follow the player
+    pub fn offline(name: &str) -> Self {
+        Self {
+            access_token: "0".to_string(),
+            name: name.to_string(),
+            uuid: get_offline_uuid(name),
+            xbox_account: None,
+            meta: None,
+            user_properties: "{}".to_string(),
+            client_id: None,
+            client_token: None,
+            refresh_token: None,
+            oauth_client_id: None,
+        }
+    }
+
+    pub(crate) fn get_xuid(&self) -> String {
+        match &self.xbox_account {
+            Some(xbox_account) => {
+                match &xbox_account.xuid {
+                    Some(xuid) => xuid.clone(),
+                    None => self.access_token.clone(),
+                }
+            },
+            None => self.access_token.clone(),
+        }
+    }
+
+    pub(crate) fn get_client_id_or_token(&self) -> &str {
+        self.client_id.as_deref()
+            .or(self.client_token.as_deref())
+            .unwrap_or(&self.access_token)
+    }
+
+    pub fn is_msa(&self) -> bool {
+        self.meta.as_ref().is_some_and(|meta| meta.type_ == "msa")
+    }
+}
+
+/// `UUID.nameUUIDFromBytes("OfflinePlayer:<name>")` of the server, a md5 based uuid without namespace
+fn get_offline_uuid(name: &str) -> String {
+    let hash = Md5::digest(format!("OfflinePlayer:{}", name).as_bytes());
+    uuid::Builder::from_md5_bytes(hash.into()).into_uuid().simple().to_string()
+}
+
+#[derive(Debug)]
+pub struct NotEntitled {
+    pub name: String,
+}
+
+impl fmt::Display for NotEntitled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Account {} does not own Minecraft", self.name)
+    }
+}
+
+impl std::error::Error for NotEntitled {}
+
+/// The account owns the game but never picked a name, it has no profile to play with yet
+#[derive(Debug)]
+pub struct NoProfile {
+    pub name: String,
+}
+
+impl fmt::Display for NoProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Account {} has no Minecraft profile yet, a name has to be picked on minecraft.net", self.name)
+    }
+}
+
+impl std::error::Error for NoProfile {}
+
+#[derive(Debug, Deserialize)]
+struct LiveToken {
+    access_token: String,
+    refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct XboxUserHash {
+    uhs: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct XboxClaims {
+    xui: Vec<XboxUserHash>,
+}
+
+#[derive(Debug, Deserialize)]
+struct XboxToken {
+    #[serde(rename = "Token")]
+    token: String,
+    #[serde(rename = "DisplayClaims")]
+    display_claims: XboxClaims,
+}
+
+#[derive(Debug, Deserialize)]
+struct MinecraftToken {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Entitlements {
+    #[serde(default)]
+    items: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileTexture {
+    url: String,
+    state: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MinecraftProfile {
+    id: String,
+    name: String,
+    #[serde(default)]
+    skins: Vec<ProfileTexture>,
+    #[serde(default)]
+    capes: Vec<ProfileTexture>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Texture {
+    pub url: String,
+    /// PNG image
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProfileTextures {
+    pub skin: Option<Texture>,
+    pub cape: Option<Texture>,
+}
+
+async fn post_xbox(url: &str, body: serde_json::Value) -> Result<XboxToken, Box<dyn Error>> {
+    let client = get_client();
+    let token = client
+        .post(url)
+        .timeout(AUTH_TIMEOUT)
+        .header("Accept", "application/json")
+        .json(&body)
+        .send().await?
+        .error_for_status()?
+        .json::<XboxToken>().await?;
+    Ok(token)
+}
+
+/// Checks the access token against the Minecraft services, an expired one is answered with a 401.
+/// Other failures are errors, they tell nothing about the token
+pub async fn validate(authenticator: &Authenticator) -> Result<bool, Box<dyn Error>> {
+    validate_at(MINECRAFT_PROFILE_URL, authenticator).await
+}
+
+async fn validate_at(url: &str, authenticator: &Authenticator) -> Result<bool, Box<dyn Error>> {
+    let client = get_client();
+    let response = client
+        .get(url)
+        .timeout(AUTH_TIMEOUT)
+        .bearer_auth(&authenticator.access_token)
+        .send().await?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Ok(false);
+    }
+    response.error_for_status()?;
+    Ok(true)
+}
+
+/// Mints a new Minecraft access token from the stored Microsoft refresh token, without a full login
+pub async fn refresh(authenticator: Authenticator) -> Result<Authenticator, Box<dyn Error>> {
+    let refresh_token = authenticator.refresh_token.clone().ok_or("No refresh token for this account")?;
+    let client_id = authenticator.oauth_client_id.clone().unwrap_or(DEFAULT_CLIENT_ID.to_string());
+    info!("Refreshing session of {}", authenticator.name);
+
+    let client = get_client();
+    let live = client
+        .post(LIVE_TOKEN_URL)
+        .timeout(AUTH_TIMEOUT)
+        .form(&[
+            ("client_id", client_id.as_str()),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+            ("redirect_uri", LIVE_REDIRECT_URL),
+        ])
+        .send().await?
+        .error_for_status()?
+        .json::<LiveToken>().await?;
+
+    let xbl = post_xbox(XBL_AUTH_URL, json!({
+        "Properties": {
+            "AuthMethod": "RPS",
+            "SiteName": "user.auth.xboxlive.com",
+            "RpsTicket": live.access_token,
+        },
+        "RelyingParty": "http://auth.xboxlive.com",
+        "TokenType": "JWT",
+    })).await?;
+
+    let xsts = post_xbox(XSTS_AUTH_URL, json!({
+        "Properties": {
+            "SandboxId": "RETAIL",
+            "UserTokens": [xbl.token],
+        },
+        "RelyingParty": "rp://api.minecraftservices.com/",
+        "TokenType": "JWT",
+    })).await?;
+    let user_hash = xsts.display_claims.xui.first().ok_or("No user hash in XSTS response")?.uhs.clone();
+
+    let minecraft = client
+        .post(MINECRAFT_LOGIN_URL)
+        .timeout(AUTH_TIMEOUT)
+        .json(&json!({ "identityToken": format!("XBL3.0 x={};{}", user_hash, xsts.token) }))
+        .send().await?
+        .error_for_status()?
+        .json::<MinecraftToken>().await?;
+    debug!("Session of {} refreshed", authenticator.name);
+
+    Ok(Authenticator {
+        access_token: minecraft.access_token,
+        refresh_token: Some(live.refresh_token),
+        ..authenticator
+    })
+}
+
+/// Refreshes Microsoft sessions whose access token is no longer accepted.
+/// The cached token is kept when the services can't be reached, the game may still be played offline
+pub async fn ensure_valid(authenticator: Authenticator) -> Result<Authenticator, Box<dyn Error>> {
+    ensure_valid_at(MINECRAFT_PROFILE_URL, authenticator).await
+}
+
+async fn ensure_valid_at(url: &str, authenticator: Authenticator) -> Result<Authenticator, Box<dyn Error>> {
+    if !authenticator.is_msa() || authenticator.refresh_token.is_none() {
+        return Ok(authenticator);
+    }
+    match validate_at(url, &authenticator).await {
+        Ok(true) => Ok(authenticator),
+        Ok(false) => refresh(authenticator).await,
+        Err(e) => {
+            warn!("Unable to validate the session of {}, keeping the cached token: {}", authenticator.name, e);
+            Ok(authenticator)
+        },
+    }
+}
+
+/// Profile found for each access token, a token only ever belongs to one account
+fn get_entitlements_cache() -> &'static Mutex<HashMap<String, (String, String)>> {
+    static ENTITLEMENTS: OnceLock<Mutex<HashMap<String, (String, String)>>> = OnceLock::new();
+    ENTITLEMENTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Confirms the account owns the game and fills in its real uuid and name, once per access token
+pub async fn check_entitlement(authenticator: Authenticator) -> Result<Authenticator, Box<dyn Error>> {
+    check_entitlement_at(MINECRAFT_ENTITLEMENTS_URL, MINECRAFT_PROFILE_URL, authenticator).await
+}
+
+async fn check_entitlement_at(entitlements_url: &str, profile_url: &str, authenticator: Authenticator) -> Result<Authenticator, Box<dyn Error>> {
+    let cached = get_entitlements_cache()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&authenticator.access_token)
+        .cloned();
+    if let Some((uuid, name)) = cached {
+        return Ok(Authenticator { uuid, name, ..authenticator });
+    }
+
+    let client = get_client();
+    let entitlements = client
+        .get(entitlements_url)
+        .timeout(AUTH_TIMEOUT)
+        .bearer_auth(&authenticator.access_token)
+        .send().await?
+        .error_for_status()?
+        .json::<Entitlements>().await?;
+    if entitlements.items.is_empty() {
+        return Err(Box::new(NotEntitled { name: authenticator.name }));
+    }
+
+    // Owners that never picked a name have no profile yet
+    let response = client
+        .get(profile_url)
+        .timeout(AUTH_TIMEOUT)
+        .bearer_auth(&authenticator.access_token)
+        .send().await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Err(Box::new(NoProfile { name: authenticator.name }));
+    }
+    let profile = response.error_for_status()?.json::<MinecraftProfile>().await?;
+    debug!("Account {} owns Minecraft as {}", authenticator.name, profile.name);
+    get_entitlements_cache()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(authenticator.access_token.clone(), (profile.id.clone(), profile.name.clone()));
+
+    Ok(Authenticator {
+        uuid: profile.id,
+        name: profile.name,
+        ..authenticator
+    })
+}
+
+/// Textures of each uuid along with when they were fetched
+fn get_textures_cache() -> &'static Mutex<HashMap<String, (Instant, ProfileTextures)>> {
+    static TEXTURES: OnceLock<Mutex<HashMap<String, (Instant, ProfileTextures)>>> = OnceLock::new();
+    TEXTURES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+async fn get_active_texture(textures: &[ProfileTexture]) -> Result<Option<Texture>, Box<dyn Error>> {
+    let url = match textures.iter().find(|texture| texture.state == "ACTIVE") {
+        Some(texture) => texture.url.clone(),
+        None => return Ok(None),
+    };
+    let client = get_client();
+    let data = client
+        .get(&url)
+        .timeout(AUTH_TIMEOUT)
+        .send().await?
+        .error_for_status()?
+        .bytes().await?
+        .to_vec();
+    Ok(Some(Texture { url, data }))
+}
+
+/// Fetches the active skin and cape of the account, cached by uuid for `TEXTURES_TTL` so a new skin shows up
+pub async fn get_profile_textures(authenticator: &Authenticator) -> Result<ProfileTextures, Box<dyn Error>> {
+    get_profile_textures_at(MINECRAFT_PROFILE_URL, authenticator).await
+}
+
+/// Drops the cached textures of `uuid`, e.g. right after the player changed their skin
+pub fn forget_profile_textures(uuid: &str) {
+    get_textures_cache()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .remove(uuid);
+}
+
+async fn get_profile_textures_at(url: &str, authenticator: &Authenticator) -> Result<ProfileTextures, Box<dyn Error>> {
+    let cached = get_textures_cache()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&authenticator.uuid)
+        .filter(|(fetched, _)| fetched.elapsed() < TEXTURES_TTL)
+        .map(|(_, textures)| textures.clone());
+    if let Some(textures) = cached {
+        return Ok(textures);
+    }
+
+    let client = get_client();
+    let profile = client
+        .get(url)
+        .timeout(AUTH_TIMEOUT)
+        .bearer_auth(&authenticator.access_token)
+        .send().await?
+        .error_for_status()?
+        .json::<MinecraftProfile>().await?;
+
+    let skin = get_active_texture(&profile.skins).await?;
+    let cape = get_active_texture(&profile.capes).await?;
+    let textures = ProfileTextures { skin, cape };
+    get_textures_cache()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(authenticator.uuid.clone(), (Instant::now(), textures.clone()));
+    Ok(textures)
+}
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use super::*;
+
+    fn msa(access_token: &str) -> Authenticator {
+        Authenticator {
+            access_token: access_token.to_string(),
+            meta: Some(Meta { type_: "msa".to_string() }),
+            refresh_token: Some("refresh".to_string()),
+            ..Authenticator::offline("Player")
+        }
+    }
+
+    #[tokio::test]
+    async fn token_validity_follows_the_profile_status() {
+        let server = MockServer::start().await;
+        for (token, status) in [("valid", 200), ("expired", 401), ("unavailable", 503)] {
+            Mock::given(method("GET")).and(path("/minecraft/profile")).and(header("Authorization", format!("Bearer {}", token)))
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&server)
+                .await;
+        }
+        let url = format!("{}/minecraft/profile", server.uri());
+
+        assert!(validate_at(&url, &msa("valid")).await.unwrap());
+        assert!(!validate_at(&url, &msa("expired")).await.unwrap());
+        assert!(validate_at(&url, &msa("unavailable")).await.is_err());
+    }
+
+    /// Entitlements and profile answered for `token`
+    async fn mount_account(server: &MockServer, token: &str, items: serde_json::Value, profile: ResponseTemplate) {
+        let bearer = format!("Bearer {}", token);
+        Mock::given(method("GET")).and(path("/entitlements/mcstore")).and(header("Authorization", bearer.as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "items": items })))
+            .expect(1)
+            .mount(server)
+            .await;
+        Mock::given(method("GET")).and(path("/minecraft/profile")).and(header("Authorization", bearer.as_str()))
+            .respond_with(profile)
+            .mount(server)
+            .await;
+    }
+
+    async fn check(server: &MockServer, authenticator: Authenticator) -> Result<Authenticator, Box<dyn Error>> {
+        let entitlements_url = format!("{}/entitlements/mcstore", server.uri());
+        let profile_url = format!("{}/minecraft/profile", server.uri());
+        check_entitlement_at(&entitlements_url, &profile_url, authenticator).await
+    }
+
+    #[tokio::test]
+    async fn owners_get_their_profile_once_per_token() {
+        let server = MockServer::start().await;
+        let profile = json!({ "id": "069a79f444e94726a5befca90e38aaf5", "name": "Notch" });
+        mount_account(&server, "owner", json!([{ "name": "game_minecraft" }]), ResponseTemplate::new(200).set_body_json(profile)).await;
+
+        for _ in 0..2 {
+            let authenticator = check(&server, msa("owner")).await.unwrap();
+            assert_eq!(authenticator.uuid, "069a79f444e94726a5befca90e38aaf5");
+            assert_eq!(authenticator.name, "Notch");
+        }
+    }
+
+    #[tokio::test]
+    async fn accounts_without_the_game_or_a_profile_are_told_apart() {
+        let server = MockServer::start().await;
+        mount_account(&server, "demo", json!([]), ResponseTemplate::new(200)).await;
+        mount_account(&server, "nameless", json!([{ "name": "game_minecraft" }]), ResponseTemplate::new(404)).await;
+
+        assert!(check(&server, msa("demo")).await.unwrap_err().downcast_ref::<NotEntitled>().is_some());
+        assert!(check(&server, msa("nameless")).await.unwrap_err().downcast_ref::<NoProfile>().is_some());
+    }
+
+    #[tokio::test]
+    async fn active_textures_are_fetched_and_cached() {
+        let server = MockServer::start().await;
+        let texture = |name: &str, state: &str| json!({ "url": format!("{}/textures/{}", server.uri(), name), "state": state });
+        Mock::given(method("GET")).and(path("/minecraft/profile"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "5c6a5e0aa3d34a1cbb2a7a1e2bba2c41",
+                "name": "Skinned",
+                "skins": [texture("old", "INACTIVE"), texture("skin", "ACTIVE")],
+                "capes": [texture("cape", "INACTIVE")],
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET")).and(path("/textures/skin"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"\x89PNG skin".to_vec()))
+            .mount(&server)
+            .await;
+        let url = format!("{}/minecraft/profile", server.uri());
+        let authenticator = Authenticator { uuid: "5c6a5e0aa3d34a1cbb2a7a1e2bba2c41".to_string(), ..msa("skinned") };
+
+        let textures = get_profile_textures_at(&url, &authenticator).await.unwrap();
+        assert_eq!(textures.skin.unwrap().data, b"\x89PNG skin");
+        assert!(textures.cape.is_none());
+        // Served from the cache, then fetched again once forgotten
+        get_profile_textures_at(&url, &authenticator).await.unwrap();
+        forget_profile_textures(&authenticator.uuid);
+        get_profile_textures_at(&url, &authenticator).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unavailable_services_keep_the_cached_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET")).and(path("/minecraft/profile"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let authenticator = ensure_valid_at(&format!("{}/minecraft/profile", server.uri()), msa("cached")).await.unwrap();
+        assert_eq!(authenticator.access_token, "cached");
+
+        // Nothing listens there once the server is dropped
+        let url = format!("{}/minecraft/profile", server.uri());
+        drop(server);
+        let authenticator = ensure_valid_at(&url, msa("cached")).await.unwrap();
+        assert_eq!(authenticator.access_token, "cached");
+    }
+
+    #[test]
+    fn offline_uuid_is_the_one_of_offline_servers() {
+        assert_eq!(Authenticator::offline("Notch").uuid, Authenticator::offline("Notch").uuid);
+        assert_eq!(Authenticator::offline("Notch").uuid, "b50ad385829d3141a2167e7d7539ba7f");
+        assert_ne!(Authenticator::offline("Notch").uuid, Authenticator::offline("jeb_").uuid);
+    }
+}
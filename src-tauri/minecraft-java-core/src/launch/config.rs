@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::launch::{LaunchMetadata, Memory};
+use crate::launch::endpoints::Endpoints;
+use crate::launch::http::{HttpConfig, set_http_config};
+
+/// Launcher wide settings, the launch options of every instance are created from them
+#[derive(Debug, Clone)]
+pub struct LauncherConfig {
+    pub base_path: PathBuf,
+    pub endpoints: Endpoints,
+    pub max_concurrent_downloads: usize,
+    /// Metadata requests timeout, file downloads get more time depending on their size
+    pub reqwest_timeout: Duration,
+    pub memory: Memory,
+    /// Proxy, certificates, retries and user agent, shared by every request
+    pub http: HttpConfig,
+}
+
+impl Default for LauncherConfig {
+    fn default() -> Self {
+        Self {
+            base_path: PathBuf::from("instances"),
+            endpoints: Endpoints::default(),
+            max_concurrent_downloads: 75,
+            reqwest_timeout: Duration::from_secs(10),
+            memory: Memory::default(),
+            http: HttpConfig::default(),
+        }
+    }
+}
+
+impl LauncherConfig {
+    /// Installs the http settings, they are shared by every request of the process
+    pub fn apply(&self) -> reqwest::Result<()> {
+        set_http_config(self.http.clone())
+    }
+
+    /// Launch options of `version` using these settings, the http ones are applied on the way
+    pub fn launch_metadata(&self, version: &str) -> reqwest::Result<LaunchMetadata> {
+        self.apply()?;
+        Ok(LaunchMetadata {
+            path: self.base_path.clone(),
+            version: version.to_string(),
+            endpoints: self.endpoints.clone(),
+            max_concurrent_downloads: self.max_concurrent_downloads,
+            reqwest_timeout: self.reqwest_timeout,
+            memory: self.memory.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn launch_options_are_taken_from_the_config() {
+        let config = LauncherConfig {
+            base_path: PathBuf::from("/launcher/instances"),
+            endpoints: Endpoints::with_base("http://mirror.test"),
+            max_concurrent_downloads: 8,
+            reqwest_timeout: Duration::from_secs(30),
+            memory: Memory { min: Some("1G".to_string()), max: Some("6G".to_string()) },
+            ..Default::default()
+        };
+
+        let options = config.launch_metadata("1.20.1").unwrap();
+        assert_eq!(options.path, PathBuf::from("/launcher/instances"));
+        assert_eq!(options.version, "1.20.1");
+        assert_eq!(options.endpoints.version_manifest(), "http://mirror.test/mc/game/version_manifest_v2.json");
+        assert_eq!(options.max_concurrent_downloads, 8);
+        assert_eq!(options.reqwest_timeout, Duration::from_secs(30));
+        assert_eq!((options.memory.min.as_deref(), options.memory.max.as_deref()), (Some("1G"), Some("6G")));
+    }
+
+    #[test]
+    fn invalid_http_config_is_reported() {
+        let config = LauncherConfig {
+            http: HttpConfig { proxy: Some("not a proxy url".to_string()), ..Default::default() },
+            ..Default::default()
+        };
+        assert!(config.launch_metadata("1.20.1").is_err());
+    }
+}
\ No newline at end of file
@@ -1,16 +1,22 @@
-use std::io::Error;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use reqwest::Client;
+use chrono::DateTime;
+use reqwest::header::LAST_MODIFIED;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use tokio::fs;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::{mpsc, Semaphore};
+use uuid::Uuid;
 
-use crate::launch::utils::read_temp_file_content;
+use crate::launch::http::{get_client, get_retry_policy, is_transient};
+use tracing::{debug, error, trace, warn};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct FileDownloadMetadata {
@@ -21,19 +27,93 @@ pub struct FileDownloadMetadata {
     pub(crate) sha1: Option<String>,
     pub(crate) size: Option<u64>,
     pub(crate) url: Option<String>,
-    pub(crate) content: Option<PathBuf>,
+    /// Written as is when there is no url, e.g. a json already held in memory
+    #[serde(skip)]
+    pub(crate) content: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+struct ChecksumEntry {
+    sha1: String,
+    mtime: u64,
+    size: u64,
+}
+
+const CHECKSUMS_FILE: &str = ".checksums.json";
+
+async fn load_checksums(path: &Path) -> HashMap<String, ChecksumEntry> {
+    match fs::read(path.join(CHECKSUMS_FILE)).await {
+        Ok(content) => serde_json::from_slice(&content).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+async fn save_checksums(path: &PathBuf, checksums: &HashMap<String, ChecksumEntry>) -> Result<(), Error> {
+    fs::create_dir_all(path).await?;
+    fs::write(path.join(CHECKSUMS_FILE), serde_json::to_vec(checksums)?).await
+}
+
+async fn get_file_stamp(file_path: &PathBuf) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(file_path).await.ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64;
+    Some((mtime, metadata.len()))
+}
+
+/// Trusts the cached hash while the file mtime and size are unchanged, rehashes otherwise
+async fn file_matches_cached_sha1(checksums: &mut HashMap<String, ChecksumEntry>, key: &str, file_path: &PathBuf, expected_sha1: &str) -> bool {
+    let (mtime, size) = match get_file_stamp(file_path).await {
+        Some(stamp) => stamp,
+        None => return false,
+    };
+    if let Some(entry) = checksums.get(key) {
+        if entry.mtime == mtime && entry.size == size {
+            return entry.sha1 == expected_sha1;
+        }
+    }
+
+    if !file_exists_and_matches_sha1(file_path, expected_sha1).await {
+        checksums.remove(key);
+        return false;
+    }
+    checksums.insert(key.to_string(), ChecksumEntry {
+        sha1: expected_sha1.to_string(),
+        mtime,
+        size,
+    });
+    true
+}
+
+/// Cache entry of a file just written, its sha1 was already checked against the bytes written
+async fn get_checksum_entry(file_path: &PathBuf, sha1: Option<&str>) -> Option<ChecksumEntry> {
+    let (mtime, size) = get_file_stamp(file_path).await?;
+    Some(ChecksumEntry {
+        sha1: sha1?.to_string(),
+        mtime,
+        size,
+    })
+}
+
+/// Presence check for the files not worth hashing, the size is compared when known
+async fn file_has_size(file_path: &PathBuf, size: Option<u64>) -> bool {
+    match fs::metadata(file_path).await {
+        Ok(metadata) => size.is_none_or(|size| metadata.len() == size),
+        Err(_) => false,
+    }
 }
 
 struct Downloader {
     path: PathBuf,
     semaphore: Arc<Semaphore>,
+    options: DownloadMultipleFilesOptions,
 }
 
 impl Downloader {
-    fn new(path: PathBuf, max_concurrent_downloads: usize) -> Self {
+    fn new(path: PathBuf, max_concurrent_downloads: usize, options: DownloadMultipleFilesOptions) -> Self {
         Downloader {
             path,
-            semaphore: Arc::new(Semaphore::new(max_concurrent_downloads)),
+            // Without a single permit no download would ever start
+            semaphore: Arc::new(Semaphore::new(max_concurrent_downloads.max(1))),
+            options,
         }
     }
 
@@ -53,88 +133,170 @@ impl Downloader {
     //     // Abonnement à l'événement d'erreur
     // }
 
-    async fn download_file_multiple(&self, files_list: &Vec<FileDownloadMetadata>, _total_size: u64, max_retries: usize) {
+    /// Returns the files still missing once their retries are exhausted
+    async fn download_file_multiple(&self, files_list: &[FileDownloadMetadata], total_size: u64) -> Vec<FileDownloadMetadata> {
         let (tx, mut rx) = mpsc::channel(32);
-        let client = Client::new();
+        let client = get_client();
+        let retry = get_retry_policy();
+        let max_retries = retry.max_retries;
         let mut handles = vec![];
+        let mut failed = vec![];
+        let mut checksums = load_checksums(&self.path).await;
 
+        let base_timeout = self.options.reqwest_timeout.unwrap_or(Duration::from_secs(10));
+        let on_progress = self.options.on_progress.clone();
+        let progress_interval = self.options.progress_interval.unwrap_or(Duration::from_millis(100));
+        let progress_step = (total_size / 100).max(1);
         let progress_handle = tokio::spawn(async move {
+            let mut downloaded: u64 = 0;
+            let mut last_reported: u64 = 0;
+            let mut last_emit = Instant::now();
             while let Some(progress) = rx.recv().await {
-                println!("Progress: {}", progress);
-                // Mettre à jour la logique de progression ici
+                downloaded += progress;
+                trace!("Progress: {}/{}", downloaded, total_size);
+                if last_emit.elapsed() < progress_interval && downloaded - last_reported < progress_step {
+                    continue;
+                }
+                if let Some(on_progress) = &on_progress {
+                    on_progress(downloaded, total_size);
+                }
+                last_reported = downloaded;
+                last_emit = Instant::now();
+            }
+            if let Some(on_progress) = &on_progress {
+                if downloaded != last_reported {
+                    on_progress(downloaded, total_size);
+                }
             }
         });
 
-        for file in files_list.iter().cloned() {
+        for mut file in files_list.iter().cloned() {
+            if let (Some(url_rewrite), Some(url)) = (&self.options.url_rewrite, &file.url) {
+                file.url = Some(url_rewrite(url));
+            }
             let path = self.path.clone();
             let file_path = path.join(standardize_path(&file.path));
 
             if file.url.is_none() {
-                let bytes = read_temp_file_content(file.content.unwrap()).await.unwrap();
-                if let Err(e) = save_to_file(file_path.clone(), &bytes).await {
-                    eprintln!("Error storing file: {:?}", e);
+                let bytes = file.content.clone().unwrap_or_default();
+                if let Err(e) = save_to_file(file_path.clone(), &bytes, file.executable.unwrap_or(false), file.sha1.as_deref(), None).await {
+                    error!("Error storing file: {:?}", e);
+                    failed.push(file);
                 } else {
+                    if let Some(entry) = get_checksum_entry(&file_path, file.sha1.as_deref()).await {
+                        checksums.insert(file.path.clone(), entry);
+                    }
                     let _ = tx.send(bytes.len() as u64).await;
+                    if let Some(on_file_complete) = &self.options.on_file_complete {
+                        on_file_complete(&file, bytes.len() as u64);
+                    }
                 }
                 continue;
             }
 
             let tx = tx.clone();
             let client = client.clone();
+            let retry = retry.clone();
             let semaphore = self.semaphore.clone();
+            let on_file_complete = self.options.on_file_complete.clone();
+            let preserve_timestamps = self.options.preserve_timestamps;
+            let timeout = get_file_timeout(base_timeout, file.size, self.options.min_bytes_per_sec);
 
+            let trusted = !self.options.verify_assets && file.type_ == "Assets" && file_has_size(&file_path, file.size).await;
+            if trusted {
+                trace!("Asset trusted without verification: {:?}", file_path);
+            }
             if let Some(ref sha1) = file.sha1 {
-                if file_exists_and_matches_sha1(&file_path, sha1).await {
-                    println!("File already downloaded and verified: {:?}", file_path);
+                if trusted || file_matches_cached_sha1(&mut checksums, &file.path, &file_path, sha1).await {
+                    debug!("File already downloaded and verified: {:?}", file_path);
+                    let _ = tx.send(file.size.unwrap_or(0)).await;
+                    if let Some(on_file_complete) = &on_file_complete {
+                        let size = fs::metadata(&file_path).await.map(|metadata| metadata.len()).unwrap_or(0);
+                        on_file_complete(&file, size);
+                    }
                     continue;
                 }
             }
 
+            let task_file = file.clone();
             let handle = tokio::spawn(async move {
                 let _permit = semaphore.acquire().await.expect("Failed to acquire semaphore permit");
 
-                println!("Downloading file: {:?}", file);
+                debug!("Downloading file: {:?}", file.path);
 
                 for attempt in 0..=max_retries {
-                    match client.get(&file.url.clone().unwrap()).timeout(std::time::Duration::from_secs(10)).send().await {
+                    match client.get(file.url.clone().unwrap()).timeout(timeout).send().await {
                         Ok(response) if response.status().is_success() => {
+                            let modified = preserve_timestamps.then(|| get_last_modified(&response)).flatten();
                             if let Ok(bytes) = response.bytes().await {
-                                if let Err(e) = save_to_file(file_path.clone(), &bytes).await {
-                                    eprintln!("Error saving file: {:?}", e);
+                                if let Err(e) = save_to_file(file_path.clone(), &bytes, file.executable.unwrap_or(false), file.sha1.as_deref(), modified).await {
+                                    error!("Error saving file: {:?}", e);
                                 } else {
                                     let _ = tx.send(bytes.len() as u64).await;
-                                    break;
+                                    if let Some(on_file_complete) = &on_file_complete {
+                                        on_file_complete(&file, bytes.len() as u64);
+                                    }
+                                    let entry = get_checksum_entry(&file_path, file.sha1.as_deref()).await;
+                                    return Ok(entry.map(|entry| (file.path, entry)));
                                 }
                             }
                         }
                         Ok(response) => {
-                            eprintln!("Error downloading file: HTTP Status {}", response.status());
-                            if attempt == max_retries {
+                            warn!("Error downloading file: HTTP Status {}", response.status());
+                            // A missing file won't show up on a retry
+                            if !is_transient(response.status()) || attempt == max_retries {
                                 break;
                             }
                         }
                         Err(e) => {
                             if attempt == max_retries {
-                                eprintln!("Error downloading file after {} attempts: {:?}", max_retries, e);
+                                error!("Error downloading file after {} attempts: {:?}", max_retries, e);
                             }
                         }
                     }
+                    if attempt < max_retries {
+                        tokio::time::sleep(retry.backoff(attempt)).await;
+                    }
                 }
+                Err(file)
             });
-            handles.push(handle);
+            handles.push((task_file, handle));
+        }
+
+        // Freshly downloaded files are trusted on the next launch without being hashed again
+        for (file, handle) in handles {
+            match handle.await {
+                Ok(Ok(Some((key, entry)))) => {
+                    checksums.insert(key, entry);
+                },
+                Ok(Ok(None)) => {},
+                Ok(Err(file)) => failed.push(file),
+                Err(e) => {
+                    error!("Download task for {} failed: {:?}", file.path, e);
+                    failed.push(file);
+                },
+            }
         }
 
-        for handle in handles {
-            println!("Waiting for download to finish...");
-            let _ = handle.await;
+        if let Err(e) = save_checksums(&self.path, &checksums).await {
+            warn!("Error saving checksums cache: {:?}", e);
         }
 
         drop(tx);
         let _ = progress_handle.await;
+        failed
     }
 }
 
-async fn file_exists_and_matches_sha1(file_path: &PathBuf, expected_sha1: &str) -> bool {
+/// Flat timeout when the size or the rate is unknown
+fn get_file_timeout(base: Duration, size: Option<u64>, min_bytes_per_sec: Option<u64>) -> Duration {
+    match (size, min_bytes_per_sec) {
+        (Some(size), Some(rate)) if rate > 0 => base + Duration::from_secs(size / rate),
+        _ => base,
+    }
+}
+
+pub(crate) async fn file_exists_and_matches_sha1(file_path: &PathBuf, expected_sha1: &str) -> bool {
     if let Ok(mut file) = File::open(file_path).await {
         let mut hasher = Sha1::new();
         let mut buffer = [0; 1024];
@@ -158,42 +320,410 @@ fn standardize_path(path: &str) -> PathBuf {
     Path::new(path).to_path_buf()
 }
 
-async fn save_to_file(file_name: PathBuf, bytes: &[u8]) -> Result<(), Error> {
+/// Modified time the server gives for the file, if any
+fn get_last_modified(response: &reqwest::Response) -> Option<SystemTime> {
+    let last_modified = response.headers().get(LAST_MODIFIED)?.to_str().ok()?;
+    DateTime::parse_from_rfc2822(last_modified).ok().map(SystemTime::from)
+}
+
+/// `modified` becomes the file mtime, the current time is kept when unset
+async fn save_to_file(file_name: PathBuf, bytes: &[u8], executable: bool, expected_sha1: Option<&str>, modified: Option<SystemTime>) -> Result<(), Error> {
+    if let Some(expected_sha1) = expected_sha1 {
+        let sha1 = format!("{:x}", Sha1::digest(bytes));
+        if sha1 != expected_sha1 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("sha1 mismatch for {:?}: expected {}, got {}", file_name, expected_sha1, sha1)));
+        }
+    }
+    let file_name = extended_length_path(&file_name);
     if let Some(parent) = file_name.parent() {
         fs::create_dir_all(parent).await?;
     }
-    println!("Saving file: {:?}", file_name);
-    let mut file = File::create(file_name).await?;
-    file.write_all(bytes).await?;
-    println!("File saved!");
+    debug!("Saving file: {:?}", file_name);
+
+    // Write next to the destination then rename, so an interrupted write never leaves a partial file in place
+    let temp_name = format!(".{}.{}.part", file_name.file_name().unwrap_or_default().to_string_lossy(), Uuid::new_v4());
+    let temp_file = file_name.with_file_name(temp_name);
+    let result = async {
+        let mut file = File::create(&temp_file).await?;
+        file.write_all(bytes).await?;
+        file.sync_all().await?;
+        if let Some(modified) = modified {
+            file.into_std().await.set_modified(modified)?;
+        }
+        if executable {
+            set_executable(&temp_file).await?;
+        }
+        fs::rename(&temp_file, &file_name).await
+    }.await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_file).await;
+    }
+    result
+}
+
+/// Longest path the Windows APIs accept without the `\\?\` prefix, MAX_PATH minus the terminating nul
+#[cfg(windows)]
+const MAX_PATH_LENGTH: usize = 259;
+
+/// Deep maven paths under a long instance folder go past MAX_PATH, the `\\?\` prefix lifts the limit.
+/// Prefixed paths are taken as is by Windows, so they have to be absolute and use backslashes only
+#[cfg(windows)]
+fn extended_length_path(path: &Path) -> PathBuf {
+    let absolute = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => match std::env::current_dir() {
+            Ok(current_dir) => current_dir.join(path),
+            Err(_) => return path.to_path_buf(),
+        },
+    };
+    let absolute = absolute.to_string_lossy().replace('/', "\\");
+    if absolute.len() <= MAX_PATH_LENGTH || absolute.starts_with(r"\\?\") {
+        return PathBuf::from(absolute);
+    }
+    match absolute.strip_prefix(r"\\") {
+        Some(share) => PathBuf::from(format!(r"\\?\UNC\{}", share)),
+        None => PathBuf::from(format!(r"\\?\{}", absolute)),
+    }
+}
+
+#[cfg(not(windows))]
+fn extended_length_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(unix)]
+async fn set_executable(file_name: &PathBuf) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(file_name).await?.permissions();
+    permissions.set_mode(permissions.mode() | 0o755);
+    fs::set_permissions(file_name, permissions).await
+}
+
+#[cfg(not(unix))]
+async fn set_executable(_file_name: &PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub type ProgressCallback = Arc<dyn Fn(u64, u64) + Send + Sync>;
+pub type FileCompleteCallback = Arc<dyn Fn(&FileDownloadMetadata, u64) + Send + Sync>;
+pub type UrlRewrite = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+#[derive(Clone)]
 pub struct DownloadMultipleFilesOptions {
-    reqwest_timeout: Option<std::time::Duration>,
+    pub(crate) reqwest_timeout: Option<Duration>,
+    /// Slowest expected transfer rate, files get `reqwest_timeout` plus the time to download them at this rate
+    pub min_bytes_per_sec: Option<u64>,
+    pub max_concurrent_downloads: usize,
+    /// Called with the downloaded and total bytes, at most once per `progress_interval` unless 1% more has been downloaded
+    pub on_progress: Option<ProgressCallback>,
+    pub progress_interval: Option<Duration>,
+    /// Called exactly once per file when it is stored or found already verified on disk
+    pub on_file_complete: Option<FileCompleteCallback>,
+    /// Applied to every file url right before it is downloaded, e.g. to redirect a maven host to a cache
+    pub url_rewrite: Option<UrlRewrite>,
+    /// Hashes the asset objects already on disk, when false their presence and size are enough
+    pub verify_assets: bool,
+    /// Sets the mtime of the downloaded files from their `Last-Modified` header
+    pub preserve_timestamps: bool,
 }
 
 impl Default for DownloadMultipleFilesOptions {
     fn default() -> Self {
         Self {
-            reqwest_timeout: Some(std::time::Duration::from_secs(10)),
+            reqwest_timeout: Some(Duration::from_secs(10)),
+            min_bytes_per_sec: Some(50 * 1024),
+            max_concurrent_downloads: 75,
+            on_progress: None,
+            progress_interval: Some(Duration::from_millis(100)),
+            on_file_complete: None,
+            url_rewrite: None,
+            verify_assets: true,
+            preserve_timestamps: false,
         }
     }
 }
 
-pub async fn download_single_file(path: PathBuf, file: FileDownloadMetadata, mut options: Option<DownloadMultipleFilesOptions>) {
+/// Fails when the file is still missing once its retries are exhausted
+pub async fn download_single_file(path: PathBuf, file: FileDownloadMetadata, mut options: Option<DownloadMultipleFilesOptions>) -> Result<(), Error> {
     options = options.or(Some(DownloadMultipleFilesOptions::default()));
-    println!("options: {:?}", options);
 
-    let downloader = Downloader::new(path, 75);
-    downloader.download_file_multiple(&vec![file], 0, 100).await;
+    let total_size = file.size.unwrap_or(0);
+    let file_path = file.path.clone();
+    let options = options.unwrap();
+    let downloader = Downloader::new(path, options.max_concurrent_downloads, options);
+    match downloader.download_file_multiple(&[file], total_size).await.is_empty() {
+        true => Ok(()),
+        false => Err(Error::other(format!("{} failed to download", file_path))),
+    }
+}
+
+#[derive(Debug)]
+pub struct InsufficientDiskSpace {
+    pub required: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for InsufficientDiskSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Insufficient disk space: {} bytes required, {} bytes available", self.required, self.available)
+    }
+}
+
+impl std::error::Error for InsufficientDiskSpace {}
+
+pub fn check_disk_space(path: &Path, files: &[FileDownloadMetadata]) -> Result<(), InsufficientDiskSpace> {
+    let required: u64 = files.iter()
+        .filter(|file| !path.join(standardize_path(&file.path)).exists())
+        .filter_map(|file| file.size)
+        .sum();
+
+    // The instance directory may not exist yet, measure the closest existing parent
+    let available = match path.ancestors().find(|ancestor| ancestor.exists()) {
+        Some(existing) => fs2::available_space(existing).unwrap_or(u64::MAX),
+        None => u64::MAX,
+    };
+
+    if required > available {
+        return Err(InsufficientDiskSpace { required, available });
+    }
+    Ok(())
 }
 
-pub async fn download_multiple_files(path: PathBuf, files: &Vec<FileDownloadMetadata>, mut options: Option<DownloadMultipleFilesOptions>) {
+/// Returns the files that couldn't be downloaded, it's up to the caller to tell whether they can be done without
+pub async fn download_multiple_files(path: PathBuf, files: &[FileDownloadMetadata], mut options: Option<DownloadMultipleFilesOptions>) -> Result<Vec<FileDownloadMetadata>, InsufficientDiskSpace> {
     options = options.or(Some(DownloadMultipleFilesOptions::default()));
-    println!("options: {:?}", options);
+    check_disk_space(&path, files)?;
+
+    let total_size = files.iter().filter_map(|file| file.size).sum();
+    let options = options.unwrap();
+    let downloader = Downloader::new(path, options.max_concurrent_downloads, options);
+    Ok(downloader.download_file_multiple(files, total_size).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_timeout_grows_with_the_size() {
+        let base = Duration::from_secs(10);
+        // 50 MiB at 1 MiB/s
+        assert_eq!(get_file_timeout(base, Some(50 * 1024 * 1024), Some(1024 * 1024)), Duration::from_secs(60));
+        assert_eq!(get_file_timeout(base, Some(512), Some(1024 * 1024)), base);
+        assert_eq!(get_file_timeout(base, None, Some(1024 * 1024)), base);
+        assert_eq!(get_file_timeout(base, Some(50 * 1024 * 1024), None), base);
+        assert_eq!(get_file_timeout(base, Some(50 * 1024 * 1024), Some(0)), base);
+    }
+
+    // Permission bits only exist on unix
+    #[cfg(unix)]
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn downloaded_file_gets_the_executable_bit() {
+        let directory = tempfile::tempdir().unwrap();
+        let java = directory.path().join("runtime/bin/java");
+        save_to_file(java.clone(), b"#!/bin/sh", true, None, None).await.unwrap();
+        assert_eq!(mode(&java) & 0o111, 0o111);
+
+        let release = directory.path().join("runtime/release");
+        save_to_file(release.clone(), b"JAVA_VERSION=17", false, None, None).await.unwrap();
+        assert_eq!(mode(&release) & 0o111, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn content_file_gets_the_executable_bit() {
+        let directory = tempfile::tempdir().unwrap();
+        let files = vec![FileDownloadMetadata {
+            type_: "CFILE".to_string(),
+            path: "runtime/bin/java".to_string(),
+            executable: Some(true),
+            sha1: None,
+            size: None,
+            url: None,
+            content: Some(b"#!/bin/sh".to_vec()),
+        }];
+
+        download_multiple_files(directory.path().to_path_buf(), &files, None).await.unwrap();
+        assert_eq!(mode(&directory.path().join("runtime/bin/java")) & 0o111, 0o111);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unwritable_single_file_is_reported() {
+        let directory = tempfile::tempdir().unwrap();
+        // A file where the parent folder should be
+        std::fs::write(directory.path().join("versions"), b"").unwrap();
+        let profile = FileDownloadMetadata {
+            type_: "CFILE".to_string(),
+            path: "versions/fabric-loader-0.14.21-1.20.1/fabric-loader-0.14.21-1.20.1.json".to_string(),
+            executable: Some(false),
+            sha1: None,
+            size: None,
+            url: None,
+            content: Some(b"{}".to_vec()),
+        };
 
-    let downloader = Downloader::new(path, 75);
-    downloader.download_file_multiple(files, 0, 100).await;
+        assert!(download_single_file(directory.path().to_path_buf(), profile, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn mismatching_sha1_leaves_nothing_behind() {
+        let directory = tempfile::tempdir().unwrap();
+        let file_path = directory.path().join("libraries/brigadier-1.1.8.jar");
+
+        let error = save_to_file(file_path.clone(), b"truncated", false, Some(&format!("{:x}", Sha1::digest(b"brigadier"))), None).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(!file_path.exists());
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn missing_space_is_reported() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = |path: &str, size: u64| FileDownloadMetadata {
+            type_: "Libraries".to_string(),
+            path: path.to_string(),
+            executable: Some(false),
+            sha1: None,
+            size: Some(size),
+            url: None,
+            content: None,
+        };
+        std::fs::write(directory.path().join("client.jar"), b"client").unwrap();
+
+        // Files already on disk don't count
+        assert!(check_disk_space(directory.path(), &[file("client.jar", u64::MAX)]).is_ok());
+        let error = check_disk_space(directory.path(), &[file("client.jar", u64::MAX), file("huge.jar", u64::MAX)]).unwrap_err();
+        assert_eq!(error.required, u64::MAX);
+        assert!(error.available < u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn missing_files_are_not_retried() {
+        let server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::path("/brigadier-1.1.8.jar"))
+            .respond_with(wiremock::ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let directory = tempfile::tempdir().unwrap();
+        let files = vec![FileDownloadMetadata {
+            type_: "Libraries".to_string(),
+            path: "libraries/brigadier-1.1.8.jar".to_string(),
+            executable: Some(false),
+            sha1: None,
+            size: None,
+            url: Some(format!("{}/brigadier-1.1.8.jar", server.uri())),
+            content: None,
+        }];
+
+        let failed = download_multiple_files(directory.path().to_path_buf(), &files, None).await.unwrap();
+        assert_eq!(failed.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn files_are_downloaded_without_any_concurrency() {
+        let server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::path("/brigadier-1.1.8.jar"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_bytes(b"brigadier".to_vec()))
+            .mount(&server)
+            .await;
+        let directory = tempfile::tempdir().unwrap();
+        let files = vec![FileDownloadMetadata {
+            type_: "Libraries".to_string(),
+            path: "libraries/brigadier-1.1.8.jar".to_string(),
+            executable: Some(false),
+            sha1: None,
+            size: None,
+            url: Some(format!("{}/brigadier-1.1.8.jar", server.uri())),
+            content: None,
+        }];
+        let options = DownloadMultipleFilesOptions { max_concurrent_downloads: 0, ..Default::default() };
+
+        let download = download_multiple_files(directory.path().to_path_buf(), &files, Some(options));
+        let failed = tokio::time::timeout(Duration::from_secs(10), download).await.expect("download never started").unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(std::fs::read(directory.path().join("libraries/brigadier-1.1.8.jar")).unwrap(), b"brigadier");
+    }
+
+    #[tokio::test]
+    async fn unchanged_file_is_trusted_from_the_cache() {
+        let directory = tempfile::tempdir().unwrap();
+        let file_path = directory.path().join("libraries/brigadier-1.1.8.jar");
+        save_to_file(file_path.clone(), b"brigadier", false, None, None).await.unwrap();
+        let (mtime, size) = get_file_stamp(&file_path).await.unwrap();
+        // Not the sha1 of the content, a rehash would tell them apart
+        let mut checksums = HashMap::from([("libraries/brigadier-1.1.8.jar".to_string(), ChecksumEntry { sha1: "cached".to_string(), mtime, size })]);
+
+        assert!(file_matches_cached_sha1(&mut checksums, "libraries/brigadier-1.1.8.jar", &file_path, "cached").await);
+    }
+
+    #[tokio::test]
+    async fn changed_file_is_hashed_again() {
+        let directory = tempfile::tempdir().unwrap();
+        let file_path = directory.path().join("libraries/brigadier-1.1.8.jar");
+        save_to_file(file_path.clone(), b"brigadier", false, None, None).await.unwrap();
+        let (mtime, size) = get_file_stamp(&file_path).await.unwrap();
+        let key = "libraries/brigadier-1.1.8.jar";
+        let mut checksums = HashMap::from([(key.to_string(), ChecksumEntry { sha1: "cached".to_string(), mtime, size })]);
+
+        save_to_file(file_path.clone(), b"patched brigadier", false, None, None).await.unwrap();
+        assert!(!file_matches_cached_sha1(&mut checksums, key, &file_path, "cached").await);
+        assert!(!checksums.contains_key(key));
+
+        let sha1 = format!("{:x}", Sha1::digest(b"patched brigadier"));
+        assert!(file_matches_cached_sha1(&mut checksums, key, &file_path, &sha1).await);
+        assert_eq!(checksums[key].sha1, sha1);
+    }
+
+    #[tokio::test]
+    async fn downloaded_files_are_added_to_the_cache() {
+        let server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::path("/brigadier-1.1.8.jar"))
+            .respond_with(wiremock::ResponseTemplate::new(200).set_body_bytes(b"brigadier".to_vec()))
+            .mount(&server)
+            .await;
+        let directory = tempfile::tempdir().unwrap();
+        let sha1 = format!("{:x}", Sha1::digest(b"brigadier"));
+        let files = vec![FileDownloadMetadata {
+            type_: "Libraries".to_string(),
+            path: "libraries/brigadier-1.1.8.jar".to_string(),
+            executable: Some(false),
+            sha1: Some(sha1.clone()),
+            size: Some(9),
+            url: Some(format!("{}/brigadier-1.1.8.jar", server.uri())),
+            content: None,
+        }];
+
+        assert!(download_multiple_files(directory.path().to_path_buf(), &files, None).await.unwrap().is_empty());
+        let checksums = load_checksums(directory.path()).await;
+        assert_eq!(checksums["libraries/brigadier-1.1.8.jar"].sha1, sha1);
+    }
+}
+
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use super::*;
+
+    #[test]
+    fn long_paths_get_the_extended_length_prefix() {
+        let instance = format!(r"C:\Users\Player\AppData\Roaming\{}", "instance".repeat(20));
+        let library = Path::new(&instance).join("libraries/org/example/deeply/nested/library/1.0/library-1.0.jar");
+        assert_eq!(extended_length_path(&library), PathBuf::from(format!(r"\\?\{}\libraries\org\example\deeply\nested\library\1.0\library-1.0.jar", instance)));
+
+        let share = Path::new(r"\\server\share").join(&instance[3..]).join("libraries/library-1.0.jar");
+        assert!(extended_length_path(&share).to_string_lossy().starts_with(r"\\?\UNC\server\share\"));
+
+        let short = Path::new(r"C:\instance").join("libraries/library-1.0.jar");
+        assert_eq!(extended_length_path(&short), PathBuf::from(r"C:\instance\libraries\library-1.0.jar"));
+    }
 }
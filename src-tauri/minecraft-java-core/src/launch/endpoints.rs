@@ -0,0 +1,90 @@
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    /// Version manifests, packages and java runtime manifests
+    pub meta: String,
+    /// Client jars and java runtime files
+    pub data: String,
+    pub libraries: String,
+    pub resources: String,
+    pub assets_manifest: String,
+    /// Java runtimes index, published by Mojang under a hashed product path that can be rotated
+    pub java_runtime_manifest: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            meta: "https://launchermeta.mojang.com".to_string(),
+            data: "https://piston-data.mojang.com".to_string(),
+            libraries: "https://libraries.minecraft.net".to_string(),
+            resources: "https://resources.download.minecraft.net".to_string(),
+            assets_manifest: "https://gist.githubusercontent.com/tacxou/fb1135d15a4772e28d5cf4223553f5fe/raw/cc1b41c2b1e954f32a0ac7b715c80b10e30cb590/assets_manifest.json".to_string(),
+            java_runtime_manifest: "https://launchermeta.mojang.com/v1/products/java-runtime/2ec0cc96c44e5a76b9c8b7c39df7210883d12871/all.json".to_string(),
+        }
+    }
+}
+
+impl Endpoints {
+    /// Serves everything from a single host, e.g. a local mock server or a full mirror
+    pub fn with_base(base: &str) -> Self {
+        let base = base.trim_end_matches('/');
+        Self {
+            meta: base.to_string(),
+            data: base.to_string(),
+            libraries: base.to_string(),
+            resources: base.to_string(),
+            assets_manifest: format!("{}/assets_manifest.json", base),
+            java_runtime_manifest: format!("{}/v1/products/java-runtime/all.json", base),
+        }
+    }
+
+    pub fn version_manifest(&self) -> String {
+        format!("{}/mc/game/version_manifest_v2.json", self.meta.trim_end_matches('/'))
+    }
+
+    /// Mirrors that only replace `meta` keep serving the index under the official path
+    pub fn java_runtime(&self) -> String {
+        self.rewrite(&self.java_runtime_manifest)
+    }
+
+    /// Points an url found in a Mojang manifest to the configured mirror, if any
+    pub fn rewrite(&self, url: &str) -> String {
+        let defaults = Endpoints::default();
+        let hosts = [
+            ("https://launchermeta.mojang.com", &self.meta, &defaults.meta),
+            ("https://piston-meta.mojang.com", &self.meta, &defaults.meta),
+            ("https://piston-data.mojang.com", &self.data, &defaults.data),
+            ("https://launcher.mojang.com", &self.data, &defaults.data),
+            ("https://libraries.minecraft.net", &self.libraries, &defaults.libraries),
+            ("https://resources.download.minecraft.net", &self.resources, &defaults.resources),
+        ];
+
+        for (official, mirror, default) in hosts {
+            if mirror == default {
+                continue;
+            }
+            if let Some(rest) = url.strip_prefix(official) {
+                return format!("{}{}", mirror.trim_end_matches('/'), rest);
+            }
+        }
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn java_runtime_index_follows_the_meta_mirror() {
+        let official = Endpoints::default();
+        assert_eq!(official.java_runtime(), official.java_runtime_manifest);
+
+        let mirror = Endpoints {
+            meta: "https://meta.mirror.lan".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(mirror.java_runtime(), "https://meta.mirror.lan/v1/products/java-runtime/2ec0cc96c44e5a76b9c8b7c39df7210883d12871/all.json");
+        assert_eq!(Endpoints::with_base("http://127.0.0.1:8080/").java_runtime(), "http://127.0.0.1:8080/v1/products/java-runtime/all.json");
+    }
+}
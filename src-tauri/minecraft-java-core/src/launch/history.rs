@@ -0,0 +1,132 @@
+use std::error::Error;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use tracing::{debug, warn};
+
+const HISTORY_FILE: &str = "play_history.json";
+
+/// Play sessions of an instance, summed up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayRecord {
+    pub instance: String,
+    /// Version of the last session
+    pub version: String,
+    pub last_played: DateTime<Utc>,
+    /// Seconds, from the process start to its exit
+    pub total_playtime: u64,
+}
+
+/// Instances exiting together must not overwrite each other's session
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Empty when nothing was played yet
+fn read_history(base_path: &Path) -> Result<Vec<PlayRecord>, Box<dyn Error>> {
+    match fs::read(base_path.join(HISTORY_FILE)) {
+        Ok(content) => Ok(serde_json::from_slice(&content)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Written next to the history then renamed over it, an interrupted write leaves the previous one whole
+fn write_history(base_path: &Path, history: &[PlayRecord]) -> std::io::Result<()> {
+    fs::create_dir_all(base_path)?;
+    let temp_file = base_path.join(format!(".{}.{}.part", HISTORY_FILE, Uuid::new_v4()));
+    let result = fs::File::create(&temp_file)
+        .and_then(|mut file| {
+            file.write_all(&serde_json::to_vec_pretty(history)?)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_file, base_path.join(HISTORY_FILE)));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_file);
+    }
+    result
+}
+
+/// Instances played from `base_path`, the most recent first
+pub fn get_play_history(base_path: &Path) -> Vec<PlayRecord> {
+    let mut history = read_history(base_path).unwrap_or_else(|e| {
+        warn!("Unable to read the play history of {:?}: {}", base_path, e);
+        vec![]
+    });
+    history.sort_by_key(|record| std::cmp::Reverse(record.last_played));
+    history
+}
+
+pub(crate) fn record_session(base_path: &Path, instance: &str, version: &str, started_at: DateTime<Utc>, playtime: Duration) -> Result<(), Box<dyn Error>> {
+    let _lock = HISTORY_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut history = match read_history(base_path) {
+        Ok(history) => history,
+        // Set aside rather than overwritten, the sessions it holds can still be recovered by hand
+        Err(e) if e.is::<serde_json::Error>() => {
+            let backup = base_path.join(format!("{}.corrupt-{}", HISTORY_FILE, Utc::now().timestamp()));
+            warn!("Play history of {:?} is corrupt ({}), moving it to {:?}", base_path, e, backup);
+            fs::rename(base_path.join(HISTORY_FILE), &backup)?;
+            vec![]
+        },
+        Err(e) => return Err(e),
+    };
+    match history.iter_mut().find(|record| record.instance == instance) {
+        Some(record) => {
+            record.version = version.to_string();
+            record.last_played = started_at;
+            record.total_playtime += playtime.as_secs();
+        },
+        None => history.push(PlayRecord {
+            instance: instance.to_string(),
+            version: version.to_string(),
+            last_played: started_at,
+            total_playtime: playtime.as_secs(),
+        }),
+    }
+
+    write_history(base_path, &history)?;
+    debug!("Session of {} recorded: {:?}", instance, playtime);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    #[test]
+    fn sessions_add_up_per_instance() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path();
+        let day = |day| Utc.with_ymd_and_hms(2024, 3, day, 20, 0, 0).unwrap();
+        assert!(get_play_history(path).is_empty());
+
+        record_session(path, "survival", "1.20.1", day(1), Duration::from_secs(3600)).unwrap();
+        record_session(path, "modpack", "1.19.2", day(2), Duration::from_secs(600)).unwrap();
+        record_session(path, "survival", "1.20.4", day(3), Duration::from_secs(1800)).unwrap();
+
+        assert_eq!(get_play_history(path), vec![
+            PlayRecord { instance: "survival".to_string(), version: "1.20.4".to_string(), last_played: day(3), total_playtime: 5400 },
+            PlayRecord { instance: "modpack".to_string(), version: "1.19.2".to_string(), last_played: day(2), total_playtime: 600 },
+        ]);
+    }
+
+    #[test]
+    fn corrupt_history_is_set_aside() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path();
+        let day = Utc.with_ymd_and_hms(2024, 3, 1, 20, 0, 0).unwrap();
+        fs::write(path.join(HISTORY_FILE), b"[{\"instance\": \"survival\", \"total_pl").unwrap();
+        assert!(get_play_history(path).is_empty());
+
+        record_session(path, "survival", "1.20.1", day, Duration::from_secs(60)).unwrap();
+        assert_eq!(get_play_history(path).len(), 1);
+        let files: Vec<String> = fs::read_dir(path).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+        let backup = files.iter().find(|name| name.starts_with("play_history.json.corrupt-")).expect("no backup");
+        assert_eq!(fs::read(path.join(backup)).unwrap(), b"[{\"instance\": \"survival\", \"total_pl");
+        assert_eq!(files.len(), 2, "{:?}", files);
+    }
+}
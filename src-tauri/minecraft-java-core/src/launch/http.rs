@@ -0,0 +1,349 @@
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Certificate, Client, Proxy, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::fs;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, on connection errors, 429 and 5xx responses
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential delay before the retry following `attempt`
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.min(16) as u32);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HttpConfig {
+    /// Proxy url used for every request, `HTTP_PROXY`/`HTTPS_PROXY`/`ALL_PROXY` are honored when unset
+    pub proxy: Option<String>,
+    /// Extra PEM encoded root certificates trusted on top of the system ones
+    pub root_certificates: Vec<Vec<u8>>,
+    /// Shared by metadata fetches and file downloads
+    pub retry: RetryPolicy,
+    /// Sent with every request, defaults to `rustmine/<version>`
+    pub user_agent: Option<String>,
+    /// Keeps the metadata responses on disk, they are revalidated with `If-None-Match`/`If-Modified-Since`
+    pub cache_dir: Option<PathBuf>,
+}
+
+struct HttpState {
+    client: Client,
+    retry: RetryPolicy,
+    cache_dir: Option<PathBuf>,
+}
+
+fn get_state() -> &'static RwLock<HttpState> {
+    static STATE: OnceLock<RwLock<HttpState>> = OnceLock::new();
+    STATE.get_or_init(|| RwLock::new(HttpState {
+        client: build_client(&HttpConfig::default()).unwrap_or_default(),
+        retry: RetryPolicy::default(),
+        cache_dir: None,
+    }))
+}
+
+fn build_client(config: &HttpConfig) -> reqwest::Result<Client> {
+    let user_agent = config.user_agent.clone().unwrap_or(format!("rustmine/{}", env!("CARGO_PKG_VERSION")));
+    let mut builder = Client::builder().user_agent(user_agent);
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    for pem in &config.root_certificates {
+        builder = builder.add_root_certificate(Certificate::from_pem(pem)?);
+    }
+    builder.build()
+}
+
+pub fn set_http_config(config: HttpConfig) -> reqwest::Result<()> {
+    let client = build_client(&config)?;
+    *get_state().write().unwrap_or_else(|poisoned| poisoned.into_inner()) = HttpState {
+        client,
+        retry: config.retry,
+        cache_dir: config.cache_dir,
+    };
+    Ok(())
+}
+
+pub fn get_client() -> Client {
+    get_state().read().unwrap_or_else(|poisoned| poisoned.into_inner()).client.clone()
+}
+
+pub fn get_retry_policy() -> RetryPolicy {
+    get_state().read().unwrap_or_else(|poisoned| poisoned.into_inner()).retry.clone()
+}
+
+fn get_cache_dir() -> Option<PathBuf> {
+    get_state().read().unwrap_or_else(|poisoned| poisoned.into_inner()).cache_dir.clone()
+}
+
+/// Validators of a cached response, its body is stored next to it
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Entry and body files of `url`, named after its sha1
+fn get_cache_paths(cache_dir: &Path, url: &str) -> (PathBuf, PathBuf) {
+    let key = format!("{:x}", Sha1::digest(url.as_bytes()));
+    (cache_dir.join(format!("{}.json", key)), cache_dir.join(format!("{}.body", key)))
+}
+
+async fn read_cache(cache_dir: &Path, url: &str) -> Option<(CacheEntry, Vec<u8>)> {
+    let (entry_path, body_path) = get_cache_paths(cache_dir, url);
+    let entry: CacheEntry = serde_json::from_slice(&fs::read(entry_path).await.ok()?).ok()?;
+    // Two urls sharing a sha1 would be served each other's body
+    if entry.url != url {
+        return None;
+    }
+    Some((entry, fs::read(body_path).await.ok()?))
+}
+
+async fn write_cache(cache_dir: &Path, entry: &CacheEntry, body: &[u8]) -> std::io::Result<()> {
+    let (entry_path, body_path) = get_cache_paths(cache_dir, &entry.url);
+    fs::create_dir_all(cache_dir).await?;
+    fs::write(body_path, body).await?;
+    fs::write(entry_path, serde_json::to_vec(entry)?).await
+}
+
+fn cached_response(body: Vec<u8>) -> Response {
+    Response::from(http::Response::new(body))
+}
+
+/// Stores a response carrying validators, the body is read to do so and handed back in a new response
+async fn store_response(cache_dir: &Path, url: &str, response: Response) -> reqwest::Result<Response> {
+    let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok()).map(String::from);
+    let entry = CacheEntry {
+        url: url.to_string(),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
+    if entry.etag.is_none() && entry.last_modified.is_none() {
+        return Ok(response);
+    }
+
+    let body = response.bytes().await?.to_vec();
+    if let Err(e) = write_cache(cache_dir, &entry, &body).await {
+        warn!("Unable to cache {}: {}", url, e);
+    }
+    Ok(cached_response(body))
+}
+
+pub(crate) fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// GET retried with backoff on transient failures, other error statuses are returned as errors.
+/// Served from the `cache_dir` of the `HttpConfig` when the server answers it didn't change
+pub async fn get_with_retry(url: &str, timeout: Duration) -> reqwest::Result<Response> {
+    send_with_retry(&get_client(), &get_retry_policy(), get_cache_dir().as_deref(), url, timeout).await
+}
+
+async fn send_with_retry(client: &Client, retry: &RetryPolicy, cache_dir: Option<&Path>, url: &str, timeout: Duration) -> reqwest::Result<Response> {
+    let cached = match cache_dir {
+        Some(cache_dir) => read_cache(cache_dir, url).await,
+        None => None,
+    };
+    let mut attempt = 0;
+    loop {
+        let mut request = client.get(url).timeout(timeout);
+        if let Some((entry, _)) = &cached {
+            if let Some(etag) = &entry.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &entry.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let result = request.send().await;
+        let retryable = match &result {
+            Ok(response) => is_transient(response.status()),
+            Err(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+        };
+        if !retryable || attempt >= retry.max_retries {
+            let response = result?;
+            return match (cache_dir, cached) {
+                (Some(_), Some((_, body))) if response.status() == StatusCode::NOT_MODIFIED => {
+                    debug!("{} not modified, served from the cache", url);
+                    Ok(cached_response(body))
+                },
+                (Some(cache_dir), _) if response.status().is_success() => store_response(cache_dir, url, response).await,
+                _ => response.error_for_status(),
+            };
+        }
+
+        let delay = retry.backoff(attempt);
+        match &result {
+            Ok(response) => warn!("{} answered {}, retrying in {:?}", url, response.status(), delay),
+            Err(e) => warn!("Request to {} failed: {}, retrying in {:?}", url, e, delay),
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+
+    /// Answers a single request with `body`, returns the proxy url and the request line it received
+    fn spawn_proxy(body: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+            request_line.trim_end().to_string()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_the_configured_proxy() {
+        let (proxy, requests) = spawn_proxy("proxied");
+        let client = build_client(&HttpConfig {
+            proxy: Some(proxy),
+            ..Default::default()
+        }).unwrap();
+
+        let body = client.get("http://mojang.invalid/version_manifest.json").send().await.unwrap().text().await.unwrap();
+        assert_eq!(body, "proxied");
+        assert_eq!(requests.join().unwrap(), "GET http://mojang.invalid/version_manifest.json HTTP/1.1");
+    }
+
+    #[test]
+    fn invalid_proxy_is_rejected() {
+        let config = HttpConfig {
+            proxy: Some("not a proxy url".to_string()),
+            ..Default::default()
+        };
+        assert!(build_client(&config).is_err());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let retry = RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        let delays: Vec<_> = (0..6).map(|attempt| retry.backoff(attempt).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(retry.backoff(usize::MAX), Duration::from_secs(1));
+    }
+
+    fn no_backoff(max_retries: usize) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET")).and(path("/version_manifest.json"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET")).and(path("/version_manifest.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("manifest"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let url = format!("{}/version_manifest.json", server.uri());
+        let response = send_with_retry(&Client::new(), &no_backoff(2), None, &url, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "manifest");
+    }
+
+    #[tokio::test]
+    async fn retries_give_up_after_the_maximum() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET")).and(path("/version_manifest.json"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(3)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET")).and(path("/missing.json"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = Client::new();
+        let url = format!("{}/version_manifest.json", server.uri());
+        let error = send_with_retry(&client, &no_backoff(2), None, &url, Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        let url = format!("{}/missing.json", server.uri());
+        let error = send_with_retry(&client, &no_backoff(2), None, &url, Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(error.status(), Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn unchanged_responses_are_served_from_the_cache() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET")).and(path("/version_manifest.json")).and(header("If-None-Match", "\"v1\""))
+            .respond_with(ResponseTemplate::new(304))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET")).and(path("/version_manifest.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("manifest").insert_header("ETag", "\"v1\""))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let url = format!("{}/version_manifest.json", server.uri());
+        let (client, retry) = (Client::new(), RetryPolicy::default());
+        for _ in 0..2 {
+            let response = send_with_retry(&client, &retry, Some(cache_dir.path()), &url, Duration::from_secs(5)).await.unwrap();
+            assert_eq!(response.text().await.unwrap(), "manifest");
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::process::{Child, ChildStdin};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use shared_child::SharedChild;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InstanceKind {
+    Client,
+    Server,
+}
+
+#[derive(Debug, Clone)]
+pub struct InstanceHandle {
+    pub name: String,
+    pub pid: u32,
+    pub kind: InstanceKind,
+}
+
+struct RunningInstance {
+    /// Shared with the threads waiting for the exit of the process
+    child: Arc<SharedChild>,
+    stdin: Option<ChildStdin>,
+    kind: InstanceKind,
+}
+
+fn get_registry() -> MutexGuard<'static, HashMap<String, RunningInstance>> {
+    static RUNNING_INSTANCES: OnceLock<Mutex<HashMap<String, RunningInstance>>> = OnceLock::new();
+    RUNNING_INSTANCES
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn register_instance(name: &str, mut child: Child, kind: InstanceKind) -> Result<InstanceHandle, Box<dyn Error>> {
+    let mut registry = get_registry();
+    if let Some(running) = registry.get(name) {
+        if running.child.try_wait()?.is_none() {
+            return Err(format!("Instance {} is already running", name).into());
+        }
+    }
+
+    let handle = InstanceHandle {
+        name: name.to_string(),
+        pid: child.id(),
+        kind,
+    };
+    debug!("Instance {} registered with pid {}", name, handle.pid);
+    let stdin = child.stdin.take();
+    registry.insert(name.to_string(), RunningInstance { child: Arc::new(SharedChild::new(child)?), stdin, kind });
+    Ok(handle)
+}
+
+/// Process of the instance registered as `name`, its exit can be waited for from any thread.
+/// It stays the same process when another one is later registered under that name
+pub(crate) fn get_process(name: &str) -> Option<Arc<SharedChild>> {
+    get_registry().get(name).map(|instance| instance.child.clone())
+}
+
+pub fn is_running(name: &str) -> bool {
+    let mut registry = get_registry();
+    let running = match registry.get(name) {
+        Some(instance) => matches!(instance.child.try_wait(), Ok(None)),
+        None => return false,
+    };
+
+    if !running {
+        registry.remove(name);
+    }
+    running
+}
+
+pub fn running_instances() -> Vec<String> {
+    let mut registry = get_registry();
+    registry.retain(|_, instance| matches!(instance.child.try_wait(), Ok(None)));
+    registry.keys().cloned().collect()
+}
+
+pub fn kill_instance(name: &str) -> Result<(), Box<dyn Error>> {
+    let instance = match get_registry().remove(name) {
+        Some(instance) => instance,
+        None => return Err(format!("Instance {} is not running", name).into()),
+    };
+
+    if instance.child.try_wait()?.is_some() {
+        return Ok(());
+    }
+
+    info!("Killing instance {}", name);
+    instance.child.kill()?;
+    instance.child.wait()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn terminate(child: &SharedChild) -> std::io::Result<()> {
+    let result = unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    if result == 0 { Ok(()) } else { Err(std::io::Error::last_os_error()) }
+}
+
+#[cfg(not(unix))]
+fn terminate(_child: &SharedChild) -> std::io::Result<()> {
+    // No polite signal on this platform, the timeout will fall back to a kill
+    Ok(())
+}
+
+pub async fn stop_instance(name: &str, timeout: Option<Duration>) -> Result<(), Box<dyn Error>> {
+    let mut instance = match get_registry().remove(name) {
+        Some(instance) => instance,
+        None => return Err(format!("Instance {} is not running", name).into()),
+    };
+
+    if instance.child.try_wait()?.is_some() {
+        return Ok(());
+    }
+
+    info!("Stopping instance {}", name);
+    let requested = match instance.kind {
+        InstanceKind::Server => match instance.stdin.as_mut() {
+            Some(stdin) => stdin.write_all(b"stop\n").and_then(|_| stdin.flush()),
+            None => terminate(&instance.child),
+        },
+        InstanceKind::Client => terminate(&instance.child),
+    };
+    if let Err(e) = requested {
+        warn!("Failed to request a graceful stop of {}: {}", name, e);
+    }
+
+    let deadline = Instant::now() + timeout.unwrap_or(Duration::from_secs(30));
+    while Instant::now() < deadline {
+        if instance.child.try_wait()?.is_some() {
+            debug!("Instance {} stopped gracefully", name);
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    warn!("Instance {} did not stop in time, killing it", name);
+    instance.child.kill()?;
+    instance.child.wait()?;
+    Ok(())
+}
@@ -1,21 +1,280 @@
-use std::path::PathBuf;
-use crate::launch::utils::get_loader_info;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use crate::launch::minecraft::json::{Arguments, Library, PackageInfo};
+use crate::launch::utils::{get_loader_info, parse_maven_coordinate};
 
 pub mod neoforge;
+pub mod fabric;
 
 #[derive(Debug, Clone)]
 pub struct Loader {
-    pub(crate) type_: String,
-    pub(crate) version: String,
-    pub(crate) build: String,
-    pub(crate) path: Option<PathBuf>,
-    pub(crate) enable: Option<bool>,
+    pub type_: String,
+    pub version: String,
+    pub build: String,
+    pub path: Option<PathBuf>,
+    pub enable: Option<bool>,
 }
 
-pub async fn install(path: PathBuf, loader_config: Loader) {
-    let loader_info = get_loader_info(loader_config.type_.as_str());
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoaderProfile {
+    pub(crate) id: String,
+    #[serde(rename = "inheritsFrom")]
+    pub(crate) inherits_from: Option<String>,
+    #[serde(rename = "mainClass")]
+    pub(crate) main_class: String,
+    pub(crate) arguments: Option<Arguments>,
+    #[serde(default)]
+    pub(crate) libraries: Vec<Library>,
+}
+
+#[derive(Debug)]
+pub enum LoaderError {
+    /// Not one of `neoforge`, `fabric`, `legacyfabric` or `quilt`
+    UnknownLoader(String),
+    BuildNotFound {
+        loader: String,
+        version: String,
+        build: String,
+    },
+    /// Metadata, profile or installer that couldn't be fetched or read
+    Metadata(String),
+    /// The installer couldn't be started or exited with an error
+    Installer(String),
+    /// No profile of the loader inherits from the version, see `find_installed`
+    NotInstalled {
+        loader: String,
+        version: String,
+    },
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoaderError::UnknownLoader(loader) => write!(f, "Unknown loader {}", loader),
+            LoaderError::BuildNotFound { loader, version, build } => write!(f, "No {} build {} found for {}", loader, build, version),
+            LoaderError::Metadata(reason) => write!(f, "Unable to install the loader: {}", reason),
+            LoaderError::Installer(reason) => write!(f, "Loader installer failed: {}", reason),
+            LoaderError::NotInstalled { loader, version } => write!(f, "{} is not installed for {}, install the instance first", loader, version),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {}
+
+impl From<Box<dyn std::error::Error>> for LoaderError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        LoaderError::Metadata(e.to_string())
+    }
+}
+
+/// Result of a loader install, its profile is merged with the vanilla package to launch
+#[derive(Debug, Clone)]
+pub struct InstalledLoader {
+    /// Build picked for `latest` and `recommended`, e.g. `0.14.21` or `1.20.1-47.1.79`
+    pub build: String,
+    pub main_class: String,
+    pub libraries: Vec<Library>,
+    pub(crate) profile: LoaderProfile,
+}
+
+impl InstalledLoader {
+    pub(crate) fn new(build: String, profile: LoaderProfile) -> Self {
+        Self {
+            build,
+            main_class: profile.main_class.clone(),
+            libraries: profile.libraries.clone(),
+            profile,
+        }
+    }
+}
+
+pub fn merge_loader_profile(package: &PackageInfo, profile: &LoaderProfile) -> PackageInfo {
+    let mut merged = package.clone();
+
+    // A vanilla library the loader also provides is dropped, e.g. an older asm, or both jars would be downloaded and loaded
+    let provided: HashSet<(String, String, Option<String>)> = profile.libraries.iter().map(|library| library_key(&library.name)).collect();
+    let mut libraries = profile.libraries.clone();
+    libraries.extend(merged.libraries.into_iter().filter(|library| !provided.contains(&library_key(&library.name))));
+    merged.libraries = libraries;
+    merged.main_class = profile.main_class.clone();
+
+    if let Some(profile_arguments) = &profile.arguments {
+        merged.arguments = match merged.arguments {
+            Some(mut arguments) => {
+                arguments.game.extend(profile_arguments.game.clone());
+                arguments.jvm.extend(profile_arguments.jvm.clone());
+                Some(arguments)
+            },
+            None => Some(profile_arguments.clone()),
+        };
+    }
+
+    merged
+}
+
+/// `group:artifact[:classifier]` of a maven coordinate, the library regardless of its version
+fn library_key(name: &str) -> (String, String, Option<String>) {
+    let coordinate = parse_maven_coordinate(name);
+    (coordinate.group, coordinate.artifact, coordinate.classifier)
+}
+
+/// Loaders shipping a java installer, they need a runtime before being installed
+pub fn runs_installer(loader_config: &Loader) -> bool {
+    loader_config.type_ == "neoforge"
+}
+
+/// Build of an installed profile id, e.g. `0.14.21` for `fabric-loader-0.14.21-1.20.1`
+fn get_profile_build(id: &str, loader_config: &Loader) -> Option<String> {
+    match loader_config.type_.as_str() {
+        "neoforge" => id.strip_prefix("neoforge-").map(String::from)
+            // Builds of the legacy `forge` artifact are prefixed by the version
+            .or_else(|| id.strip_prefix(&format!("{}-forge-", loader_config.version)).map(|build| format!("{}-{}", loader_config.version, build))),
+        "fabric" | "legacyfabric" => id.strip_prefix("fabric-loader-")?.strip_suffix(&format!("-{}", loader_config.version)).map(String::from),
+        "quilt" => id.strip_prefix("quilt-loader-")?.strip_suffix(&format!("-{}", loader_config.version)).map(String::from),
+        _ => None,
+    }
+}
+
+/// Profile of the loader already installed in `path`, read from disk only.
+/// `latest` and `recommended` pick the last installed build
+pub fn find_installed(path: &Path, loader_config: &Loader) -> Result<InstalledLoader, LoaderError> {
+    let mut installed: Vec<(SystemTime, InstalledLoader)> = vec![];
+    for entry in std::fs::read_dir(path.join("versions")).into_iter().flatten().flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let profile_path = entry.path().join(format!("{}.json", name));
+        let profile = match std::fs::read(&profile_path).ok().and_then(|content| serde_json::from_slice::<LoaderProfile>(&content).ok()) {
+            Some(profile) if profile.inherits_from.as_deref() == Some(loader_config.version.as_str()) => profile,
+            _ => continue,
+        };
+        let build = match get_profile_build(&profile.id, loader_config) {
+            Some(build) if matches!(loader_config.build.as_str(), "latest" | "recommended") || build == loader_config.build => build,
+            _ => continue,
+        };
+        let modified = entry.metadata().and_then(|metadata| metadata.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+        installed.push((modified, InstalledLoader::new(build, profile)));
+    }
+
+    installed.into_iter()
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, loader)| loader)
+        .ok_or_else(|| LoaderError::NotInstalled {
+            loader: format!("{} {}", loader_config.type_, loader_config.build),
+            version: loader_config.version.clone(),
+        })
+}
+
+/// `java` runs the installer of the loaders needing one, `java` from the PATH when unset
+pub async fn install(path: PathBuf, loader_config: Loader, java: Option<PathBuf>) -> Result<InstalledLoader, LoaderError> {
     match loader_config.type_.as_str() {
-        "neoforge" => neoforge::install_neoforge(path, loader_config, loader_info).await,
-        _ => println!("Loader not found")
+        "neoforge" => {
+            let loader_info = get_loader_info(loader_config.type_.as_str());
+            neoforge::install_neoforge(path, loader_config, loader_info, java.unwrap_or_else(|| PathBuf::from("java"))).await
+        },
+        "fabric" | "legacyfabric" | "quilt" => {
+            let loader_info = get_loader_info(loader_config.type_.as_str());
+            fabric::install_fabric(path, loader_config, loader_info).await
+        },
+        _ => Err(LoaderError::UnknownLoader(loader_config.type_)),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use super::*;
+
+    fn library(name: &str) -> Value {
+        json!({ "name": name, "downloads": { "artifact": { "path": "", "sha1": "", "size": 0, "url": "" } } })
+    }
+
+    fn vanilla_package() -> PackageInfo {
+        serde_json::from_value(json!({
+            "arguments": { "game": ["--username", "${auth_player_name}"], "jvm": ["-Djava.library.path=${natives_directory}"] },
+            "assetIndex": { "id": "5", "sha1": "", "size": 0, "totalSize": 0, "url": "" },
+            "assets": "5",
+            "downloads": { "client": { "sha1": "", "size": 0, "url": "" } },
+            "id": "1.20.1",
+            "libraries": [library("org.ow2.asm:asm:9.3"), library("com.mojang:brigadier:1.1.8")],
+            "mainClass": "net.minecraft.client.main.Main",
+            "minimumLauncherVersion": 21,
+            "time": "2023-06-12T13:25:51+00:00",
+            "type": "release",
+        })).unwrap()
+    }
+
+    fn fabric_profile(arguments: Option<Value>) -> LoaderProfile {
+        serde_json::from_value(json!({
+            "id": "fabric-loader-0.14.21-1.20.1",
+            "inheritsFrom": "1.20.1",
+            "mainClass": "net.fabricmc.loader.impl.launch.knot.KnotClient",
+            "arguments": arguments,
+            "libraries": [
+                { "name": "org.ow2.asm:asm:9.5", "url": "https://maven.fabricmc.net/" },
+                { "name": "net.fabricmc:fabric-loader:0.14.21", "url": "https://maven.fabricmc.net/" },
+            ],
+        })).unwrap()
+    }
+
+    fn loader(type_: &str, build: &str) -> Loader {
+        Loader {
+            type_: type_.to_string(),
+            version: "1.20.1".to_string(),
+            build: build.to_string(),
+            path: None,
+            enable: Some(true),
+        }
+    }
+
+    fn write_profile(path: &Path, id: &str, inherits_from: &str) {
+        let profile_path = path.join("versions").join(id);
+        std::fs::create_dir_all(&profile_path).unwrap();
+        std::fs::write(profile_path.join(format!("{}.json", id)), serde_json::to_vec(&json!({
+            "id": id,
+            "inheritsFrom": inherits_from,
+            "mainClass": "net.fabricmc.loader.impl.launch.knot.KnotClient",
+        })).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn installed_profile_is_found_by_build() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path();
+        write_profile(path, "fabric-loader-0.14.21-1.20.1", "1.20.1");
+        write_profile(path, "fabric-loader-0.14.22-1.19.4", "1.19.4");
+        write_profile(path, "1.20.1-forge-47.1.79", "1.20.1");
+
+        assert_eq!(find_installed(path, &loader("fabric", "latest")).unwrap().build, "0.14.21");
+        assert_eq!(find_installed(path, &loader("fabric", "0.14.21")).unwrap().profile.id, "fabric-loader-0.14.21-1.20.1");
+        assert_eq!(find_installed(path, &loader("neoforge", "1.20.1-47.1.79")).unwrap().profile.id, "1.20.1-forge-47.1.79");
+        assert!(matches!(find_installed(path, &loader("fabric", "0.14.22")), Err(LoaderError::NotInstalled { .. })));
+        assert!(matches!(find_installed(path, &loader("quilt", "latest")), Err(LoaderError::NotInstalled { .. })));
+    }
+
+    fn names(package: &PackageInfo) -> Vec<&str> {
+        package.libraries.iter().map(|library| library.name.as_str()).collect()
+    }
+
+    #[test]
+    fn loader_libraries_replace_the_vanilla_ones() {
+        let merged = merge_loader_profile(&vanilla_package(), &fabric_profile(None));
+        // The vanilla asm 9.3 is replaced by the loader one
+        assert_eq!(names(&merged), vec!["org.ow2.asm:asm:9.5", "net.fabricmc:fabric-loader:0.14.21", "com.mojang:brigadier:1.1.8"]);
+        assert_eq!(merged.main_class, "net.fabricmc.loader.impl.launch.knot.KnotClient");
+    }
+
+    #[test]
+    fn loader_arguments_are_appended() {
+        let package = vanilla_package();
+        let merged = merge_loader_profile(&package, &fabric_profile(Some(json!({ "jvm": ["-DFabricMcEmu= net.minecraft.client.main.Main "] }))));
+        let arguments = merged.arguments.unwrap();
+        assert_eq!(arguments.game.len(), 2);
+        assert_eq!(arguments.jvm, vec![json!("-Djava.library.path=${natives_directory}"), json!("-DFabricMcEmu= net.minecraft.client.main.Main ")]);
+
+        // Without loader arguments the vanilla ones are kept as they are
+        let merged = merge_loader_profile(&package, &fabric_profile(None));
+        assert_eq!(merged.arguments.unwrap().jvm.len(), 1);
+    }
+}
@@ -0,0 +1,89 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use serde_json::to_vec;
+use crate::launch::downloader::{download_single_file, FileDownloadMetadata};
+use crate::launch::loaders::{InstalledLoader, Loader, LoaderError, LoaderProfile};
+use crate::launch::utils::LoaderInfo;
+use crate::launch::http::get_with_retry;
+use tracing::info;
+
+pub async fn install_fabric(path: PathBuf, loader_config: Loader, loader_info: LoaderInfo) -> Result<InstalledLoader, LoaderError> {
+    let (build, profile) = get_loader_profile(loader_config, loader_info, None).await?;
+
+    download_single_file(path, FileDownloadMetadata {
+        type_: "CFILE".to_string(),
+        path: format!("versions/{}/{}.json", profile.id, profile.id),
+        content: Some(to_vec(&profile).map_err(|e| LoaderError::Metadata(e.to_string()))?),
+        executable: Some(false),
+        sha1: None,
+        size: None,
+        url: None,
+    }, None).await.map_err(|e| LoaderError::Metadata(e.to_string()))?;
+    info!("Loader profile installed: {}", profile.id);
+    Ok(InstalledLoader::new(build, profile))
+}
+
+#[derive(Debug, Clone)]
+pub struct ProfileOptions {
+    reqwest_timeout: Option<Duration>,
+}
+
+impl Default for ProfileOptions {
+    fn default() -> Self {
+        Self {
+            reqwest_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LoaderVersion {
+    pub version: String,
+    pub stable: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LoaderMetadata {
+    pub loader: Vec<LoaderVersion>,
+}
+
+async fn get_loader_metadata(url: String, options: &ProfileOptions) -> Result<LoaderMetadata, Box<dyn Error>> {
+    let timeout_duration = options.reqwest_timeout.unwrap_or(Duration::from_secs(10));
+
+    let data = get_with_retry(&url, timeout_duration).await?
+        .json::<LoaderMetadata>().await?;
+    Ok(data)
+}
+
+/// Returns the resolved build along with its profile
+pub async fn get_loader_profile(loader_config: Loader, loader_info: LoaderInfo, mut options: Option<ProfileOptions>) -> Result<(String, LoaderProfile), LoaderError> {
+    options = options.or(Some(ProfileOptions::default()));
+    let options = options.unwrap();
+
+    let metadata = get_loader_metadata(loader_info.metadata, &options).await?;
+    let build = match loader_config.build.as_str() {
+        "latest" => metadata.loader.first(),
+        "recommended" => metadata.loader.iter().find(|loader| loader.stable.unwrap_or(true)),
+        _ => metadata.loader.iter().find(|loader| loader.version == loader_config.build),
+    };
+    let build = match build {
+        Some(build) => build,
+        None => return Err(LoaderError::BuildNotFound {
+            loader: loader_config.type_,
+            version: loader_config.version,
+            build: loader_config.build,
+        }),
+    };
+
+    let url = loader_info.json.ok_or(LoaderError::Metadata(format!("No profile url for {}", loader_config.type_)))?
+        .replace("${version}", &loader_config.version)
+        .replace("${build}", &build.version);
+    let timeout_duration = options.reqwest_timeout.unwrap_or(Duration::from_secs(10));
+
+    let metadata_error = |e: reqwest::Error| LoaderError::Metadata(e.to_string());
+    let profile = get_with_retry(&url, timeout_duration).await.map_err(metadata_error)?
+        .json::<LoaderProfile>().await.map_err(metadata_error)?;
+    Ok((build.version.clone(), profile))
+}
@@ -1,16 +1,72 @@
 use std::error::Error;
-use std::path::PathBuf;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::time::Duration;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use crate::launch::downloader::{download_single_file, FileDownloadMetadata};
-use crate::launch::loaders::Loader;
+use crate::launch::loaders::{InstalledLoader, Loader, LoaderError, LoaderProfile};
 use crate::launch::utils::LoaderInfo;
+use crate::launch::http::get_with_retry;
+use tracing::{debug, info};
 
-pub async fn install_neoforge(path: PathBuf, loader_config: Loader, loader_info: LoaderInfo) {
-    let (file_path, old_api) = download_installer(path, loader_config, loader_info, None).await;
-    println!("file_path: {:?}", file_path);
-    println!("old_api: {:?}", old_api);
+pub async fn install_neoforge(path: PathBuf, loader_config: Loader, loader_info: LoaderInfo, java: PathBuf) -> Result<InstalledLoader, LoaderError> {
+    let (file_path, build, old_api) = download_installer(path.clone(), loader_config, loader_info, None).await?;
+    info!("Neoforge installer downloaded: {:?}", file_path);
+    debug!("old_api: {:?}", old_api);
+
+    let profile = install_profile(&java, &file_path, &path).await?;
+    Ok(InstalledLoader::new(build, profile))
+}
+
+/// Runs the installer unless a previous run already left its profile behind
+async fn install_profile(java: &Path, installer_path: &Path, path: &Path) -> Result<LoaderProfile, LoaderError> {
+    let profile = read_installer_profile(installer_path)?;
+    match is_profile_installed(path, &profile) {
+        true => debug!("Neoforge {} is already installed, skipping its installer", profile.id),
+        false => run_installer(java, installer_path, path).await?,
+    }
+    Ok(profile)
+}
+
+/// Whether the profile json and the libraries only the installer can generate are in `path`
+fn is_profile_installed(path: &Path, profile: &LoaderProfile) -> bool {
+    let profile_path = path.join("versions").join(&profile.id).join(format!("{}.json", profile.id));
+    profile_path.is_file() && profile.libraries.iter()
+        .filter_map(|library| library.downloads.artifact.as_ref())
+        // The downloadable ones are fetched with the other libraries
+        .filter(|artifact| artifact.url.is_empty())
+        .all(|artifact| path.join("libraries").join(&artifact.path).is_file())
+}
+
+/// Runs the installer headless, it generates the patched client jars into `path`
+async fn run_installer(java: &Path, installer_path: &Path, path: &Path) -> Result<(), LoaderError> {
+    // The installer refuses a folder that doesn't look like a launcher one
+    let launcher_profiles = path.join("launcher_profiles.json");
+    if !launcher_profiles.exists() {
+        tokio::fs::write(&launcher_profiles, br#"{"profiles":{}}"#).await.map_err(|e| LoaderError::Installer(e.to_string()))?;
+    }
+
+    info!("Running the Neoforge installer with {:?}", java);
+    let mut command = Command::new(java);
+    command.arg("-jar").arg(installer_path).arg("--installClient").arg(path).current_dir(path);
+    let output = tokio::task::spawn_blocking(move || command.output()).await
+        .map_err(|e| LoaderError::Installer(e.to_string()))?
+        .map_err(|e| LoaderError::Installer(format!("{:?}: {}", java, e)))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(LoaderError::Installer(format!("exited with {}: {}", output.status, stderr.trim())));
+    }
+    Ok(())
+}
+
+/// The installer ships the launch profile as `version.json`, next to the install profile
+fn read_installer_profile(installer_path: &Path) -> Result<LoaderProfile, LoaderError> {
+    let installer_error = |e: &dyn Error| LoaderError::Metadata(format!("{:?}: {}", installer_path, e));
+    let file = File::open(installer_path).map_err(|e| installer_error(&e))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| installer_error(&e))?;
+    let profile = archive.by_name("version.json").map_err(|e| installer_error(&e))?;
+    serde_json::from_reader(profile).map_err(|e| installer_error(&e))
 }
 
 #[derive(Debug, Clone)]
@@ -36,20 +92,17 @@ pub struct MetadataManifest {
 async fn get_metadata_manifest(url: String, options: ManifestsOptions) -> Result<MetadataManifest, Box<dyn Error>> {
     let timeout_duration = options.reqwest_timeout.unwrap_or(Duration::from_secs(10));
 
-    let client = Client::new();
-    let data = client
-        .get(url)
-        .timeout(timeout_duration)
-        .send().await?
+    let data = get_with_retry(&url, timeout_duration).await?
         .json::<MetadataManifest>().await?;
     Ok(data)
 }
 
-async fn download_installer(path: PathBuf, loader_config: Loader, loader_info: LoaderInfo, mut options: Option<ManifestsOptions>) -> (PathBuf, bool) {
+/// Returns the installer path, the resolved build and whether it comes from the legacy `forge` artifact
+async fn download_installer(path: PathBuf, loader_config: Loader, loader_info: LoaderInfo, mut options: Option<ManifestsOptions>) -> Result<(PathBuf, String, bool), LoaderError> {
     options = options.or(Some(ManifestsOptions::default()));
 
-    let legacy_metadata = get_metadata_manifest(loader_info.legacy_metadata.unwrap(), options.clone().unwrap()).await.unwrap();
-    let metadata = get_metadata_manifest(loader_info.metadata, options.clone().unwrap()).await.unwrap();
+    let legacy_metadata = get_metadata_manifest(loader_info.legacy_metadata.unwrap(), options.clone().unwrap()).await?;
+    let metadata = get_metadata_manifest(loader_info.metadata, options.clone().unwrap()).await?;
     let mut old_api = true;
     let mut versions = legacy_metadata.versions.iter()
         .filter(|v| v.contains(&format!("{}-", loader_config.version)))
@@ -65,26 +118,26 @@ async fn download_installer(path: PathBuf, loader_config: Loader, loader_info: L
             .collect::<Vec<String>>();
         old_api = false;
     }
-    if versions.is_empty() {
-        panic!("No versions found for Neoforge");
-    }
-
     let build = match loader_config.build.as_str() {
         "latest" | "recommended" => versions.last(),
         _ => versions.iter().find(|&loader| loader == loader_config.build.as_str()),
     };
-
-    if build.is_none() {
-        panic!("No build found for Neoforge");
-    }
+    let build = match build {
+        Some(build) => build.clone(),
+        None => return Err(LoaderError::BuildNotFound {
+            loader: loader_config.type_,
+            version: loader_config.version,
+            build: loader_config.build,
+        }),
+    };
 
     let neoforge_url = if old_api {
-        loader_info.legacy_install.unwrap().replace("${version}", build.unwrap())
+        loader_info.legacy_install.unwrap().replace("${version}", &build)
     } else {
-        loader_info.install.unwrap().replace("${version}", build.unwrap())
+        loader_info.install.unwrap().replace("${version}", &build)
     };
 
-    let file_path = path.join(format!("neoforge-{}-installer.jar", build.unwrap()));
+    let file_path = path.join(format!("neoforge-{}-installer.jar", build));
 
     download_single_file(path, FileDownloadMetadata {
         type_: "CFILE".to_string(),
@@ -94,7 +147,99 @@ async fn download_installer(path: PathBuf, loader_config: Loader, loader_info: L
         content: None,
         sha1: None,
         size: None,
-    }, None).await;
+    }, None).await.map_err(|e| LoaderError::Installer(e.to_string()))?;
 
-    return (file_path, old_api);
-}
\ No newline at end of file
+    Ok((file_path, build, old_api))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use serde_json::json;
+    use super::*;
+
+    fn write_installer(installer_path: &Path, profile: serde_json::Value) {
+        let mut installer = zip::ZipWriter::new(File::create(installer_path).unwrap());
+        installer.start_file("version.json", zip::write::FileOptions::default()).unwrap();
+        installer.write_all(&serde_json::to_vec(&profile).unwrap()).unwrap();
+        installer.finish().unwrap();
+    }
+
+    #[test]
+    fn profile_is_read_from_the_installer() {
+        let directory = tempfile::tempdir().unwrap();
+        let installer_path = directory.path().join("neoforge-47.1.79-installer.jar");
+        write_installer(&installer_path, json!({
+            "id": "1.20.1-forge-47.1.79",
+            "inheritsFrom": "1.20.1",
+            "mainClass": "cpw.mods.bootstraplauncher.BootstrapLauncher",
+            "libraries": [{ "name": "net.neoforged:forge:1.20.1-47.1.79:universal" }],
+        }));
+
+        let profile = read_installer_profile(&installer_path).unwrap();
+        assert_eq!(profile.main_class, "cpw.mods.bootstraplauncher.BootstrapLauncher");
+        assert_eq!(profile.libraries.len(), 1);
+        assert!(matches!(read_installer_profile(&directory.path().join("missing.jar")), Err(LoaderError::Metadata(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn installer_runs_with_the_given_java() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let java = directory.path().join("java");
+        std::fs::write(&java, "#!/bin/sh\necho \"$@\" > arguments\necho broken jar >&2\nexit 1\n").unwrap();
+        std::fs::set_permissions(&java, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let error = run_installer(&java, Path::new("installer.jar"), directory.path()).await.unwrap_err();
+        assert!(matches!(&error, LoaderError::Installer(reason) if reason.ends_with("broken jar")));
+        let arguments = std::fs::read_to_string(directory.path().join("arguments")).unwrap();
+        assert_eq!(arguments.trim(), format!("-jar installer.jar --installClient {}", directory.path().display()));
+        assert!(directory.path().join("launcher_profiles.json").exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn installer_is_skipped_once_its_profile_is_installed() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path();
+        let java = path.join("java");
+        std::fs::write(&java, "#!/bin/sh\necho run >> installer_runs\n").unwrap();
+        std::fs::set_permissions(&java, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let installer_path = path.join("neoforge-20.4.237-installer.jar");
+        let client = "net/neoforged/neoforge/20.4.237/neoforge-20.4.237-client.jar";
+        write_installer(&installer_path, json!({
+            "id": "neoforge-20.4.237",
+            "inheritsFrom": "1.20.4",
+            "mainClass": "cpw.mods.bootstraplauncher.BootstrapLauncher",
+            "libraries": [
+                { "name": "net.neoforged:neoforge:20.4.237:client", "downloads": { "artifact": { "path": client, "sha1": "", "size": 0, "url": "" } } },
+                {
+                    "name": "net.neoforged:bus:7.0.10",
+                    "downloads": { "artifact": { "path": "net/neoforged/bus/7.0.10/bus-7.0.10.jar", "sha1": "", "size": 0, "url": "https://maven.neoforged.net/releases/net/neoforged/bus/7.0.10/bus-7.0.10.jar" } },
+                },
+            ],
+        }));
+        let runs = || std::fs::read_to_string(path.join("installer_runs")).map_or(0, |runs| runs.lines().count());
+
+        install_profile(&java, &installer_path, path).await.unwrap();
+        assert_eq!(runs(), 1);
+
+        // What the installer would have left, the downloadable library isn't needed
+        std::fs::create_dir_all(path.join("versions/neoforge-20.4.237")).unwrap();
+        std::fs::write(path.join("versions/neoforge-20.4.237/neoforge-20.4.237.json"), "{}").unwrap();
+        std::fs::create_dir_all(path.join("libraries").join(client).parent().unwrap()).unwrap();
+        std::fs::write(path.join("libraries").join(client), "client").unwrap();
+        let profile = install_profile(&java, &installer_path, path).await.unwrap();
+        assert_eq!(profile.id, "neoforge-20.4.237");
+        assert_eq!(runs(), 1);
+
+        // A generated library gone missing brings the installer back
+        std::fs::remove_file(path.join("libraries").join(client)).unwrap();
+        install_profile(&java, &installer_path, path).await.unwrap();
+        assert_eq!(runs(), 2);
+    }
+}
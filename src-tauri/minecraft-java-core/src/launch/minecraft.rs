@@ -4,4 +4,3 @@ pub mod libraries;
 pub mod bundle;
 pub mod java;
 pub mod assets;
-pub mod loader;
\ No newline at end of file
@@ -1,48 +1,17 @@
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
-use serde_json::Value;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use tokio::fs;
+use crate::launch::auth::Authenticator;
+use crate::launch::minecraft::assets::AssetsManifest;
 use crate::launch::minecraft::json::{GameArgument, is_older, PackageInfo};
-use crate::launch::utils::{get_os_name, get_path_libraries};
+use crate::launch::utils::{get_native_classifier, get_os_name, get_path_libraries};
+use tracing::{debug, warn};
 
-struct XboxAccount {
-    xuid: Option<String>,
-    display_name: Option<String>,
-}
-
-struct Meta {
-    type_: String,
-}
-
-struct Authenticator {
-    access_token: String,
-    name: String,
-    uuid: String,
-    xbox_account: Option<XboxAccount>,
-    meta: Option<Meta>,
-    user_properties: String,
-    client_id: Option<String>,
-    client_token: Option<String>,
-}
-
-impl Authenticator {
-    fn get_xuid(&self) -> String {
-        match &self.xbox_account {
-            Some(xbox_account) => {
-                match &xbox_account.xuid {
-                    Some(xuid) => xuid.clone(),
-                    None => self.access_token.clone(),
-                }
-            },
-            None => self.access_token.clone(),
-        }
-    }
-
-    fn get_client_id_or_token(&self) -> &str {
-        self.client_id.as_deref()
-            .or(self.client_token.as_deref())
-            .unwrap_or(&self.access_token)
-    }
+#[derive(Debug, Clone)]
+pub struct ServerAddress {
+    pub host: String,
+    pub port: u16,
 }
 
 pub struct JvmMemory {
@@ -53,8 +22,20 @@ pub struct JvmMemory {
 pub struct ArgumentsOptions {
     pub(crate) has_natives: bool,
     pub(crate) memory: JvmMemory,
+    pub(crate) natives_dir: PathBuf,
+    pub(crate) game_directory: PathBuf,
+    pub(crate) assets_directory: Option<PathBuf>,
+    pub(crate) dock_icon: Option<String>,
+    /// JVM arguments of the loader profile
+    pub(crate) loader_jvm_arguments: Vec<String>,
+    pub(crate) profile_arguments: Vec<String>,
+    pub(crate) use_default_gc_flags: bool,
+    pub(crate) server: Option<ServerAddress>,
     pub game_arguments: Option<String>,
     pub jvm_arguments: Option<String>,
+    pub(crate) extra_classpath: Vec<PathBuf>,
+    pub(crate) agents: Vec<(PathBuf, Option<String>)>,
+    pub(crate) main_class_override: Option<String>,
 }
 
 #[derive(Debug)]
@@ -65,9 +46,10 @@ pub struct ArgumentsResult {
     pub(crate) main_class: String,
 }
 
-pub async fn get_arguments(path: &PathBuf, package: PackageInfo, options: &ArgumentsOptions) -> ArgumentsResult {
-    let game = get_game_arguments(path, &package, options);
-    let jvm = get_jvm_arguments(path, &package, options).await;
+/// Builds the launch arguments without touching the disk, the dock icon is resolved beforehand with `get_dock_icon`
+pub fn get_arguments(path: &PathBuf, package: PackageInfo, authenticator: &Authenticator, options: &ArgumentsOptions) -> ArgumentsResult {
+    let game = get_game_arguments(path, &package, authenticator, options);
+    let jvm = get_jvm_arguments(path, &package, options);
     let class_path = get_class_path(path, &package, options);
 
     ArgumentsResult {
@@ -78,24 +60,82 @@ pub async fn get_arguments(path: &PathBuf, package: PackageInfo, options: &Argum
     }
 }
 
-fn get_game_arguments(path: &PathBuf, package: &PackageInfo, options: &ArgumentsOptions) -> Vec<String> {
-    let authenticator = Authenticator {
-        access_token: "xxxxx".to_string(),
-        name: "Tacxounet".to_string(),
-        uuid: "xxxxx".to_string(),
-        xbox_account: Some(XboxAccount {
-            xuid: Option::from("2535436694008266".to_string()),
-            display_name: Option::from("xxxxx".to_string()),
-        }),
-        meta: Some(Meta {
-            type_: "msa".to_string(),
-        }),
-        user_properties: "xxxxx".to_string(),
-        client_id: Option::from("xxxxx".to_string()),
-        client_token: Option::from("xxxxx".to_string()),
-    };
+/// Launch arguments the game can't start with, checked by `validate_arguments` before spawning it
+#[derive(Debug)]
+pub struct MalformedArguments {
+    /// Missing class path or main class
+    pub problems: Vec<String>,
+    /// `${...}` placeholders left after the substitution
+    pub unresolved_placeholders: Vec<String>,
+}
 
-    let mut game: Vec<GameArgument> = match &package.minecraft_arguments {
+impl fmt::Display for MalformedArguments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed launch arguments")?;
+        if !self.problems.is_empty() {
+            write!(f, ": {}", self.problems.join(", "))?;
+        }
+        if !self.unresolved_placeholders.is_empty() {
+            write!(f, " (unresolved {})", self.unresolved_placeholders.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MalformedArguments {}
+
+/// Every `${...}` placeholder found in `argument`
+fn get_placeholders(argument: &str) -> Vec<String> {
+    let mut placeholders = Vec::new();
+    let mut rest = argument;
+    while let Some(start) = rest.find("${") {
+        match rest[start..].find('}') {
+            Some(end) => {
+                placeholders.push(rest[start..start + end + 1].to_string());
+                rest = &rest[start + end + 1..];
+            },
+            None => break,
+        }
+    }
+    placeholders
+}
+
+/// Catches what would only fail once the game is running, like an unsubstituted `${auth_access_token}`
+pub fn validate_arguments(arguments: &ArgumentsResult) -> Result<(), MalformedArguments> {
+    let mut problems = Vec::new();
+    match arguments.class_path.as_slice() {
+        [flag, class_path] if flag == "-cp" && !class_path.is_empty() => {},
+        _ => problems.push("empty class path".to_string()),
+    }
+    if arguments.main_class.trim().is_empty() {
+        problems.push("empty main class".to_string());
+    }
+
+    let mut seen = HashSet::new();
+    let unresolved_placeholders: Vec<String> = arguments.jvm.iter()
+        .chain(arguments.game.iter())
+        .flat_map(|argument| get_placeholders(argument))
+        .filter(|placeholder| seen.insert(placeholder.clone()))
+        .collect();
+
+    if problems.is_empty() && unresolved_placeholders.is_empty() {
+        return Ok(());
+    }
+    Err(MalformedArguments { problems, unresolved_placeholders })
+}
+
+/// Arguments the legacy launcher passed to classic, alpha and beta clients, whose JSON declares none
+const LEGACY_GAME_ARGUMENTS: &str = "${auth_player_name} ${auth_session} --gameDir ${game_directory} --assetsDir ${game_assets}";
+
+fn get_game_arguments(path: &Path, package: &PackageInfo, authenticator: &Authenticator, options: &ArgumentsOptions) -> Vec<String> {
+    let minecraft_arguments = match (&package.minecraft_arguments, &package.arguments) {
+        (None, None) => {
+            debug!("Version {} declares no game arguments, using the legacy ones", package.id);
+            Some(LEGACY_GAME_ARGUMENTS)
+        },
+        (minecraft_arguments, _) => minecraft_arguments.as_deref(),
+    };
+    let mut game: Vec<GameArgument> = match minecraft_arguments {
         Some(args) => args.split_whitespace()
             .map(String::from)
             .map(GameArgument::from)
@@ -113,15 +153,12 @@ fn get_game_arguments(path: &PathBuf, package: &PackageInfo, options: &Arguments
     table.insert("${user_type}".to_string(), authenticator.meta.as_ref().map_or("legacy".to_string(), |meta| meta.type_.clone()));
     table.insert("${version_name}".to_string(), package.id.clone());
     table.insert("${assets_index_name}".to_string(), package.asset_index.id.clone());
-    table.insert("${game_directory}".to_string(), path.as_os_str().to_str().unwrap().to_string());
-    // table.insert("${assets_root}".to_string(), is_older(&package)
-    //     .then(|| path.join("resources").to_str().unwrap().to_string())
-    //     .unwrap_or_else(|| path.join("assets").to_str().unwrap().to_string())
-    // );
-    table.insert("${assets_root}".to_string(), is_older(&package)
-        .then(|| "resources".to_string())
-        .unwrap_or_else(|| "assets".to_string())
-    );
+    table.insert("${game_directory}".to_string(), options.game_directory.to_string_lossy().to_string());
+    let assets_root = options.assets_directory.clone().unwrap_or_else(|| match is_older(package) {
+        true => path.join("resources"),
+        false => path.join("assets"),
+    });
+    table.insert("${assets_root}".to_string(), assets_root.to_string_lossy().to_string());
     table.insert("${game_assets}".to_string(), table.get("${assets_root}").unwrap().clone());
     table.insert("${version_type}".to_string(), package.type_.clone());
     table.insert("${clientid}".to_string(), authenticator.get_client_id_or_token().to_string());
@@ -139,6 +176,14 @@ fn get_game_arguments(path: &PathBuf, package: &PackageInfo, options: &Arguments
                 if let Some(s) = item.as_str() {
                     if let Some(replacement) = table.get(s) {
                         to_replace.push((i, replacement.clone()));
+                    } else if s.contains("${") {
+                        // The game would get the literal token, it goes away along with its flag
+                        warn!("Dropping game argument {} of {}, it has no value", s, package.id);
+                        let flag = i.checked_sub(1).filter(|&previous| game[previous].as_str().is_some_and(|arg| arg.starts_with("--")));
+                        if let Some(flag) = flag.filter(|flag| !to_remove.contains(flag)) {
+                            to_remove.push(flag);
+                        }
+                        to_remove.push(i);
                     }
                 }
             },
@@ -153,6 +198,18 @@ fn get_game_arguments(path: &PathBuf, package: &PackageInfo, options: &Arguments
         game.remove(*i);
     }
 
+    // Old versions don't template it, the game would then fall back to the default .minecraft folder.
+    // The oldest ones only take positional arguments and would read it as one
+    let uses_flags = game.iter().any(|arg| arg.as_str().is_some_and(|arg| arg.starts_with("--")));
+    if uses_flags && !game.iter().any(|arg| arg.as_str() == Some("--gameDir")) {
+        game.push(GameArgument::from("--gameDir".to_string()));
+        game.push(GameArgument::from(options.game_directory.to_string_lossy().to_string()));
+    }
+
+    if let Some(server) = &options.server {
+        game.extend(get_server_arguments(package, server).into_iter().map(GameArgument::from));
+    }
+
     game.extend(options.game_arguments.clone().unwrap_or_default().split_whitespace().map(String::from).map(GameArgument::from));
     game.iter()
         .filter_map(|arg| arg.as_str())
@@ -160,30 +217,77 @@ fn get_game_arguments(path: &PathBuf, package: &PackageInfo, options: &Arguments
         .collect()
 }
 
-async fn get_jvm_arguments(path: &PathBuf, package: &PackageInfo, options: &ArgumentsOptions) -> Vec<String> {
+/// Quick Play is declared by the version JSON through a `is_quick_play_multiplayer` feature rule
+fn supports_quick_play(package: &PackageInfo) -> bool {
+    package.arguments.as_ref().is_some_and(|arguments| arguments.game.iter().any(|argument| match argument {
+        GameArgument::ComplexWithRules { value, .. } => value.iter().any(|v| v == "--quickPlayMultiplayer"),
+        GameArgument::CatchAll(value) => value.to_string().contains("--quickPlayMultiplayer"),
+        _ => false,
+    }))
+}
+
+/// Clients before 1.6 only take positional arguments and would reject `--server`
+fn supports_legacy_server(package: &PackageInfo) -> bool {
+    match &package.minecraft_arguments {
+        Some(args) => args.split_whitespace().any(|arg| arg.starts_with("--")),
+        None => package.arguments.is_some(),
+    }
+}
+
+fn get_server_arguments(package: &PackageInfo, server: &ServerAddress) -> Vec<String> {
+    if supports_quick_play(package) {
+        return vec!["--quickPlayMultiplayer".to_string(), format!("{}:{}", server.host, server.port)];
+    }
+    if !supports_legacy_server(package) {
+        warn!("Version {} can't join a server on startup, ignoring {}:{}", package.id, server.host, server.port);
+        return vec![];
+    }
+    // Not declared by the version JSON, the client parses them anyway
+    vec!["--server".to_string(), server.host.clone(), "--port".to_string(), server.port.to_string()]
+}
+
+fn get_loader_jvm_arguments(path: &Path, package: &PackageInfo, options: &ArgumentsOptions) -> Vec<String> {
+    let separator = if get_os_name() == "windows" { ";" } else { ":" };
+    let library_directory = path.join("libraries").to_string_lossy().to_string();
+
+    options.loader_jvm_arguments.iter()
+        .map(|argument| argument
+            .replace("${library_directory}", &library_directory)
+            .replace("${classpath_separator}", separator)
+            .replace("${version_name}", &package.id))
+        .collect()
+}
+
+fn get_jvm_arguments(path: &Path, package: &PackageInfo, options: &ArgumentsOptions) -> Vec<String> {
     let os = get_os_name();
+    let natives = options.natives_dir.to_string_lossy().to_string();
     let mut opts = HashMap::new();
     opts.insert("windows", "-XX:HeapDumpPath=MojangTricksIntelDriversForPerformance_javaw.exe_minecraft.exe.heapdump");
-    opts.insert("macos", "-XstartOnFirstThread");
+    opts.insert("osx", "-XstartOnFirstThread");
     opts.insert("linux", "-Xss1M");
 
     let mut jvm = vec![
         format!("-Xms{}", options.memory.min),
         format!("-Xmx{}", options.memory.max),
-        "-XX:+UnlockExperimentalVMOptions".to_string(),
-        "-XX:G1NewSizePercent=20".to_string(),
-        "-XX:G1ReservePercent=20".to_string(),
-        "-XX:MaxGCPauseMillis=50".to_string(),
-        "-XX:G1HeapRegionSize=32M".to_string(),
-        "-Dfml.ignoreInvalidMinecraftCertificates=true".to_string(),
-        format!("-Djna.tmpdir=versions/{}/natives", package.id),
-        // format!("-Djna.tmpdir={}/versions/{}/natives", path.to_str().unwrap().to_string(), package.id),
-        format!("-Dorg.lwjgl.system.SharedLibraryExtractPath=versions/{}/natives", package.id),
-        // format!("-Dorg.lwjgl.system.SharedLibraryExtractPath={}/versions/{}/natives", path.to_str().unwrap().to_string(), package.id),
-        format!("-Dio.netty.native.workdir=versions/{}/natives", package.id),
-        // format!("-Dio.netty.native.workdir={}/versions/{}/natives", path.to_str().unwrap().to_string(), package.id),
     ];
 
+    if options.use_default_gc_flags {
+        jvm.extend([
+            "-XX:+UnlockExperimentalVMOptions".to_string(),
+            "-XX:G1NewSizePercent=20".to_string(),
+            "-XX:G1ReservePercent=20".to_string(),
+            "-XX:MaxGCPauseMillis=50".to_string(),
+            "-XX:G1HeapRegionSize=32M".to_string(),
+        ]);
+    }
+
+    jvm.extend([
+        "-Dfml.ignoreInvalidMinecraftCertificates=true".to_string(),
+        format!("-Djna.tmpdir={}", natives),
+        format!("-Dorg.lwjgl.system.SharedLibraryExtractPath={}", natives),
+        format!("-Dio.netty.native.workdir={}", natives),
+    ]);
+
     if package.minecraft_arguments.is_none() {
         if let Some(opt) = opts.get(os) {
             jvm.push(opt.to_string());
@@ -191,30 +295,126 @@ async fn get_jvm_arguments(path: &PathBuf, package: &PackageInfo, options: &Argu
     }
 
     if options.has_natives {
-        jvm.push(format!("-Djava.library.path={}/versions/{}/natives", path.to_str().unwrap().to_string(), package.id));
+        jvm.push(format!("-Djava.library.path={}", natives));
     }
 
-    if os == "macos" {
-        let path_assets = format!("{}/assets/indexes/{}.json", path.to_str().unwrap().to_string(), package.assets);
-        let assets_content = fs::read_to_string(path_assets).await.unwrap();
-        let assets: Value = serde_json::from_str(&assets_content).expect("Failed to parse assets index");
+    if os == "osx" {
+        jvm.push("-Xdock:name=Minecraft".to_string());
 
-        if let Some(icon_hash) = assets["objects"]["icons/minecraft.icns"]["hash"].as_str() {
-            let icon = format!("{}/assets/objects/{}/{}", path.to_str().unwrap().to_string(), &icon_hash[0..2], icon_hash);
-            jvm.push("-Xdock:name=Minecraft".to_string());
+        if let Some(icon) = &options.dock_icon {
             jvm.push(format!("-Xdock:icon={}", icon));
         }
     }
 
-    jvm.extend(options.jvm_arguments.clone().unwrap_or_default().split_whitespace().map(String::from));
+    jvm.extend(get_loader_jvm_arguments(path, package, options));
+
+    // User arguments are merged last so they win over both the defaults and the profile
+    let jvm = merge_jvm_arguments(jvm, options.profile_arguments.clone());
+    let user_arguments = options.jvm_arguments.clone().unwrap_or_default().split_whitespace().map(String::from).collect();
+    let mut jvm = merge_jvm_arguments(jvm, user_arguments);
+    jvm.extend(get_agent_arguments(&options.agents));
     jvm
 }
 
+/// Agents are kept apart from the merge, several of them can be attached at once
+fn get_agent_arguments(agents: &[(PathBuf, Option<String>)]) -> Vec<String> {
+    agents.iter()
+        .map(|(agent, agent_options)| match agent_options {
+            Some(agent_options) => format!("-javaagent:{}={}", agent.to_string_lossy(), agent_options),
+            None => format!("-javaagent:{}", agent.to_string_lossy()),
+        })
+        .collect()
+}
+
+pub async fn get_dock_icon(path: &Path, package: &PackageInfo) -> Option<String> {
+    let path_assets = path.join("assets").join("indexes").join(format!("{}.json", package.assets));
+    let assets_content = match fs::read_to_string(&path_assets).await {
+        Ok(content) => content,
+        Err(e) => {
+            warn!("Unable to read assets index {:?}, skipping dock icon: {}", path_assets, e);
+            return None;
+        },
+    };
+    let assets: AssetsManifest = match serde_json::from_str(&assets_content) {
+        Ok(assets) => assets,
+        Err(e) => {
+            warn!("Unable to parse assets index {:?}, skipping dock icon: {}", path_assets, e);
+            return None;
+        },
+    };
+
+    // Older asset indexes don't ship the icon
+    match assets.objects.get("icons/minecraft.icns").and_then(|icon| icon.get_object_key()) {
+        Some(object_key) => Some(path.join("assets").join("objects").join(object_key).to_string_lossy().to_string()),
+        None => {
+            debug!("No dock icon in assets index {}", package.assets);
+            None
+        },
+    }
+}
+
+/// Key identifying what a JVM flag configures, so `-XX:+UseG1GC` and `-XX:-UseG1GC` collide
+fn jvm_flag_key(argument: &str) -> String {
+    if let Some(option) = argument.strip_prefix("-XX:") {
+        let option = option.trim_start_matches(['+', '-']);
+        return format!("-XX:{}", option.split('=').next().unwrap_or(option));
+    }
+    for prefix in ["-Xms", "-Xmx", "-Xss", "-Xmn"] {
+        if argument.starts_with(prefix) {
+            return prefix.to_string();
+        }
+    }
+    if argument.starts_with("-D") {
+        return argument.split('=').next().unwrap_or(argument).to_string();
+    }
+    argument.to_string()
+}
+
+/// Flags taking their value as the next argument
+const JVM_FLAGS_WITH_VALUE: [&str; 9] = ["-p", "--module-path", "-cp", "-classpath", "--class-path", "--add-opens", "--add-exports", "--add-reads", "--add-modules"];
+/// Flags given once, the others can be repeated with different values as NeoForge does with `--add-opens`
+const SINGLE_VALUE_JVM_FLAGS: [&str; 5] = ["-p", "--module-path", "-cp", "-classpath", "--class-path"];
+
+/// Splits the arguments into flags, each kept along with its separate value
+fn group_jvm_arguments(arguments: Vec<String>) -> Vec<Vec<String>> {
+    let mut groups = Vec::new();
+    let mut arguments = arguments.into_iter();
+    while let Some(argument) = arguments.next() {
+        let takes_value = JVM_FLAGS_WITH_VALUE.contains(&argument.as_str());
+        let mut group = vec![argument];
+        if takes_value {
+            group.extend(arguments.next());
+        }
+        groups.push(group);
+    }
+    groups
+}
+
+/// Key of a flag and its separate value, a repeatable flag only collides with the same value
+fn jvm_group_key(group: &[String]) -> String {
+    match group {
+        [flag, value] if !SINGLE_VALUE_JVM_FLAGS.contains(&flag.as_str()) => format!("{} {}", flag, value),
+        [flag, ..] => jvm_flag_key(flag),
+        [] => String::new(),
+    }
+}
+
+/// Appends `overrides` to `defaults`, dropping the defaults they conflict with
+pub(crate) fn merge_jvm_arguments(defaults: Vec<String>, overrides: Vec<String>) -> Vec<String> {
+    let overrides = group_jvm_arguments(overrides);
+    let keys: HashSet<String> = overrides.iter().map(|group| jvm_group_key(group)).collect();
+    group_jvm_arguments(defaults).into_iter()
+        .filter(|group| !keys.contains(&jvm_group_key(group)))
+        .chain(overrides)
+        .flatten()
+        .collect()
+}
+
 
 fn filter_class_path(class_path: Vec<String>) -> Vec<String> {
     let mut last_segments = HashSet::new();
     class_path.into_iter().filter(|url| {
-        url.split('/').last().map_or(false, |last_segment| last_segments.insert(last_segment.to_string()))
+        url.split(['/', '\\']).next_back().is_some_and(|last_segment| last_segments.insert(last_segment.to_string()))
     }).collect()
 }
 
@@ -223,58 +423,473 @@ pub struct ClassPath {
     pub(crate) class_path: Vec<String>,
 }
 
-fn get_class_path(path: &PathBuf, package: &PackageInfo, _options: &ArgumentsOptions) -> ClassPath {
+fn get_class_path(path: &PathBuf, package: &PackageInfo, options: &ArgumentsOptions) -> ClassPath {
     let mut class_path: Vec<String> = Vec::new();
     let mut libraries = package.libraries.clone();
-    // if let Some(loader) = loader_json {
-    //     if let Some(loader_libraries) = loader.get("libraries") {
-    //         libraries.extend(loader_libraries.as_array().unwrap().clone());
-    //     }
-    // }
     let mut seen = HashSet::new();
-    libraries = libraries.into_iter().filter(|lib| seen.insert(lib.name.clone())).collect();
+    libraries.retain(|lib| seen.insert(lib.name.clone()));
 
-    let platform = get_os_name();
     for lib in libraries {
+        if !lib.is_allowed() {
+            continue;
+        }
+        let mut native_string = None;
         if let Some(natives) = &lib.natives {
-            let native = natives.get(platform);
-
-            if native.is_none() {
-                continue;
-            }
-        } else if let Some(rules) = &lib.rules {
-            if let Some(rule) = rules.get(0) {
-                if let Some(os) = &rule.os {
-                    if os.get("name").unwrap().as_str() != platform {
-                        continue;
-                    }
-                }
+            match get_native_classifier(natives, lib.downloads.classifiers.as_ref()) {
+                Some(native) => native_string = Some(format!("-{}", native)),
+                None => continue,
             }
         }
 
-        // let path_libraries = get_path_libraries(&lib.name, None, None);
-        // let lib_path = if let Some(loader) = &lib.loader {
-        //     format!("{}/libraries/{}", loader, path.display())
-        // } else {
-        //     format!("{}/libraries/{}", path.to_str().unwrap().to_string(), path_libraries)
-        // };
-
-        let lib_parse = get_path_libraries(&lib.name, None, None);
-        // let lib_path = format!("{}/libraries/{}/{}", path.to_str().unwrap().to_string(), lib_parse.path, lib_parse.name);
+        let lib_parse = get_path_libraries(&lib.name, native_string.as_deref(), None);
         let lib_path = format!("libraries/{}/{}", lib_parse.path, lib_parse.name);
 
         class_path.push(lib_path);
     }
 
     // TODO: loader + mcp
-    // class_path.push(format!("{}/versions/{}/{}.jar", path.to_str().unwrap().to_string(), package.id, package.id));
     class_path.push(format!("versions/{}/{}.jar", package.id, package.id));
 
+    // Entries are relative to the working directory, which is no longer the instance with a separate game directory
+    if options.game_directory != *path {
+        class_path = class_path.iter().map(|entry| path.join(entry).to_string_lossy().to_string()).collect();
+    }
+    let mut filter_class_path = filter_class_path(class_path);
+    // Two jars given by the user can share a name, only the same path listed twice is dropped
+    for entry in &options.extra_classpath {
+        let entry = entry.to_string_lossy().to_string();
+        if !filter_class_path.contains(&entry) {
+            filter_class_path.push(entry);
+        }
+    }
+
     let separator = if get_os_name() == "windows" { ";" } else { ":" };
-    let filter_class_path = filter_class_path(class_path.clone());
 
     ClassPath {
-        main_class: package.main_class.clone(),
-        class_path: vec!["-cp", filter_class_path.join(separator).as_str()].iter().map(|s| s.to_string()).collect(),
+        main_class: options.main_class_override.clone().unwrap_or_else(|| package.main_class.clone()),
+        class_path: vec!["-cp".to_string(), filter_class_path.join(separator)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use serde_json::{json, Value};
+    use super::*;
+
+    /// Smallest 1.20.1 package accepted by the parser, with the given libraries
+    fn package(libraries: Value) -> PackageInfo {
+        serde_json::from_value(json!({
+            "arguments": {
+                "game": ["--username", "${auth_player_name}", "--gameDir", "${game_directory}"],
+                "jvm": [],
+            },
+            "assetIndex": { "id": "5", "sha1": "", "size": 0, "totalSize": 0, "url": "" },
+            "assets": "5",
+            "downloads": { "client": { "sha1": "", "size": 0, "url": "" } },
+            "id": "1.20.1",
+            "libraries": libraries,
+            "mainClass": "net.minecraft.client.main.Main",
+            "minimumLauncherVersion": 21,
+            "time": "2023-06-12T13:25:51+00:00",
+            "type": "release",
+        })).unwrap()
+    }
+
+    /// Trimmed Mojang 1.20.1 package, see `tests/fixtures`
+    fn fixture_package() -> PackageInfo {
+        serde_json::from_str(include_str!("../../../tests/fixtures/1.20.1.json")).unwrap()
+    }
+
+    /// Offline player with a fixed uuid, so the arguments are the same on every run
+    fn authenticator() -> Authenticator {
+        Authenticator {
+            uuid: "f84c6a790a4e45e0879bcd49ebd4c4e2".to_string(),
+            ..Authenticator::offline("Player")
+        }
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    /// Value following `flag` in a command line
+    fn value_of(arguments: &[String], flag: &str) -> Option<String> {
+        let position = arguments.iter().position(|argument| argument == flag)?;
+        arguments.get(position + 1).cloned()
+    }
+
+    fn options(path: &Path) -> ArgumentsOptions {
+        ArgumentsOptions {
+            has_natives: false,
+            memory: JvmMemory { min: "1G".to_string(), max: "2G".to_string() },
+            natives_dir: path.join("versions/1.20.1/natives"),
+            game_directory: path.to_path_buf(),
+            assets_directory: None,
+            dock_icon: None,
+            loader_jvm_arguments: vec![],
+            profile_arguments: vec![],
+            use_default_gc_flags: false,
+            server: None,
+            game_arguments: None,
+            jvm_arguments: None,
+            extra_classpath: vec![],
+            agents: vec![],
+            main_class_override: None,
+        }
+    }
+
+    fn class_path_entries(class_path: &ClassPath) -> Vec<String> {
+        let separator = if get_os_name() == "windows" { ";" } else { ":" };
+        class_path.class_path[1].split(separator).map(String::from).collect()
+    }
+
+    #[test]
+    fn class_path_includes_the_native_classifier_jar() {
+        let path = PathBuf::from("/instance");
+        let package = package(json!([{
+            "name": "org.lwjgl.lwjgl:lwjgl-platform:2.9.4-nightly-20150209",
+            "natives": { "linux": "natives-linux", "osx": "natives-osx", "windows": "natives-windows" },
+            "downloads": { "classifiers": {
+                "natives-linux": { "path": "", "sha1": "", "size": 0, "url": "" },
+                "natives-osx": { "path": "", "sha1": "", "size": 0, "url": "" },
+                "natives-windows": { "path": "", "sha1": "", "size": 0, "url": "" },
+            } },
+        }]));
+
+        let entries = class_path_entries(&get_class_path(&path, &package, &options(&path)));
+        assert_eq!(entries, vec![
+            format!("libraries/org/lwjgl/lwjgl/lwjgl-platform/2.9.4-nightly-20150209/lwjgl-platform-2.9.4-nightly-20150209-natives-{}.jar", get_os_name()),
+            "versions/1.20.1/1.20.1.jar".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn class_path_follows_the_last_matching_rule() {
+        let path = PathBuf::from("/instance");
+        // b1.7.3 ships a nightly LWJGL for macOS only, the release one everywhere else
+        let package = package(json!([
+            {
+                "name": "org.lwjgl.lwjgl:lwjgl:2.9.0",
+                "rules": [{ "action": "allow" }, { "action": "disallow", "os": { "name": "osx" } }],
+            },
+            {
+                "name": "org.lwjgl.lwjgl:lwjgl:2.9.1-nightly-20130708-debug3",
+                "rules": [{ "action": "allow", "os": { "name": "osx" } }],
+            },
+            {
+                "name": "net.java.jinput:jinput:2.0.5",
+                "rules": [{ "action": "allow" }, { "action": "disallow", "os": { "arch": "x86" } }],
+            },
+        ]));
+
+        let entries = class_path_entries(&get_class_path(&path, &package, &options(&path)));
+        let lwjgl = match get_os_name() {
+            "osx" => "libraries/org/lwjgl/lwjgl/lwjgl/2.9.1-nightly-20130708-debug3/lwjgl-2.9.1-nightly-20130708-debug3.jar",
+            _ => "libraries/org/lwjgl/lwjgl/lwjgl/2.9.0/lwjgl-2.9.0.jar",
+        };
+        assert_eq!(entries[0], lwjgl);
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn class_path_skips_natives_without_a_classifier() {
+        let path = PathBuf::from("/instance");
+        let package = package(json!([{
+            "name": "org.lwjgl.lwjgl:lwjgl-platform:2.9.4-nightly-20150209",
+            "natives": { "solaris": "natives-solaris" },
+        }]));
+
+        let entries = class_path_entries(&get_class_path(&path, &package, &options(&path)));
+        assert_eq!(entries, vec!["versions/1.20.1/1.20.1.jar".to_string()]);
+    }
+
+    #[test]
+    fn extra_class_path_is_appended_and_deduplicated() {
+        let path = PathBuf::from("/instance");
+        let options = ArgumentsOptions {
+            extra_classpath: vec![PathBuf::from("/opt/profiler/agent.jar"), PathBuf::from("/opt/other/agent.jar"), PathBuf::from("/opt/profiler/agent.jar")],
+            ..options(&path)
+        };
+
+        let entries = class_path_entries(&get_class_path(&path, &package(json!([])), &options));
+        assert_eq!(entries, vec!["versions/1.20.1/1.20.1.jar", "/opt/profiler/agent.jar", "/opt/other/agent.jar"]);
+    }
+
+    #[test]
+    fn agents_are_passed_to_the_jvm() {
+        let path = PathBuf::from("/instance");
+        let options = ArgumentsOptions {
+            agents: vec![
+                (PathBuf::from("/opt/mixin/agent.jar"), None),
+                (PathBuf::from("/opt/profiler/agent.jar"), Some("port=8849,nowait".to_string())),
+            ],
+            ..options(&path)
+        };
+
+        let jvm = get_jvm_arguments(&path, &package(json!([])), &options);
+        assert_eq!(jvm[jvm.len() - 2..], strings(&["-javaagent:/opt/mixin/agent.jar", "-javaagent:/opt/profiler/agent.jar=port=8849,nowait"]));
+    }
+
+    #[test]
+    fn main_class_can_be_overridden() {
+        let path = PathBuf::from("/instance");
+        let options = ArgumentsOptions {
+            main_class_override: Some("com.example.client.Main".to_string()),
+            ..options(&path)
+        };
+
+        let arguments = get_arguments(&path, fixture_package(), &authenticator(), &options);
+        assert_eq!(arguments.main_class, "com.example.client.Main");
+    }
+
+    fn legacy_package(minecraft_arguments: &str) -> PackageInfo {
+        let mut package = package(json!([]));
+        package.arguments = None;
+        package.minecraft_arguments = Some(minecraft_arguments.to_string());
+        package
+    }
+
+    #[test]
+    fn server_is_joined_the_way_the_version_supports() {
+        let server = ServerAddress { host: "play.example.net".to_string(), port: 25565 };
+        let beta = legacy_package("${auth_player_name} ${auth_session}");
+        let release = legacy_package("--username ${auth_player_name} --session ${auth_session}");
+
+        assert!(supports_quick_play(&fixture_package()));
+        assert!(!supports_quick_play(&package(json!([]))));
+        assert!(!supports_legacy_server(&beta));
+        assert!(supports_legacy_server(&release));
+        assert!(supports_legacy_server(&package(json!([]))));
+
+        assert_eq!(get_server_arguments(&fixture_package(), &server), strings(&["--quickPlayMultiplayer", "play.example.net:25565"]));
+        assert_eq!(get_server_arguments(&release, &server), strings(&["--server", "play.example.net", "--port", "25565"]));
+        assert!(get_server_arguments(&beta, &server).is_empty());
+    }
+
+    #[test]
+    fn jvm_flag_keys() {
+        assert_eq!(jvm_flag_key("-Xmx4G"), "-Xmx");
+        assert_eq!(jvm_flag_key("-XX:+UseG1GC"), "-XX:UseG1GC");
+        assert_eq!(jvm_flag_key("-XX:-UseG1GC"), "-XX:UseG1GC");
+        assert_eq!(jvm_flag_key("-XX:MaxGCPauseMillis=50"), "-XX:MaxGCPauseMillis");
+        assert_eq!(jvm_flag_key("-Dfile.encoding=UTF-8"), "-Dfile.encoding");
+        assert_eq!(jvm_flag_key("-XstartOnFirstThread"), "-XstartOnFirstThread");
+    }
+
+    #[test]
+    fn merged_jvm_arguments_let_the_overrides_win() {
+        let merged = merge_jvm_arguments(
+            strings(&["-Xms1G", "-Xmx2G", "-XX:+UseG1GC", "-Dfile.encoding=Cp1252", "-Dlog4j2.formatMsgNoLookups=true"]),
+            strings(&["-Xmx8G", "-XX:-UseG1GC", "-Dfile.encoding=UTF-8"]),
+        );
+        assert_eq!(merged, strings(&["-Xms1G", "-Dlog4j2.formatMsgNoLookups=true", "-Xmx8G", "-XX:-UseG1GC", "-Dfile.encoding=UTF-8"]));
+    }
+
+    #[test]
+    fn module_flags_are_merged_with_their_values() {
+        let neoforge = strings(&[
+            "-p", "libraries/cpw/mods/bootstraplauncher/1.1.2/bootstraplauncher-1.1.2.jar",
+            "--add-modules", "ALL-MODULE-PATH",
+            "--add-opens", "java.base/java.util.jar=cpw.mods.securejarhandler",
+            "--add-opens", "java.base/java.lang.invoke=cpw.mods.securejarhandler",
+            "--add-exports", "java.base/sun.security.util=cpw.mods.securejarhandler",
+            "-Xmx2G",
+        ]);
+        let merged = merge_jvm_arguments(neoforge, strings(&[
+            "--add-opens", "java.base/java.lang=ALL-UNNAMED",
+            "--add-opens", "java.base/java.util.jar=cpw.mods.securejarhandler",
+            "-p", "mods/modules",
+            "-Xmx8G",
+        ]));
+        assert_eq!(merged, strings(&[
+            "--add-modules", "ALL-MODULE-PATH",
+            "--add-opens", "java.base/java.lang.invoke=cpw.mods.securejarhandler",
+            "--add-exports", "java.base/sun.security.util=cpw.mods.securejarhandler",
+            "--add-opens", "java.base/java.lang=ALL-UNNAMED",
+            "--add-opens", "java.base/java.util.jar=cpw.mods.securejarhandler",
+            "-p", "mods/modules",
+            "-Xmx8G",
+        ]));
+    }
+
+    #[test]
+    fn user_memory_replaces_the_default_one() {
+        let path = PathBuf::from("/instance");
+        let options = ArgumentsOptions {
+            jvm_arguments: Some("-Xmx8G -Dfml.earlyprogresswindow=false".to_string()),
+            ..options(&path)
+        };
+
+        let jvm = get_jvm_arguments(&path, &package(json!([])), &options);
+        assert_eq!(jvm.iter().filter(|argument| argument.starts_with("-Xmx")).collect::<Vec<_>>(), vec!["-Xmx8G"]);
+        assert!(jvm.contains(&"-Xms1G".to_string()));
+        assert!(jvm.contains(&"-Dfml.earlyprogresswindow=false".to_string()));
+    }
+
+    #[test]
+    fn game_arguments_of_a_1_20_1_package() {
+        let path = PathBuf::from("/instance");
+        let arguments = get_arguments(&path, fixture_package(), &authenticator(), &options(&path));
+
+        let game_directory = path.to_string_lossy().to_string();
+        let assets_directory = path.join("assets").to_string_lossy().to_string();
+        assert_eq!(arguments.game, strings(&[
+            "--username", "Player",
+            "--version", "1.20.1",
+            "--gameDir", &game_directory,
+            "--assetsDir", &assets_directory,
+            "--assetIndex", "5",
+            "--uuid", "f84c6a790a4e45e0879bcd49ebd4c4e2",
+            "--accessToken", "0",
+            "--clientId", "0",
+            "--xuid", "0",
+            "--userType", "legacy",
+            "--versionType", "release",
+        ]));
+        assert_eq!(arguments.main_class, "net.minecraft.client.main.Main");
+    }
+
+    #[test]
+    fn game_directory_replaces_its_placeholder() {
+        let path = PathBuf::from("/instance");
+        let game_directory = PathBuf::from("/saves/survival");
+        let options = ArgumentsOptions {
+            game_directory: game_directory.clone(),
+            ..options(&path)
+        };
+
+        let game = get_game_arguments(&path, &fixture_package(), &authenticator(), &options);
+        assert_eq!(value_of(&game, "--gameDir"), Some(game_directory.to_string_lossy().to_string()));
+        assert_eq!(game.iter().filter(|argument| *argument == "--gameDir").count(), 1);
+    }
+
+    #[test]
+    fn game_directory_is_appended_when_not_templated() {
+        let path = PathBuf::from("/instance");
+        let mut package = fixture_package();
+        package.minecraft_arguments = Some("--username ${auth_player_name} --session ${auth_session}".to_string());
+        package.arguments = None;
+
+        let game = get_game_arguments(&path, &package, &authenticator(), &options(&path));
+        assert_eq!(game, strings(&["--username", "Player", "--session", "0", "--gameDir", &path.to_string_lossy()]));
+
+        // Positional only, a flag would be taken for one of them
+        package.minecraft_arguments = Some("${auth_player_name} ${auth_session}".to_string());
+        let game = get_game_arguments(&path, &package, &authenticator(), &options(&path));
+        assert_eq!(game, strings(&["Player", "0"]));
+    }
+
+    #[test]
+    fn placeholders_are_found_inside_arguments() {
+        assert_eq!(get_placeholders("-Dlog=${path}/logs/${version_name}.log"), strings(&["${path}", "${version_name}"]));
+        assert_eq!(get_placeholders("${unterminated"), Vec::<String>::new());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unresolved_placeholders_are_rejected() {
+        let path = PathBuf::from("/instance");
+        let mut arguments = get_arguments(&path, fixture_package(), &authenticator(), &options(&path));
+        assert!(validate_arguments(&arguments).is_ok());
+
+        arguments.game.push("${auth_access_token}".to_string());
+        arguments.main_class = String::new();
+        let error = validate_arguments(&arguments).unwrap_err();
+        assert_eq!(error.unresolved_placeholders, strings(&["${auth_access_token}"]));
+        assert_eq!(error.problems, strings(&["empty main class"]));
+    }
+
+    #[test]
+    fn unknown_tokens_are_dropped_with_their_flag() {
+        let path = PathBuf::from("/instance");
+        let mut package = fixture_package();
+        package.minecraft_arguments = Some("${auth_player_name} --width ${resolution_width} --demo".to_string());
+        package.arguments = None;
+
+        let game = get_game_arguments(&path, &package, &authenticator(), &options(&path));
+        assert_eq!(game, strings(&["Player", "--demo", "--gameDir", &path.to_string_lossy()]));
+    }
+
+    #[test]
+    fn versions_without_arguments_get_the_legacy_ones() {
+        let path = PathBuf::from("/instance");
+        let mut package = fixture_package();
+        package.minecraft_arguments = None;
+        package.arguments = None;
+        package.assets = "pre-1.6".to_string();
+
+        let game = get_game_arguments(&path, &package, &authenticator(), &options(&path));
+        assert_eq!(game, strings(&[
+            "Player", "0",
+            "--gameDir", &path.to_string_lossy(),
+            "--assetsDir", &path.join("resources").to_string_lossy(),
+        ]));
+    }
+
+    /// JVM flags added for a single platform, dropped from the golden file
+    const PLATFORM_JVM_ARGUMENTS: [&str; 4] = [
+        "-XX:HeapDumpPath=MojangTricksIntelDriversForPerformance_javaw.exe_minecraft.exe.heapdump",
+        "-XstartOnFirstThread",
+        "-Xss1M",
+        "-Xdock:name=Minecraft",
+    ];
+
+    #[tokio::test]
+    async fn dock_icon_is_only_taken_from_a_valid_hash() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path();
+        let indexes = path.join("assets").join("indexes");
+        std::fs::create_dir_all(&indexes).unwrap();
+        let hash = "b62ca8ec10d07e6bf5ac8dae0c8c1d2e6a1e3356";
+        let write_index = |hash: &str| std::fs::write(indexes.join("5.json"), json!({
+            "objects": { "icons/minecraft.icns": { "hash": hash, "size": 1 } },
+        }).to_string()).unwrap();
+
+        write_index(hash);
+        let expected = path.join("assets").join("objects").join("b6").join(hash);
+        assert_eq!(get_dock_icon(path, &package(json!([]))).await, Some(expected.to_string_lossy().to_string()));
+
+        // Multi-byte characters would otherwise be split when taking the first two bytes
+        write_index("é€0000");
+        assert_eq!(get_dock_icon(path, &package(json!([]))).await, None);
+    }
+
+    /// Arguments as written in `tests/fixtures/1.20.1.arguments.txt`, the same on every platform
+    fn golden_arguments(arguments: &ArgumentsResult) -> String {
+        let normalize = |argument: &str| argument.replace('\\', "/")
+            .replace("natives-linux", "natives-${os}")
+            .replace("natives-macos", "natives-${os}")
+            .replace("natives-windows", "natives-${os}");
+        let jvm = arguments.jvm.iter()
+            .filter(|argument| !PLATFORM_JVM_ARGUMENTS.contains(&argument.as_str()))
+            .map(|argument| normalize(argument));
+        // The objc bridge is only on the macOS class path
+        let class_path = class_path_entries(&ClassPath { main_class: String::new(), class_path: arguments.class_path.clone() }).into_iter()
+            .filter(|entry| !entry.contains("java-objc-bridge"))
+            .map(|entry| normalize(&entry));
+
+        let mut golden = vec!["[jvm]".to_string()];
+        golden.extend(jvm);
+        golden.push("[class path]".to_string());
+        golden.extend(class_path);
+        golden.push("[main class]".to_string());
+        golden.push(arguments.main_class.clone());
+        golden.push("[game]".to_string());
+        golden.extend(arguments.game.iter().map(|argument| normalize(argument)));
+        golden.join("\n") + "\n"
+    }
+
+    #[test]
+    fn arguments_of_a_1_20_1_package_match_the_golden_file() {
+        let path = PathBuf::from("/instance");
+        let arguments = get_arguments(&path, fixture_package(), &authenticator(), &options(&path));
+        let golden = golden_arguments(&arguments);
+
+        // `UPDATE_GOLDEN=1 cargo test` rewrites the file after an intended change
+        let golden_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/1.20.1.arguments.txt");
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&golden_path, &golden).unwrap();
+        }
+        assert_eq!(golden, std::fs::read_to_string(&golden_path).unwrap().replace("\r\n", "\n"));
+    }
+}
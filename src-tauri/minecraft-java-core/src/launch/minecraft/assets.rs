@@ -1,17 +1,20 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use serde_json::to_string;
-use crate::launch::downloader::FileDownloadMetadata;
+use tokio::fs;
+use crate::launch::downloader::{file_exists_and_matches_sha1, FileDownloadMetadata};
+use crate::launch::endpoints::Endpoints;
 use crate::launch::minecraft::json::PackageInfo;
-use crate::launch::utils::create_temp_file_with_content;
+use crate::launch::http::get_with_retry;
+use tracing::{debug, warn};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileDetail {
-    hash: String,
-    size: u64,
+    pub(crate) hash: String,
+    pub(crate) size: u64,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -19,55 +22,137 @@ pub struct AssetsManifest {
     pub (crate) objects: HashMap<String, FileDetail>,
 }
 
+impl FileDetail {
+    /// `<first two characters>/<hash>`, where objects are stored and served. None for a malformed hash,
+    /// it would panic or point outside of the objects folder
+    pub(crate) fn get_object_key(&self) -> Option<String> {
+        if self.hash.len() != 40 || !self.hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(format!("{}/{}", &self.hash[..2], self.hash))
+    }
+}
+
+/// Called with the name of every asset object, e.g. `minecraft/sounds/ambient/cave/cave1.ogg`, the object is kept when it returns true
+pub type AssetFilter = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
 pub struct AssetsMetadataOptions {
-    reqwest_timeout: Option<Duration>,
+    pub(crate) reqwest_timeout: Option<Duration>,
+    pub(crate) endpoints: Endpoints,
+    /// Instance checked for an already complete assets index
+    pub(crate) path: Option<PathBuf>,
+    /// Objects left out of the bundle, the index is always kept
+    pub(crate) filter: Option<AssetFilter>,
 }
 
 impl Default for AssetsMetadataOptions {
     fn default() -> Self {
         Self {
             reqwest_timeout: Some(Duration::from_secs(10)),
+            endpoints: Endpoints::default(),
+            path: None,
+            filter: None,
         }
     }
 }
 
-async fn get_assets_manifest(url: String, options: AssetsMetadataOptions) -> Result<AssetsManifest, Box<dyn Error>> {
+/// Returns the raw index alongside the parsed one, so the file on disk keeps its original checksum
+async fn get_assets_manifest(url: String, options: AssetsMetadataOptions) -> Result<(Vec<u8>, AssetsManifest), Box<dyn Error>> {
     let timeout_duration = options.reqwest_timeout.unwrap_or(std::time::Duration::from_secs(10));
-    let client = Client::new();
-    let data = client.get(&url).timeout(timeout_duration).send().await?.json::<AssetsManifest>().await?;
-    Ok(data)
+    let bytes = get_with_retry(&url, timeout_duration).await?.bytes().await?.to_vec();
+    let data = serde_json::from_slice::<AssetsManifest>(&bytes)?;
+    Ok((bytes, data))
 }
 
-pub async fn get_game_assets(package: &PackageInfo, mut options: Option<AssetsMetadataOptions>) -> Result<Vec<FileDownloadMetadata>, Box<dyn std::error::Error>> {
-    options = options.or(Some(AssetsMetadataOptions::default()));
-    let manifest = get_assets_manifest(package.asset_index.url.clone(), options.unwrap()).await?;
-    let mut assets = Vec::new();
-    let temp_file_path = create_temp_file_with_content(to_string(&manifest).unwrap().as_bytes()).await?;
+/// Index already on disk with every object it references, nothing has to be enumerated again
+async fn is_assets_index_complete(path: &Path, package: &PackageInfo) -> bool {
+    let index_path = path.join(format!("assets/indexes/{}.json", package.asset_index.id));
+    if !file_exists_and_matches_sha1(&index_path, &package.asset_index.sha1).await {
+        return false;
+    }
+    let manifest = match fs::read(&index_path).await.ok().and_then(|content| serde_json::from_slice::<AssetsManifest>(&content).ok()) {
+        Some(manifest) => manifest,
+        None => return false,
+    };
 
-    assets.push(FileDownloadMetadata {
+    for detail in manifest.objects.values() {
+        let object_path = match detail.get_object_key() {
+            Some(key) => path.join("assets/objects").join(key),
+            None => return false,
+        };
+        if !fs::try_exists(&object_path).await.unwrap_or(false) {
+            return false;
+        }
+    }
+    true
+}
+
+fn get_index_file(package: &PackageInfo, content: Option<Vec<u8>>) -> FileDownloadMetadata {
+    FileDownloadMetadata {
         type_: "CFILE".to_string(),
         path: format!("assets/indexes/{}.json", package.asset_index.id),
-        content: Some(temp_file_path),
+        content,
         executable: Some(false),
-        sha1: None,
-        size: None,
+        sha1: Some(package.asset_index.sha1.clone()),
+        size: Some(package.asset_index.size),
         url: None,
-    });
-
-    return Ok(assets);
-
-    // for (_, detail) in manifest.objects {
-    //     let hash_prefix = &detail.hash[..2];
-    //     assets.push(FileDownloadMetadata {
-    //         url: Some(format!("https://resources.download.minecraft.net/{}/{}", hash_prefix, detail.hash)),
-    //         path: format!("assets/objects/{}/{}", hash_prefix, detail.hash),
-    //         sha1: Some(detail.hash),
-    //         size: Some(detail.size),
-    //         content: None,
-    //         executable: Some(false),
-    //         type_: "CFILE".to_string(),
-    //     });
-    // }
-    //
-    // return Ok(assets);
-}
\ No newline at end of file
+    }
+}
+
+pub async fn get_game_assets(package: &PackageInfo, mut options: Option<AssetsMetadataOptions>) -> Result<Vec<FileDownloadMetadata>, Box<dyn std::error::Error>> {
+    options = options.or(Some(AssetsMetadataOptions::default()));
+    let options = options.unwrap();
+
+    if let Some(path) = &options.path {
+        if is_assets_index_complete(path, package).await {
+            debug!("Assets index {} is complete, skipping objects", package.asset_index.id);
+            return Ok(vec![]);
+        }
+    }
+
+    let resources = options.endpoints.resources.trim_end_matches('/').to_string();
+    let filter = options.filter.clone();
+    let (raw_manifest, manifest) = get_assets_manifest(options.endpoints.rewrite(&package.asset_index.url), options).await?;
+    let mut assets = Vec::new();
+    assets.push(get_index_file(package, Some(raw_manifest)));
+
+    for (name, detail) in manifest.objects {
+        if filter.as_ref().is_some_and(|filter| !filter(&name)) {
+            continue;
+        }
+        let key = match detail.get_object_key() {
+            Some(key) => key,
+            None => {
+                warn!("Skipping asset {}: malformed hash {:?}", name, detail.hash);
+                continue;
+            },
+        };
+        assets.push(FileDownloadMetadata {
+            url: Some(format!("{}/{}", resources, key)),
+            path: format!("assets/objects/{}", key),
+            sha1: Some(detail.hash.clone()),
+            size: Some(detail.size),
+            content: None,
+            executable: Some(false),
+            type_: "Assets".to_string(),
+        });
+    }
+
+    Ok(assets)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detail(hash: &str) -> FileDetail {
+        FileDetail { hash: hash.to_string(), size: 0 }
+    }
+
+    #[test]
+    fn objects_are_stored_under_their_hash_prefix() {
+        assert_eq!(detail("bdf48ef6b5d0d23bbb02e17d04865216179f510a").get_object_key().as_deref(), Some("bd/bdf48ef6b5d0d23bbb02e17d04865216179f510a"));
+        for malformed in ["", "b", "é", "../../../../../../../../../../../../../etc", "bdf48ef6b5d0d23bbb02e17d04865216179f510"] {
+            assert_eq!(detail(malformed).get_object_key(), None, "{}", malformed);
+        }
+    }
+}
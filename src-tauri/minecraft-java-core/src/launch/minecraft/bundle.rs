@@ -1,16 +1,171 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
 use crate::launch::downloader::FileDownloadMetadata;
+use crate::launch::utils::{get_arch_name, get_os_arch_mapping, get_os_name};
+use tracing::{debug, info};
 
-pub fn check_bundle(_bundle: Vec<FileDownloadMetadata>) -> Vec<FileDownloadMetadata> {
-    println!("Checking bundle...");
-    return _bundle;
-    // println!("{:?}", bundle);
-    // let version_metadata = minecraft::json::get_version_metadata("1.6.4", None).await.unwrap().clone();
-    // let libraries = get_libraries(&version_metadata.package);
-    // let assets = get_assets("https://gist.githubusercontent.com/tacxou/fb1135d15a4772e28d5cf4223553f5fe/raw/cc1b41c2b1e954f32a0ac7b715c80b10e30cb590/assets_manifest.json".to_owned(), None).await.unwrap();
-    // let mut path = std::env::current_dir().unwrap();
-    // path.push("instances");
-    // path.push("miratest");
-    // let natives = minecraft::libraries::get_natives(path, &version_metadata.package, libraries);
-    // println!("Natives: {:?}", natives);
-    // println!("Bundle checked!")
-}
\ No newline at end of file
+#[derive(Debug, Serialize)]
+struct BundleManifest<'a> {
+    version: &'a str,
+    os: &'a str,
+    arch: &'a str,
+    platform: &'a str,
+    generated_at: String,
+    files: &'a Vec<FileDownloadMetadata>,
+}
+
+/// Download order of a file, what the game needs to start comes first so an interrupted install can still launch
+pub fn get_download_priority(file: &FileDownloadMetadata) -> u8 {
+    match file.type_.as_str() {
+        "Jar" => 0,
+        "CFILE" => 1,
+        "Libraries" | "Natives" => 2,
+        "Java" => 3,
+        "Assets" => 5,
+        _ => 4,
+    }
+}
+
+pub fn check_bundle(bundle: Vec<FileDownloadMetadata>) -> Vec<FileDownloadMetadata> {
+    info!("Checking bundle...");
+    let total = bundle.len();
+
+    // Asset objects are stored by hash, so the same object can be listed several times
+    let mut seen = HashSet::new();
+    let bundle: Vec<FileDownloadMetadata> = bundle.into_iter()
+        .filter(|file| seen.insert(file.path.clone()))
+        .collect();
+    if bundle.len() < total {
+        debug!("{} duplicated files removed from bundle", total - bundle.len());
+    }
+    // Stable, the files of a same priority keep their order
+    let mut bundle = bundle;
+    bundle.sort_by_key(get_download_priority);
+    bundle
+}
+
+pub fn diff_bundle(previous: &[FileDownloadMetadata], next: &[FileDownloadMetadata]) -> Vec<FileDownloadMetadata> {
+    let known: HashSet<(&String, &String)> = previous.iter()
+        .filter_map(|file| file.sha1.as_ref().map(|sha1| (&file.path, sha1)))
+        .collect();
+
+    // Files without a sha1 (generated content) can't be compared and are always kept
+    next.iter()
+        .filter(|file| match &file.sha1 {
+            Some(sha1) => !known.contains(&(&file.path, sha1)),
+            None => true,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Writes the resolved files with the platform they were resolved for, to be attached to bug reports
+pub async fn export_bundle(output: &Path, version: &str, files: &Vec<FileDownloadMetadata>) -> Result<(), Box<dyn Error>> {
+    let manifest = BundleManifest {
+        version,
+        os: get_os_name(),
+        arch: get_arch_name(),
+        platform: get_os_arch_mapping(),
+        generated_at: Utc::now().to_rfc3339(),
+        files,
+    };
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(output, serde_json::to_vec_pretty(&manifest)?).await?;
+    debug!("Bundle manifest exported to {:?}", output);
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportedBundle {
+    pub version: String,
+    pub files: Vec<FileDownloadMetadata>,
+}
+
+/// Reads back a manifest written by `export_bundle`
+pub async fn read_bundle(input: &Path) -> Result<ExportedBundle, Box<dyn Error>> {
+    Ok(serde_json::from_slice(&fs::read(input).await?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(type_: &str, path: &str) -> FileDownloadMetadata {
+        FileDownloadMetadata {
+            type_: type_.to_string(),
+            path: path.to_string(),
+            executable: Some(false),
+            sha1: None,
+            size: None,
+            url: None,
+            content: None,
+        }
+    }
+
+    fn hashed(path: &str, sha1: &str) -> FileDownloadMetadata {
+        FileDownloadMetadata {
+            sha1: Some(sha1.to_string()),
+            ..file("Libraries", path)
+        }
+    }
+
+    #[test]
+    fn only_new_and_changed_files_are_kept() {
+        let previous = vec![
+            hashed("libraries/com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar", "5244ce82c3337bba4a196a3ce858bfaecc74404a"),
+            hashed("versions/1.20.1/1.20.1.jar", "0c3ec587af28e5a785c0b4a7b8a30f9a8f78f838"),
+        ];
+        let next = vec![
+            hashed("libraries/com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar", "5244ce82c3337bba4a196a3ce858bfaecc74404a"),
+            hashed("versions/1.20.1/1.20.1.jar", "8a0b4c5d2bb3ba2b95ed4ac0b5c1ae38caf1b8a1"),
+            hashed("libraries/org/joml/joml/1.10.5/joml-1.10.5.jar", "22566d58af70ad3d72308bab63b8339906deb649"),
+            file("CFILE", "versions/1.20.2/1.20.2.json"),
+        ];
+
+        let paths: Vec<String> = diff_bundle(&previous, &next).into_iter().map(|file| file.path).collect();
+        assert_eq!(paths, vec![
+            "versions/1.20.1/1.20.1.jar",
+            "libraries/org/joml/joml/1.10.5/joml-1.10.5.jar",
+            "versions/1.20.2/1.20.2.json",
+        ]);
+    }
+
+    #[test]
+    fn duplicated_files_are_listed_once() {
+        let bundle = check_bundle(vec![
+            file("Assets", "assets/objects/bd/bdf48ef6b5d0d23bbb02e17d04865216179f510a"),
+            file("Libraries", "libraries/a.jar"),
+            file("Assets", "assets/objects/bd/bdf48ef6b5d0d23bbb02e17d04865216179f510a"),
+            file("Libraries", "libraries/a.jar"),
+        ]);
+        let paths: Vec<&str> = bundle.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, vec!["libraries/a.jar", "assets/objects/bd/bdf48ef6b5d0d23bbb02e17d04865216179f510a"]);
+    }
+
+    #[test]
+    fn client_jar_is_downloaded_first() {
+        let bundle = check_bundle(vec![
+            file("Libraries", "libraries/a.jar"),
+            file("Assets", "assets/objects/aa/aa"),
+            file("Java", "runtime/bin/java"),
+            file("Jar", "versions/1.20.1/1.20.1.jar"),
+            file("Libraries", "libraries/b.jar"),
+            file("CFILE", "versions/1.20.1/1.20.1.json"),
+        ]);
+        let paths: Vec<&str> = bundle.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, vec![
+            "versions/1.20.1/1.20.1.jar",
+            "versions/1.20.1/1.20.1.json",
+            "libraries/a.jar",
+            "libraries/b.jar",
+            "runtime/bin/java",
+            "assets/objects/aa/aa",
+        ]);
+    }
+}
@@ -1,53 +1,24 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use crate::launch::downloader::FileDownloadMetadata;
+use crate::launch::endpoints::Endpoints;
 
 use crate::launch::minecraft::json::PackageInfo;
 use crate::launch::utils::{get_os_arch_mapping, get_os_name};
+use crate::launch::http::get_with_retry;
+use tracing::debug;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct JavaRuntimeMetadata {
-    gamecore: Platform,
-
-    linux: Platform,
-    #[serde(rename = "linux-i386")]
-    linux_i386: Platform,
-
-    #[serde(rename = "mac-os")]
-    mac_os: Platform,
-    #[serde(rename = "mac-os-arm64")]
-    mac_os_arm64: Platform,
-
-    #[serde(rename = "windows-arm64")]
-    windows_arm64: Platform,
-    #[serde(rename = "windows-x64")]
-    windows_x64: Platform,
-    #[serde(rename = "windows-x86")]
-    windows_x86: Platform,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Platform {
-    #[serde(rename = "java-runtime-alpha")]
-    java_runtime_alpha: Vec<JavaRuntime>,
-    #[serde(rename = "java-runtime-beta")]
-    java_runtime_beta: Vec<JavaRuntime>,
-    #[serde(rename = "java-runtime-gamma")]
-    java_runtime_gamma: Vec<JavaRuntime>,
-    #[serde(rename = "java-runtime-gamma-snapshot")]
-    java_runtime_gamma_snapshot: Vec<JavaRuntime>,
-    #[serde(rename = "jre-legacy")]
-    jre_legacy: Vec<JavaRuntime>,
-    #[serde(rename = "minecraft-java-exe")]
-    minecraft_java_exe: Vec<JavaRuntime>,
-}
+/// Runtimes of each component, e.g. `java-runtime-gamma`, keyed by platform, e.g. `windows-x64`.
+/// Components come and go with the Java versions the game needs, they aren't listed here
+pub type JavaRuntimeMetadata = HashMap<String, HashMap<String, Vec<JavaRuntime>>>;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-struct JavaRuntime {
+pub struct JavaRuntime {
     availability: Availability,
     manifest: Manifest,
     version: Version,
@@ -72,29 +43,60 @@ struct Version {
     released: String,
 }
 
+/// Where the java runtimes are stored
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RuntimeScope {
+    /// Shared by every instance under `runtime/jre-<name>-<arch>`
+    #[default]
+    Global,
+    /// Kept under `runtime/instances/<instance>/`, so instances pinning different patches don't overwrite each other
+    Instance,
+}
+
 #[derive(Clone)]
 pub struct JavaMetadataOptions {
-    reqwest_timeout: Option<Duration>,
+    pub(crate) reqwest_timeout: Option<Duration>,
+    pub(crate) component: Option<String>,
+    pub(crate) endpoints: Endpoints,
+    pub(crate) show_console: bool,
+    /// Instance owning the runtime, shared by every instance when unset
+    pub(crate) instance: Option<String>,
 }
 
 impl Default for JavaMetadataOptions {
     fn default() -> Self {
         Self {
             reqwest_timeout: Some(Duration::from_secs(10)),
+            component: None,
+            endpoints: Endpoints::default(),
+            show_console: false,
+            instance: None,
         }
     }
 }
 
+/// The java runtimes index couldn't be fetched or read, see `Endpoints::java_runtime_manifest`
+#[derive(Debug)]
+pub struct JavaRuntimeUnavailable {
+    pub url: String,
+    pub reason: String,
+}
+
+impl fmt::Display for JavaRuntimeUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Java runtimes index {} is unavailable: {}", self.url, self.reason)
+    }
+}
+
+impl Error for JavaRuntimeUnavailable {}
+
 async fn get_java_runtime(options: &JavaMetadataOptions) -> Result<JavaRuntimeMetadata, Box<dyn Error>> {
-    let url = "https://launchermeta.mojang.com/v1/products/java-runtime/2ec0cc96c44e5a76b9c8b7c39df7210883d12871/all.json";
+    let url = options.endpoints.java_runtime();
     let timeout_duration = options.reqwest_timeout.unwrap_or(Duration::from_secs(10));
 
-    let client = Client::new();
-    let data = client
-        .get(url)
-        .timeout(timeout_duration)
-        .send().await?
-        .json::<JavaRuntimeMetadata>().await?;
+    let unavailable = |e: reqwest::Error| JavaRuntimeUnavailable { url: url.clone(), reason: e.to_string() };
+    let data = get_with_retry(&url, timeout_duration).await.map_err(unavailable)?
+        .json::<JavaRuntimeMetadata>().await.map_err(unavailable)?;
     Ok(data)
 }
 
@@ -129,78 +131,55 @@ struct FileDownload {
 async fn get_java_manifest(url: String, options: &JavaMetadataOptions) -> Result<JavaManifestMetadata, Box<dyn Error>> {
     let timeout_duration = options.reqwest_timeout.unwrap_or(Duration::from_secs(10));
 
-    let client = Client::new();
-    let data = client
-        .get(url)
-        .timeout(timeout_duration)
-        .send().await?
+    let data = get_with_retry(&url, timeout_duration).await?
         .json::<JavaManifestMetadata>().await?;
     Ok(data)
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct RuntimeManifestMetadata {
-    runtime: JavaRuntime,
-    manifest: JavaManifestMetadata,
-}
-
 async fn get_runtime_manifest(arch_mapping: &str, java_version: &str, options: Option<JavaMetadataOptions>) -> Result<(JavaRuntime, JavaManifestMetadata), Box<dyn Error>> {
     let java_versions_json = get_java_runtime(&options.clone().unwrap()).await?;
-    let platform = match arch_mapping {
-        "windows-x86" => &java_versions_json.windows_x86,
-        "windows-x64" => &java_versions_json.windows_x64,
-        "windows-arm64" => &java_versions_json.windows_arm64,
-        "linux" => &java_versions_json.linux,
-        "linux-i386" => &java_versions_json.linux_i386,
-        "mac-os" => &java_versions_json.mac_os,
-        "mac-os-arm64" => &java_versions_json.mac_os_arm64,
-        _ => panic!("Unsupported OS or architecture"),
-    };
-    let java_runtime = match java_version {
-        "jre-legacy" => &platform.jre_legacy,
-        "java-runtime-alpha" => &platform.java_runtime_alpha,
-        "java-runtime-beta" => &platform.java_runtime_beta,
-        "java-runtime-gamma" => &platform.java_runtime_gamma,
-        "java-runtime-gamma-snapshot" => &platform.java_runtime_gamma_snapshot,
-        "minecraft-java-exe" => &platform.minecraft_java_exe,
-        _ => panic!("Unsupported Java version"),
+    let platform = java_versions_json.get(arch_mapping)
+        .ok_or_else(|| format!("Unsupported OS or architecture {}", arch_mapping))?;
+    let java_runtime = match platform.get(java_version) {
+        Some(java_runtime) => java_runtime,
+        None => {
+            let mut components: Vec<&str> = platform.keys().map(String::as_str).collect();
+            components.sort();
+            return Err(format!("Unknown java runtime component {}, expected one of {}", java_version, components.join(", ")).into());
+        },
     };
 
-    return if let Some(runtime) = java_runtime.get(0) {
-        let manifest = get_java_manifest(runtime.manifest.url.to_string(), &options.clone().unwrap()).await?;
+    if let Some(runtime) = java_runtime.first() {
+        let options = options.unwrap();
+        let manifest = get_java_manifest(options.endpoints.rewrite(&runtime.manifest.url), &options).await?;
         Ok((runtime.clone(), manifest))
     } else {
-        Err("No Java runtime found".into())
+        Err(format!("No Java runtime {} found for {}", java_version, arch_mapping).into())
     }
 }
 
-async fn process_java_files(java_files: HashMap<String, FileType>, version_name: &String, arch_mapping: &str) -> Vec<FileDownloadMetadata> {
-    // let os_specific_file = if cfg!(target_os = "windows") { "bin/javaw.exe" } else { "bin/java" };
+async fn process_java_files(java_files: HashMap<String, FileType>, runtime_dir: &str, endpoints: &Endpoints) -> Result<Vec<FileDownloadMetadata>, Box<dyn Error>> {
     let os_specific_file = if get_os_name() == "windows" { "bin/javaw.exe" } else { "bin/java" };
     let java_path_key = java_files.keys()
         .find(|path| path.ends_with(os_specific_file))
-        .expect("Java executable not found").to_string();
-    // let to_delete = PathBuf::from(java_path_key)
-    //     .parent()
-    //     .expect("Failed to find parent directory")
-    //     .to_path_buf();
-    let to_delete = java_path_key.trim_end_matches(os_specific_file);
+        .ok_or(format!("No {} in the java runtime manifest", os_specific_file))?.to_string();
+    // The macOS runtime is a bundle which must keep its structure to run
+    let to_delete = if java_path_key.contains("jre.bundle/") { "" } else { java_path_key.trim_end_matches(os_specific_file) };
 
     let mut files: Vec<FileDownloadMetadata> = Vec::new();
     for (path, entry) in java_files {
         match entry {
             FileType::Directory => continue,
             FileType::File { downloads, executable } => {
-                if let Some(downloads) = downloads {
-                    let adjusted_path = path.replace(to_delete, "");
-                    // println!("path {:?}, {:?}", path, path.replace(to_delete, ""));
+                // Files are always served raw, the lzma copy is optional
+                if let Some(raw) = downloads.and_then(|downloads| downloads.raw) {
+                    let adjusted_path = path.strip_prefix(to_delete).unwrap_or(&path);
                     files.push(FileDownloadMetadata {
-                        path: format!("runtime/jre-{}-{}/{}", version_name, arch_mapping, adjusted_path),
-                        // path: format!("runtime/jre-{}-{}/{}", version_name, arch_mapping, path.replace(to_delete.to_str().unwrap_or(""), "")),
+                        path: format!("{}/{}", runtime_dir, adjusted_path),
                         executable: Option::from(executable.unwrap_or(false)),
-                        sha1: Option::from(downloads.raw.clone().unwrap().sha1),
-                        size: Option::from(downloads.raw.clone().unwrap().size),
-                        url: Option::from(downloads.raw.clone().unwrap().url),
+                        sha1: Option::from(raw.sha1),
+                        size: Option::from(raw.size),
+                        url: Option::from(endpoints.rewrite(&raw.url)),
                         type_: "Java".to_string(),
                         content: None,
                     });
@@ -208,12 +187,13 @@ async fn process_java_files(java_files: HashMap<String, FileType>, version_name:
             },
         }
     }
-    return files;
+    Ok(files)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JavaFilesMetadata {
     pub(crate) path: String,
+    pub(crate) version: String,
     pub(crate) list: Vec<FileDownloadMetadata>,
 }
 
@@ -221,16 +201,181 @@ pub async fn get_java_files(package_info: &PackageInfo, mut options: Option<Java
     options = options.or(Some(JavaMetadataOptions::default()));
     let arch_mapping = get_os_arch_mapping();
     let binding = "jre-legacy".to_string();
-    let java_version = package_info.java_version.as_ref()
-        .map(|v| &v.component)
-        .unwrap_or(&binding);
+    // Checked against the components of the runtimes index
+    let java_version = match options.as_ref().and_then(|o| o.component.as_ref()) {
+        Some(component) => component.clone(),
+        None => package_info.java_version.as_ref()
+            .map(|v| &v.component)
+            .unwrap_or(&binding)
+            .clone(),
+    };
 
-    let (runtime, manifest) = get_runtime_manifest(arch_mapping, java_version, options).await?;
-    let list = process_java_files(manifest.files, &runtime.version.name, arch_mapping).await;
+    let endpoints = options.as_ref().unwrap().endpoints.clone();
+    let show_console = options.as_ref().unwrap().show_console;
+    let instance = options.as_ref().unwrap().instance.clone();
+    let (runtime, manifest) = get_runtime_manifest(arch_mapping, &java_version, options).await?;
+    let runtime_dir = get_runtime_dir(&runtime.version.name, arch_mapping, instance.as_deref());
+    let list = process_java_files(manifest.files, &runtime_dir, &endpoints).await?;
 
     Ok(JavaFilesMetadata {
-        //TODO: replace by resolve path
-        path: format!("runtime/jre-{}-{}/bin/java", &runtime.version.name, arch_mapping),
+        path: resolve_java_executable(Path::new(&runtime_dir), show_console).to_string_lossy().to_string(),
+        version: runtime.version.name,
         list,
     })
-}
\ No newline at end of file
+}
+
+/// Runtime folder relative to the instance path, `instance` isolates it from the other instances
+pub(crate) fn get_runtime_dir(version_name: &str, arch_mapping: &str, instance: Option<&str>) -> String {
+    match instance {
+        Some(instance) => format!("runtime/instances/{}/jre-{}-{}", instance, version_name, arch_mapping),
+        None => format!("runtime/jre-{}-{}", version_name, arch_mapping),
+    }
+}
+
+/// Java binary inside a runtime folder, following the layout shipped for each platform.
+/// On Windows `javaw.exe` is picked unless `show_console` is set, `java.exe` keeping a console open
+pub fn resolve_java_executable(runtime_dir: &Path, show_console: bool) -> PathBuf {
+    java_executable_for(get_os_name(), runtime_dir, show_console)
+}
+
+fn java_executable_for(os: &str, runtime_dir: &Path, show_console: bool) -> PathBuf {
+    match os {
+        "windows" if show_console => runtime_dir.join("bin").join("java.exe"),
+        "windows" => runtime_dir.join("bin").join("javaw.exe"),
+        "osx" => runtime_dir.join("jre.bundle").join("Contents").join("Home").join("bin").join("java"),
+        _ => runtime_dir.join("bin").join("java"),
+    }
+}
+
+/// Runtime found in the `runtime` folder of an install
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledRuntime {
+    /// Version name of the runtime, e.g. `17.0.8` or `8u51`
+    pub version: String,
+    /// Platform it was downloaded for, e.g. `windows-x64` or `mac-os-arm64`
+    pub platform: String,
+    pub path: PathBuf,
+    pub java: PathBuf,
+}
+
+/// Runtimes shared by the instances of `base_path`, the ones missing their java executable are left out
+pub fn list_installed_runtimes(base_path: &Path) -> Vec<InstalledRuntime> {
+    let entries = match std::fs::read_dir(base_path.join("runtime")) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut runtimes: Vec<InstalledRuntime> = entries.filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // The version name never holds a dash, the platform can
+            let (version, platform) = name.strip_prefix("jre-")?.split_once('-')?;
+            let java = resolve_java_executable(&entry.path(), true);
+            if !java.is_file() {
+                debug!("Skipping runtime {}: {:?} is missing", name, java);
+                return None;
+            }
+            Some(InstalledRuntime {
+                version: version.to_string(),
+                platform: platform.to_string(),
+                path: entry.path(),
+                java,
+            })
+        })
+        .collect();
+    runtimes.sort_by(|a, b| a.path.cmp(&b.path));
+    runtimes
+}
+
+pub fn get_java_major_version(version: &str) -> Option<u32> {
+    // Handles "17.0.8", "1.8.0_51" and the "8u51" naming of the legacy runtime
+    let mut parts = version.split(|c: char| !c.is_ascii_digit()).filter(|part| !part.is_empty());
+    match parts.next()?.parse::<u32>().ok()? {
+        1 => parts.next()?.parse::<u32>().ok(),
+        major => Some(major),
+    }
+}
+
+pub fn get_required_java_version(package_info: &PackageInfo) -> u32 {
+    if let Some(java_version) = &package_info.java_version {
+        return java_version.major_version;
+    }
+
+    // Manifests without javaVersion predate 1.17, which was the first release to need Java 16+
+    let minor = package_info.id.split('.').nth(1).and_then(|minor| minor.parse::<u32>().ok());
+    match minor {
+        Some(minor) if minor >= 17 => 17,
+        _ => 8,
+    }
+}
+
+pub fn check_java_compatibility(package_info: &PackageInfo, java_version: &str) -> Result<(), Box<dyn Error>> {
+    let required = get_required_java_version(package_info);
+    let found = get_java_major_version(java_version)
+        .ok_or(format!("Unable to read the Java version {}", java_version))?;
+
+    if found < required {
+        return Err(format!("{} requires Java {}, found Java {}", package_info.id, required, found).into());
+    }
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use super::*;
+
+    fn package(id: &str, java_version: Option<Value>) -> PackageInfo {
+        serde_json::from_value(json!({
+            "assetIndex": { "id": "5", "sha1": "", "size": 0, "totalSize": 0, "url": "" },
+            "assets": "5",
+            "downloads": { "client": { "sha1": "", "size": 0, "url": "" } },
+            "id": id,
+            "javaVersion": java_version,
+            "libraries": [],
+            "mainClass": "net.minecraft.client.main.Main",
+            "minimumLauncherVersion": 21,
+            "time": "2023-06-12T13:25:51+00:00",
+            "type": "release",
+        })).unwrap()
+    }
+
+    #[test]
+    fn java_major_version_of_each_naming() {
+        assert_eq!(get_java_major_version("17.0.8"), Some(17));
+        assert_eq!(get_java_major_version("1.8.0_51"), Some(8));
+        assert_eq!(get_java_major_version("8u51"), Some(8));
+        assert_eq!(get_java_major_version("jre"), None);
+    }
+
+    #[test]
+    fn required_java_version_without_java_version() {
+        assert_eq!(get_required_java_version(&package("1.20.1", Some(json!({ "component": "java-runtime-gamma", "majorVersion": 17 })))), 17);
+        assert_eq!(get_required_java_version(&package("1.17", None)), 17);
+        assert_eq!(get_required_java_version(&package("1.12.2", None)), 8);
+        assert_eq!(get_required_java_version(&package("23w31a", None)), 8);
+    }
+
+    #[test]
+    fn java_executable_of_each_runtime_layout() {
+        let runtime = Path::new("runtime/java-runtime-gamma-17.0.8");
+        assert_eq!(java_executable_for("linux", runtime, false), runtime.join("bin/java"));
+        assert_eq!(java_executable_for("osx", runtime, false), runtime.join("jre.bundle/Contents/Home/bin/java"));
+        assert_eq!(resolve_java_executable(runtime, false), java_executable_for(get_os_name(), runtime, false));
+    }
+
+    #[test]
+    fn console_is_only_shown_when_asked_on_windows() {
+        let runtime = Path::new("runtime/java-runtime-gamma-17.0.8");
+        assert_eq!(java_executable_for("windows", runtime, false), runtime.join("bin").join("javaw.exe"));
+        assert_eq!(java_executable_for("windows", runtime, true), runtime.join("bin").join("java.exe"));
+        // Only Windows has a console-less java
+        assert_eq!(java_executable_for("linux", runtime, true), java_executable_for("linux", runtime, false));
+    }
+
+    #[test]
+    fn older_java_is_refused() {
+        let package = package("1.20.1", Some(json!({ "component": "java-runtime-gamma", "majorVersion": 17 })));
+        assert!(check_java_compatibility(&package, "17.0.8").is_ok());
+        assert!(check_java_compatibility(&package, "8u51").is_err());
+    }
+}
@@ -1,11 +1,15 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::time::Duration;
 
 use chrono::{DateTime, Utc};
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tracing::debug;
+use crate::launch::endpoints::Endpoints;
+use crate::launch::http::get_with_retry;
+use crate::launch::utils::get_os_name;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct LatestInfo {
@@ -21,13 +25,23 @@ pub struct VersionInfo {
     pub type_: String,
     pub url: String,
     pub time: DateTime<Utc>,
-    #[serde(rename = "release_time")]
+    #[serde(rename = "releaseTime")]
     pub release_time: Option<DateTime<Utc>>,
     pub sha1: String,
-    #[serde(rename = "compliance_level")]
+    #[serde(rename = "complianceLevel")]
     pub compliance_level: Option<i32>,
 }
 
+impl VersionInfo {
+    pub fn released_at(&self) -> DateTime<Utc> {
+        self.release_time.unwrap_or(self.time)
+    }
+
+    pub fn compliance(&self) -> i32 {
+        self.compliance_level.unwrap_or(0)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct VersionManifest {
     pub latest: LatestInfo,
@@ -43,6 +57,30 @@ pub struct Rule {
     features: Option<HashMap<String, bool>>,
 }
 
+impl Rule {
+    /// Features are launcher options such as a demo user, none of them is enabled.
+    /// OS version constraints only target releases too old to run the game and never match
+    fn matches(&self, os_name: &str, arch: &str) -> bool {
+        if self.features.is_some() {
+            return false;
+        }
+        match &self.os {
+            Some(os) => os.get("name").is_none_or(|name| name == os_name)
+                && os.get("arch").is_none_or(|rule_arch| rule_arch == arch)
+                && !os.contains_key("version"),
+            None => true,
+        }
+    }
+}
+
+/// Mojang rule evaluation: nothing is allowed until a rule matches, and the last matching rule wins
+pub(crate) fn rules_allow(rules: &[Rule], os_name: &str, arch: &str) -> bool {
+    rules.iter()
+        .rev()
+        .find(|rule| rule.matches(os_name, arch))
+        .is_some_and(|rule| rule.action == "allow")
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum GameArgument {
@@ -57,9 +95,7 @@ pub enum GameArgument {
 
 impl From<String> for GameArgument {
     fn from(s: String) -> Self {
-        match s.as_str() {
-            _ => GameArgument::Simple(s),
-        }
+        GameArgument::Simple(s)
     }
 }
 
@@ -75,17 +111,19 @@ impl GameArgument {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Arguments {
+    #[serde(default)]
     pub(crate) game: Vec<GameArgument>,
+    #[serde(default)]
     pub(crate) jvm: Vec<Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AssetIndex {
     pub(crate) id: String,
-    sha1: String,
-    size: u32,
+    pub(crate) sha1: String,
+    pub(crate) size: u64,
     #[serde(rename = "totalSize")]
-    total_size: u64,
+    pub(crate) total_size: u64,
     pub(crate) url: String,
 }
 
@@ -99,24 +137,28 @@ pub struct ClientDownload {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Downloads {
     pub(crate) client: ClientDownload,
+    /// Missing from the oldest versions, which had no dedicated server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) server: Option<ClientDownload>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JavaVersion {
     pub(crate) component: String,
     #[serde(rename = "majorVersion")]
-    major_version: u32,
+    pub(crate) major_version: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ArtifactDownload {
-    pub(crate) path: String,
-    pub(crate) sha1: String,
-    pub(crate) size: u64,
-    pub(crate) url: String,
+    /// Ignored by `LaunchMetadata::artifact_overrides`, the library keeps its manifest path
+    pub path: String,
+    pub sha1: String,
+    pub size: u64,
+    pub url: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct LibraryDownloads {
     pub(crate) artifact: Option<ArtifactDownload>,
     pub(crate) classifiers: Option<HashMap<String, ArtifactDownload>>,
@@ -124,13 +166,27 @@ pub struct LibraryDownloads {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Library {
+    #[serde(default)]
     pub(crate) downloads: LibraryDownloads,
     pub(crate) name: String,
+    pub(crate) url: Option<String>,
+    pub(crate) sha1: Option<String>,
+    pub(crate) size: Option<u64>,
     pub(crate) rules: Option<Vec<Rule>>,
     pub(crate) natives: Option<HashMap<String, String>>,
     extract: Option<HashMap<String, Vec<String>>>,
 }
 
+impl Library {
+    /// Whether the rules of the library keep it on this platform, it is always kept without rules
+    pub(crate) fn is_allowed(&self) -> bool {
+        match &self.rules {
+            Some(rules) => rules_allow(rules, get_os_name(), std::env::consts::ARCH),
+            None => true,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct LogFile {
     id: String,
@@ -187,35 +243,103 @@ pub struct InfoMetadata {
 }
 
 pub struct VersionMetadataOptions {
-    reqwest_timeout: Option<Duration>,
+    pub(crate) reqwest_timeout: Option<Duration>,
+    pub(crate) endpoints: Endpoints,
 }
 
 impl Default for VersionMetadataOptions {
     fn default() -> Self {
         Self {
             reqwest_timeout: Some(Duration::from_secs(10)),
+            endpoints: Endpoints::default(),
         }
     }
 }
 
-async fn get_version_manifest(options: VersionMetadataOptions) -> Result<VersionManifest, Box<dyn Error>> {
+async fn get_version_manifest(options: &VersionMetadataOptions) -> Result<VersionManifest, Box<dyn Error>> {
     let current_time = Utc::now();
     let iso_string = current_time.to_rfc3339();
-    let url = format!("https://launchermeta.mojang.com/mc/game/version_manifest_v2.json?_t={}", iso_string);
+    let url = format!("{}?_t={}", options.endpoints.version_manifest(), iso_string);
     let timeout_duration = options.reqwest_timeout.unwrap_or(Duration::from_secs(10));
 
-    let client = Client::new();
-    let data = client
-        .get(url)
-        .timeout(timeout_duration)
-        .send().await?
+    let data = get_with_retry(&url, timeout_duration).await?
         .json::<VersionManifest>().await?;
     Ok(data)
 }
 
+pub fn filter_versions(versions: Vec<VersionInfo>, types: &[String]) -> Vec<VersionInfo> {
+    let mut versions: Vec<VersionInfo> = versions.into_iter()
+        .filter(|version| types.is_empty() || types.contains(&version.type_))
+        .collect();
+    versions.sort_by(|a, b| {
+        b.released_at().cmp(&a.released_at())
+            .then_with(|| b.compliance().cmp(&a.compliance()))
+    });
+    versions
+}
+
+pub async fn get_version_list(types: Option<Vec<String>>, mut options: Option<VersionMetadataOptions>) -> Result<Vec<VersionInfo>, Box<dyn Error>> {
+    options = options.or(Some(VersionMetadataOptions::default()));
+    let manifest = get_version_manifest(&options.unwrap()).await?;
+    Ok(filter_versions(manifest.versions, &types.unwrap_or_default()))
+}
+
+#[derive(Debug)]
+pub struct VersionNotFound {
+    pub version: String,
+    /// Closest known ids, the nearest first
+    pub suggestions: Vec<String>,
+    pub latest_release: String,
+    pub latest_snapshot: String,
+}
+
+impl fmt::Display for VersionNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Version {} not found", self.version)?;
+        if !self.suggestions.is_empty() {
+            write!(f, ", did you mean {}?", self.suggestions.join(", "))?;
+        }
+        write!(f, " (latest release: {}, latest snapshot: {})", self.latest_release, self.latest_snapshot)
+    }
+}
+
+impl std::error::Error for VersionNotFound {}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn get_version_not_found(version: &str, manifest: &VersionManifest) -> VersionNotFound {
+    let mut candidates: Vec<(usize, &VersionInfo)> = manifest.versions.iter()
+        .map(|info| (edit_distance(version, &info.id), info))
+        .filter(|(distance, _)| *distance <= 3)
+        .collect();
+    // Releases first among equally close ids, `1.20` is more likely `1.20.1` than a snapshot
+    candidates.sort_by(|(a, a_info), (b, b_info)| a.cmp(b)
+        .then_with(|| (b_info.type_ == "release").cmp(&(a_info.type_ == "release")))
+        .then_with(|| b_info.released_at().cmp(&a_info.released_at())));
+    VersionNotFound {
+        version: version.to_string(),
+        suggestions: candidates.into_iter().take(3).map(|(_, info)| info.id.clone()).collect(),
+        latest_release: manifest.latest.release.clone(),
+        latest_snapshot: manifest.latest.snapshot.clone(),
+    }
+}
+
 pub async fn get_version_metadata(version: &str, mut options: Option<VersionMetadataOptions>) -> Result<InfoMetadata, Box<dyn Error>> {
     options = options.or(Some(VersionMetadataOptions::default()));
-    let manifest = get_version_manifest(options.unwrap()).await?;
+    let options = options.unwrap();
+    let manifest = get_version_manifest(&options).await?;
     let version_id = match version {
         "latest_release" | "r" | "lr" => manifest.latest.release.clone(),
         "latest_snapshot" | "s" | "ls" => manifest.latest.snapshot.clone(),
@@ -223,14 +347,14 @@ pub async fn get_version_metadata(version: &str, mut options: Option<VersionMeta
     };
     let version_info = match manifest.versions.iter().find(|v| v.id == version_id) {
         Some(info) => info.clone(),
-        None => return Err("Version not found".into()),
+        None => return Err(Box::new(get_version_not_found(&version_id, &manifest))),
     };
 
-    let package = reqwest::get(&version_info.url)
-        .await?
+    let timeout_duration = options.reqwest_timeout.unwrap_or(Duration::from_secs(10));
+    let package = get_with_retry(&options.endpoints.rewrite(&version_info.url), timeout_duration).await?
         .json::<PackageInfo>()
         .await?;
-    println!("{:?}", package);
+    debug!("Version package loaded: {}", package.id);
     let info_metadata = InfoMetadata {
         version: version_id,
         info_version: version_info,
@@ -241,4 +365,108 @@ pub async fn get_version_metadata(version: &str, mut options: Option<VersionMeta
 
 pub fn is_older(package: &PackageInfo) -> bool {
     package.assets == "legacy" || package.assets == "pre-1.6"
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn versions_without_optional_fields_sort_by_time() {
+        let manifest: VersionManifest = serde_json::from_value(json!({
+            "latest": { "release": "1.20.1", "snapshot": "1.20.1" },
+            "versions": [
+                { "id": "rd-132211", "type": "old_alpha", "url": "", "time": "2009-05-13T20:11:00+00:00", "sha1": "" },
+                {
+                    "id": "1.20.1", "type": "release", "url": "", "sha1": "",
+                    "time": "2023-06-12T13:25:51+00:00", "releaseTime": "2023-06-12T13:25:51+00:00", "complianceLevel": 1,
+                },
+                { "id": "b1.7.3", "type": "old_beta", "url": "", "time": "2011-07-07T22:00:00+00:00", "sha1": "" },
+                {
+                    "id": "1.20.1-rc1", "type": "snapshot", "url": "", "sha1": "",
+                    "time": "2023-06-12T13:25:51+00:00", "releaseTime": "2023-06-12T13:25:51+00:00",
+                },
+            ],
+        })).unwrap();
+
+        let ids: Vec<String> = filter_versions(manifest.versions, &[]).into_iter().map(|version| version.id).collect();
+        assert_eq!(ids, vec!["1.20.1", "1.20.1-rc1", "b1.7.3", "rd-132211"]);
+    }
+
+    #[test]
+    fn last_matching_rule_wins() {
+        // LWJGL entry of the b1.7.3 version json, checksums left out
+        let library: Library = serde_json::from_value(json!({
+            "downloads": { "artifact": {
+                "path": "org/lwjgl/lwjgl/lwjgl/2.9.0/lwjgl-2.9.0.jar",
+                "sha1": "", "size": 0,
+                "url": "https://libraries.minecraft.net/org/lwjgl/lwjgl/lwjgl/2.9.0/lwjgl-2.9.0.jar",
+            } },
+            "name": "org.lwjgl.lwjgl:lwjgl:2.9.0",
+            "rules": [{ "action": "allow" }, { "action": "disallow", "os": { "name": "osx" } }],
+        })).unwrap();
+        let rules = library.rules.unwrap();
+        assert!(rules_allow(&rules, "linux", "x86_64"));
+        assert!(rules_allow(&rules, "windows", "x86"));
+        assert!(!rules_allow(&rules, "osx", "aarch64"));
+
+        let rules: Vec<Rule> = serde_json::from_value(json!([
+            { "action": "allow", "os": { "name": "osx" } },
+            { "action": "disallow", "os": { "arch": "x86" } },
+        ])).unwrap();
+        assert!(rules_allow(&rules, "osx", "x86_64"));
+        assert!(!rules_allow(&rules, "osx", "x86"));
+        assert!(!rules_allow(&rules, "linux", "x86_64"));
+        assert!(!rules_allow(&[], "linux", "x86_64"));
+    }
+
+    #[test]
+    fn versions_are_filtered_by_type() {
+        let manifest: VersionManifest = serde_json::from_value(json!({
+            "latest": { "release": "1.20.1", "snapshot": "1.20.1" },
+            "versions": [
+                { "id": "rd-132211", "type": "old_alpha", "url": "", "time": "2009-05-13T20:11:00+00:00", "sha1": "" },
+                { "id": "1.20.1", "type": "release", "url": "", "time": "2023-06-12T13:25:51+00:00", "sha1": "" },
+            ],
+        })).unwrap();
+
+        let ids: Vec<String> = filter_versions(manifest.versions, &["old_alpha".to_string()]).into_iter().map(|version| version.id).collect();
+        assert_eq!(ids, vec!["rd-132211"]);
+    }
+
+    #[tokio::test]
+    async fn version_package_is_fetched_from_the_endpoints() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/mc/game/version_manifest_v2.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "latest": { "release": "1.20.1", "snapshot": "23w31a" },
+                "versions": [{
+                    "id": "1.20.1", "type": "release", "sha1": "",
+                    "url": "https://piston-meta.mojang.com/v1/packages/b6e9b5e3d5d4d5c7f8b4d1b1e4d0a3a0e6e5c0b1/1.20.1.json",
+                    "time": "2023-06-12T13:25:51+00:00", "releaseTime": "2023-06-12T13:25:51+00:00",
+                }],
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v1/packages/b6e9b5e3d5d4d5c7f8b4d1b1e4d0a3a0e6e5c0b1/1.20.1.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(include_str!("../../../tests/fixtures/1.20.1.json")))
+            .mount(&server)
+            .await;
+
+        let metadata = get_version_metadata("lr", Some(VersionMetadataOptions {
+            reqwest_timeout: Some(Duration::from_secs(5)),
+            endpoints: Endpoints::with_base(&server.uri()),
+        })).await.unwrap();
+        assert_eq!(metadata.version, "1.20.1");
+        assert_eq!(metadata.info_version.type_, "release");
+        assert_eq!(metadata.package.id, "1.20.1");
+        assert_eq!(metadata.package.libraries.len(), 29);
+        assert_eq!(metadata.package.java_version.unwrap().major_version, 17);
+    }
+}
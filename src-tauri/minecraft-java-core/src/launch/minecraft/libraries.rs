@@ -1,56 +1,81 @@
-use std::fs::create_dir_all;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, remove_dir_all, File};
+use std::io;
 use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, Utc};
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use serde_json::to_string;
+use serde_json::to_vec;
 use crate::launch::downloader::FileDownloadMetadata;
+use crate::launch::endpoints::Endpoints;
 
 use crate::launch::minecraft::json::{ArtifactDownload, PackageInfo};
-use crate::launch::utils::{create_temp_file_with_content, get_arch_name, get_os_name};
+use crate::launch::utils::{get_native_classifier, get_os_name, get_path_libraries, natives_dir};
+use crate::launch::http::get_with_retry;
+use tracing::debug;
 
-pub async  fn get_libraries(package_info: &PackageInfo) -> Result<Vec<FileDownloadMetadata>, Box<dyn std::error::Error>> {
+/// Natives are left out when `include_natives` is false, a headless server has no use for them.
+/// `artifact_overrides` replaces the url and sha1 of the libraries matching its maven coordinates
+pub async  fn get_libraries(package_info: &PackageInfo, endpoints: &Endpoints, include_natives: bool, artifact_overrides: &HashMap<String, ArtifactDownload>) -> Result<Vec<FileDownloadMetadata>, Box<dyn std::error::Error>> {
     let platform = get_os_name();
-    let arch = get_arch_name();
     let mut libraries = Vec::new();
 
     for lib in &package_info.libraries {
         let mut type_ = "Libraries";
         let artifact: Option<ArtifactDownload>;
 
+        if !lib.is_allowed() {
+            debug!("Skipping library {}: its rules don't allow {}", lib.name, platform);
+            continue;
+        }
         if let Some(natives) = &lib.natives {
+            if !include_natives {
+                debug!("Skipping library {}: natives are not needed", lib.name);
+                continue;
+            }
             type_ = "Natives";
-            if let Some(native_str) = natives.get(platform) {
-                let modified_native = native_str.replace("${arch}", &arch);
+            if let Some(modified_native) = get_native_classifier(natives, lib.downloads.classifiers.as_ref()) {
                 let art = lib.downloads.classifiers.as_ref().and_then(|map| map.get(&modified_native));
                 artifact = art.cloned();
-            } else { continue; }
-        } else {
-            if let Some(rules) = &lib.rules {
-                let os_found = rules.iter().any(|rule| {
-                    rule.os.as_ref().map_or(false, |os| {
-                        os.get("name").map_or(false, |os_name| *os_name == platform)
-                    })
-                });
-                if !os_found { continue; }
+            } else {
+                debug!("Skipping library {}: no native classifier for {}", lib.name, platform);
+                continue;
             }
-            artifact = Some(lib.downloads.artifact.clone().unwrap());
+        } else {
+            artifact = lib.downloads.artifact.clone();
         }
 
-        let artifact = match artifact {
-            Some(artifact) => artifact,
-            None => continue,
+        let mut library = match artifact {
+            Some(artifact) if artifact.url.is_empty() => {
+                // Generated by the loader installer, it still belongs to the class path
+                debug!("Skipping library {}: its artifact has no url", lib.name);
+                continue;
+            },
+            Some(artifact) => FileDownloadMetadata {
+                type_: type_.to_string(),
+                sha1: Some(artifact.sha1),
+                size: Some(artifact.size),
+                path: format!("libraries/{}", artifact.path),
+                url: Some(endpoints.rewrite(&artifact.url)),
+                executable: Some(false),
+                content: None,
+            },
+            None => match &lib.url {
+                Some(url) => get_maven_library(&lib.name, &endpoints.rewrite(url), lib.sha1.clone(), lib.size),
+                None => {
+                    debug!("Skipping library {}: no artifact nor url to download it from", lib.name);
+                    continue;
+                },
+            },
         };
-        libraries.push(FileDownloadMetadata {
-            type_: type_.to_string(),
-            sha1: Some(artifact.sha1),
-            size: Some(artifact.size),
-            path: format!("libraries/{}", artifact.path),
-            url: Some(artifact.url),
-            executable: Some(false),
-            content: None,
-        });
+        // The jar stays where the manifest puts it, the class path doesn't change
+        if let Some(artifact_override) = artifact_overrides.get(&lib.name) {
+            debug!("Overriding library {} with {}", lib.name, artifact_override.url);
+            library.sha1 = Some(artifact_override.sha1.clone());
+            library.size = Some(artifact_override.size);
+            library.url = Some(artifact_override.url.clone());
+        }
+        libraries.push(library);
     }
 
     libraries.push(FileDownloadMetadata {
@@ -58,21 +83,53 @@ pub async  fn get_libraries(package_info: &PackageInfo) -> Result<Vec<FileDownlo
         sha1: Some(package_info.downloads.client.sha1.clone()),
         size: Some(package_info.downloads.client.size),
         path: format!("versions/{}/{}.jar", package_info.id, package_info.id),
-        url: Some(package_info.downloads.client.url.clone()),
+        url: Some(endpoints.rewrite(&package_info.downloads.client.url)),
         executable: Some(false),
         content: None,
     });
-    let temp_file_path = create_temp_file_with_content(to_string(&package_info).unwrap().as_bytes()).await?;
     libraries.push(FileDownloadMetadata {
         type_: "CFILE".to_string(),
         path: format!("versions/{}/{}.json", package_info.id, package_info.id),
-        content: Some(temp_file_path),
+        content: Some(to_vec(&package_info)?),
         executable: Some(false),
         sha1: None,
         size: None,
         url: None,
     });
-    return Ok(libraries);
+    Ok(libraries)
+}
+
+/// Dedicated server jar of the version, it bundles its own libraries
+pub fn get_server_jar(package_info: &PackageInfo, endpoints: &Endpoints) -> Option<FileDownloadMetadata> {
+    let server = package_info.downloads.server.as_ref()?;
+    Some(FileDownloadMetadata {
+        type_: "Jar".to_string(),
+        sha1: Some(server.sha1.clone()),
+        size: Some(server.size),
+        path: server_jar_path(&package_info.id),
+        url: Some(endpoints.rewrite(&server.url)),
+        executable: Some(false),
+        content: None,
+    })
+}
+
+pub fn server_jar_path(version: &str) -> String {
+    format!("versions/{}/{}-server.jar", version, version)
+}
+
+pub fn get_maven_library(name: &str, base_url: &str, sha1: Option<String>, size: Option<u64>) -> FileDownloadMetadata {
+    let lib_parse = get_path_libraries(name, None, None);
+    let base_url = base_url.trim_end_matches('/');
+
+    FileDownloadMetadata {
+        type_: "Libraries".to_string(),
+        sha1,
+        size,
+        path: format!("libraries/{}/{}", lib_parse.path, lib_parse.name),
+        url: Some(format!("{}/{}/{}", base_url, lib_parse.path, lib_parse.name)),
+        executable: Some(false),
+        content: None,
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -100,8 +157,7 @@ async fn get_assets_manifest(url: String, options: AssetsMetadataOptions) -> Res
     let iso_string = current_time.to_rfc3339();
     let url = format!("{}?t={}", url, iso_string);
     let timeout_duration = options.reqwest_timeout.unwrap_or(std::time::Duration::from_secs(10));
-    let client = Client::new();
-    let data = client.get(&url).timeout(timeout_duration).send().await?.json::<AssetsManifest>().await?;
+    let data = get_with_retry(&url, timeout_duration).await?.json::<AssetsManifest>().await?;
     Ok(data)
 }
 
@@ -114,11 +170,11 @@ pub async fn get_assets(url: String, mut options: Option<AssetsMetadataOptions>)
     options = options.or(Some(AssetsMetadataOptions::default()));
     let manifest = get_assets_manifest(url, options.unwrap()).await?;
     let mut data = manifest.data;
-    let temp_file_path = create_temp_file_with_content(to_string(&data).unwrap().as_bytes()).await?;
+    let content = to_vec(&data)?;
     data.push(FileDownloadMetadata {
         type_: "CFILE".to_string(),
         path: format!("versions/{}/assets_manifest.json", manifest.id),
-        content: Some(temp_file_path),
+        content: Some(content),
         executable: Some(false),
         sha1: None,
         size: None,
@@ -129,21 +185,109 @@ pub async fn get_assets(url: String, mut options: Option<AssetsMetadataOptions>)
     })
 }
 
-pub fn get_natives(path: PathBuf, package_info: &PackageInfo, libraries: Vec<FileDownloadMetadata>) -> Vec<FileDownloadMetadata> {
+/// Extracts the native jars of the bundle and returns the files written in the natives folder
+pub fn get_natives(path: PathBuf, package_info: &PackageInfo, libraries: Vec<FileDownloadMetadata>, clean_natives: bool) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
     let natives: Vec<FileDownloadMetadata> = libraries.iter().filter(|lib| lib.type_ == "Natives").cloned().collect();
-    if natives.len() == 0 { return natives; }
-    let mut natives_folder = path.clone();
-    natives_folder.push("versions");
-    natives_folder.push(package_info.id.as_str());
-    natives_folder.push("natives");
+    if natives.is_empty() { return Ok(vec![]); }
+    let natives_folder = natives_dir(&path, &package_info.id);
 
+    // A crashed launch can leave a partially extracted folder behind
+    if clean_natives && Path::new(&natives_folder).exists() {
+        debug!("Cleaning natives folder: {:?}", natives_folder);
+        remove_dir_all(&natives_folder)?;
+    }
     if !Path::new(&natives_folder).exists() {
-        create_dir_all(&natives_folder).unwrap();
+        create_dir_all(&natives_folder)?;
     }
+    let mut extracted = Vec::new();
     for native in &natives {
-        println!("Native: {:?}", native);
-        // TODO
-        // let zip = Zip::from(native.url.as_ref().unwrap().as_str());
+        debug!("Native: {:?}", native.path);
+        let mut archive = zip::ZipArchive::new(File::open(path.join(&native.path))?)?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            // Signatures of the jar, and entries escaping the folder
+            let name = match entry.enclosed_name() {
+                Some(name) if !entry.is_dir() && !name.starts_with("META-INF") => name.to_path_buf(),
+                _ => continue,
+            };
+            let output = natives_folder.join(&name);
+            if let Some(parent) = output.parent() {
+                create_dir_all(parent)?;
+            }
+            io::copy(&mut entry, &mut File::create(&output)?)?;
+            extracted.push(output);
+        }
+    }
+    debug!("{} native files extracted to {:?}", extracted.len(), natives_folder);
+    Ok(extracted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+
+    #[test]
+    fn natives_are_extracted_without_the_signatures() {
+        let directory = tempfile::tempdir().unwrap();
+        let package: PackageInfo = serde_json::from_str(include_str!("../../../tests/fixtures/1.20.1.json")).unwrap();
+        let jar = "libraries/org/lwjgl/lwjgl/3.3.1/lwjgl-3.3.1-natives-linux.jar";
+        create_dir_all(directory.path().join(jar).parent().unwrap()).unwrap();
+        let mut writer = zip::ZipWriter::new(File::create(directory.path().join(jar)).unwrap());
+        for name in ["META-INF/MANIFEST.MF", "linux/x64/org/lwjgl/liblwjgl.so"] {
+            writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(b"native").unwrap();
+        }
+        writer.finish().unwrap();
+        let libraries = vec![FileDownloadMetadata {
+            type_: "Natives".to_string(),
+            path: jar.to_string(),
+            executable: Some(false),
+            sha1: None,
+            size: None,
+            url: None,
+            content: None,
+        }];
+
+        let extracted = get_natives(directory.path().to_path_buf(), &package, libraries, true).unwrap();
+        let natives_folder = natives_dir(directory.path(), &package.id);
+        assert_eq!(extracted, vec![natives_folder.join("linux/x64/org/lwjgl/liblwjgl.so")]);
+        assert_eq!(std::fs::read(&extracted[0]).unwrap(), b"native");
     }
-    return natives;
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn libraries_are_resolved_for_the_platform() {
+        let package: PackageInfo = serde_json::from_str(include_str!("../../../tests/fixtures/1.20.1.json")).unwrap();
+        let libraries = get_libraries(&package, &Endpoints::with_base("http://mirror.test"), true, &HashMap::new()).await.unwrap();
+
+        assert!(libraries.iter().filter_map(|file| file.url.as_deref()).all(|url| url.starts_with("http://mirror.test/")));
+        assert!(libraries.iter().any(|file| file.path == "libraries/com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar"));
+        let platform_natives = match get_os_name() {
+            "osx" => "natives-macos",
+            "windows" => "natives-windows",
+            _ => "natives-linux",
+        };
+        let natives: Vec<&str> = libraries.iter().map(|file| file.path.as_str()).filter(|path| path.contains("natives-")).collect();
+        assert_eq!(natives.len(), 3, "{:?}", natives);
+        assert!(natives.iter().all(|path| path.contains(platform_natives)), "{:?}", natives);
+
+        // The client jar and the version json close the list
+        let last: Vec<(&str, &str)> = libraries[libraries.len() - 2..].iter().map(|file| (file.type_.as_str(), file.path.as_str())).collect();
+        assert_eq!(last, vec![("Jar", "versions/1.20.1/1.20.1.jar"), ("CFILE", "versions/1.20.1/1.20.1.json")]);
+    }
+
+    #[test]
+    fn maven_library_with_a_classifier() {
+        let library = get_maven_library("net.fabricmc:intermediary:1.20.1:v2", "https://maven.fabricmc.net/", None, Some(2048));
+        assert_eq!(library.path, "libraries/net/fabricmc/intermediary/1.20.1/intermediary-1.20.1-v2.jar");
+        assert_eq!(library.url.as_deref(), Some("https://maven.fabricmc.net/net/fabricmc/intermediary/1.20.1/intermediary-1.20.1-v2.jar"));
+        assert_eq!(library.size, Some(2048));
+    }
+
+    #[test]
+    fn maven_library_with_an_extension() {
+        let library = get_maven_library("de.oceanlabs.mcp:mcp_config:1.20.1-20230612.114412@zip", "https://maven.neoforged.net/releases", None, None);
+        assert_eq!(library.path, "libraries/de/oceanlabs/mcp/mcp_config/1.20.1-20230612.114412/mcp_config-1.20.1-20230612.114412.zip");
+        assert_eq!(library.url.as_deref(), Some("https://maven.neoforged.net/releases/de/oceanlabs/mcp/mcp_config/1.20.1-20230612.114412/mcp_config-1.20.1-20230612.114412.zip"));
+    }
+}
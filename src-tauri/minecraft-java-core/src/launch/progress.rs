@@ -0,0 +1,24 @@
+//! Terminal rendering of the download progress, for the standalone binary
+
+use std::sync::Arc;
+use indicatif::{ProgressBar, ProgressStyle};
+use crate::launch::downloader::ProgressCallback;
+
+const TEMPLATE: &str = "{spinner} [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}";
+
+/// `on_progress` callback drawing a progress bar with the speed and remaining time on stderr
+pub fn progress_bar() -> ProgressCallback {
+    let bar = ProgressBar::new(0);
+    bar.set_style(ProgressStyle::with_template(TEMPLATE).unwrap_or_else(|_| ProgressStyle::default_bar()).progress_chars("=> "));
+
+    Arc::new(move |downloaded: u64, total: u64| {
+        // The total is only known once the files are resolved
+        if bar.length() != Some(total) {
+            bar.set_length(total);
+        }
+        bar.set_position(downloaded);
+        if downloaded >= total {
+            bar.finish();
+        }
+    })
+}
@@ -1,9 +1,8 @@
+use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use uuid::Uuid;
+use crate::launch::minecraft::json::ArtifactDownload;
 
 pub fn get_os_name() -> &'static str {
     let platform = env::consts::OS;
@@ -23,7 +22,7 @@ pub fn get_arch_name() -> &'static str {
 
     match arch {
         "x86" | "arm" => "32",
-        "x86_64" | "arm64" => "64",
+        "x86_64" | "arm64" | "aarch64" => "64",
 
         //TODO: Add more arch
         _ => panic!("Unsupported architecture"),
@@ -40,7 +39,7 @@ pub fn get_os_arch_mapping() -> &'static str {
         ("windows", "arm") => "windows-arm64",
 
         ("macos", "x86_64") => "mac-os",
-        ("macos", "arm") => "mac-os-arm64",
+        ("macos", "arm") | ("macos", "aarch64") => "mac-os-arm64",
 
         ("linux", "x86") => "linux-i386",
         ("linux", "x86_64") => "linux",
@@ -50,61 +49,82 @@ pub fn get_os_arch_mapping() -> &'static str {
     }
 }
 
+/// Returns the default (min, max) JVM heap in megabytes, max being half of the RAM capped to 4G
+pub fn get_default_memory() -> (u64, u64) {
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    let total_megabytes = system.total_memory() / 1024 / 1024;
+
+    if total_megabytes == 0 {
+        return (2048, 4096);
+    }
+    let max = (total_megabytes / 2).clamp(512, 4096);
+    ((max / 2).clamp(512, 2048), max)
+}
+
+pub fn get_native_classifier(natives: &HashMap<String, String>, classifiers: Option<&HashMap<String, ArtifactDownload>>) -> Option<String> {
+    let native = natives.get(get_os_name())?;
+    let candidates = [
+        native.replace("${arch}", get_arch_name()),
+        native.replace("-${arch}", "").replace("${arch}", ""),
+    ];
+
+    // LWJGL2 era libraries don't always publish an arch specific classifier
+    match classifiers {
+        Some(classifiers) => candidates.into_iter().find(|candidate| classifiers.contains_key(candidate)),
+        None => candidates.into_iter().next(),
+    }
+}
+
+pub fn natives_dir(path: &Path, version_id: &str) -> PathBuf {
+    path.join("versions").join(version_id).join("natives")
+}
+
 pub struct LibraryPath {
     pub(crate) path: String,
     pub(crate) name: String,
 }
 
-pub fn get_path_libraries(main: &str, native_string: Option<&str>, force_ext: Option<&str>) -> LibraryPath {
-    let lib_split: Vec<&str> = main.split(':').collect();
-    let file_name = if lib_split.len() > 3 {
-        format!("{}-{}", lib_split[2], lib_split[3])
-    } else {
-        lib_split[2].to_string()
-    };
+#[derive(Debug, Clone, PartialEq)]
+pub struct MavenCoordinate {
+    pub(crate) group: String,
+    pub(crate) artifact: String,
+    pub(crate) version: String,
+    pub(crate) classifier: Option<String>,
+    pub(crate) ext: Option<String>,
+}
 
-    let final_file_name = if file_name.contains('@') {
-        file_name.replace('@', ".")
-    } else {
-        format!(
-            "{}{}{}",
-            file_name,
-            native_string.unwrap_or(""),
-            force_ext.unwrap_or(".jar")
-        )
+pub fn parse_maven_coordinate(main: &str) -> MavenCoordinate {
+    let (coordinate, ext) = match main.split_once('@') {
+        Some((coordinate, ext)) => (coordinate, Some(ext.to_string())),
+        None => (main, None),
     };
+    let lib_split: Vec<&str> = coordinate.split(':').collect();
 
-    let path_lib = format!(
-        "{}/{}/{}",
-        lib_split[0].replace(".", "/"),
-        lib_split[1],
-        lib_split[2].split('@').next().unwrap_or_default()
-    );
-
-    LibraryPath {
-        path: path_lib,
-        name: format!("{}-{}", lib_split[1], final_file_name),
+    MavenCoordinate {
+        group: lib_split.first().unwrap_or(&"").to_string(),
+        artifact: lib_split.get(1).unwrap_or(&"").to_string(),
+        version: lib_split.get(2).unwrap_or(&"").to_string(),
+        classifier: lib_split.get(3).map(|classifier| classifier.to_string()),
+        ext,
     }
 }
 
-pub async fn create_temp_file_with_content(content: &[u8]) -> Result<PathBuf, Box<dyn std::error::Error>> {
-    let temp_dir = env::temp_dir();
-    let temp_file_name = Uuid::new_v4().to_string();
-    let temp_file_path = temp_dir.join(temp_file_name).with_extension("tmp");
-
-    let mut temp_file = File::create(&temp_file_path)?;
-    temp_file.write_all(content)?;
-
-    Ok(temp_file_path)
-}
+pub fn get_path_libraries(main: &str, native_string: Option<&str>, force_ext: Option<&str>) -> LibraryPath {
+    let coordinate = parse_maven_coordinate(main);
+    let file_name = match &coordinate.classifier {
+        Some(classifier) => format!("{}-{}", coordinate.version, classifier),
+        None => coordinate.version.clone(),
+    };
+    let ext = match &coordinate.ext {
+        Some(ext) => format!(".{}", ext),
+        None => force_ext.unwrap_or(".jar").to_string(),
+    };
 
-pub async fn read_temp_file_content(temp_file_path: PathBuf) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    println!("Reading temp file: {:?}", temp_file_path);
-    let mut file = File::open(temp_file_path.clone())?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
-    println!("Temp file deleted: {:?}", temp_file_path);
-    Ok(content)
+    LibraryPath {
+        path: format!("{}/{}/{}", coordinate.group.replace('.', "/"), coordinate.artifact, coordinate.version),
+        name: format!("{}-{}{}{}", coordinate.artifact, file_name, native_string.unwrap_or(""), ext),
+    }
 }
 
 pub struct LoaderInfo {
@@ -113,9 +133,14 @@ pub struct LoaderInfo {
     pub(crate) install: Option<String>,
     pub(crate) legacy_metadata: Option<String>,
     pub(crate) legacy_install: Option<String>,
+    // Only the forge entry sets the following ones, there is no forge loader yet
+    #[allow(dead_code)]
     pub(crate) promotions: Option<String>,
+    #[allow(dead_code)]
     pub(crate) universal: Option<String>,
+    #[allow(dead_code)]
     pub(crate) client: Option<String>,
+    #[allow(dead_code)]
     pub(crate) meta: Option<String>,
 }
 
@@ -145,7 +170,7 @@ pub fn get_loader_info(loader_type: &str) -> LoaderInfo {
         },
         "fabric" => LoaderInfo {
             metadata: "https://meta.fabricmc.net/v2/versions".to_string(),
-            json: Some("https://meta.fabricmc.net/v2/versions/loader/{version}/{build}/profile/json".to_string()),
+            json: Some("https://meta.fabricmc.net/v2/versions/loader/${version}/${build}/profile/json".to_string()),
             legacy_metadata: None,
             legacy_install: None,
             promotions: None,
@@ -179,3 +204,123 @@ pub fn get_loader_info(loader_type: &str) -> LoaderInfo {
         _ => panic!("Loader type inconnu!"),
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coordinate(group: &str, artifact: &str, version: &str, classifier: Option<&str>, ext: Option<&str>) -> MavenCoordinate {
+        MavenCoordinate {
+            group: group.to_string(),
+            artifact: artifact.to_string(),
+            version: version.to_string(),
+            classifier: classifier.map(String::from),
+            ext: ext.map(String::from),
+        }
+    }
+
+    /// 1.8 style twitch natives, only the windows ones exist per arch
+    fn legacy_natives() -> HashMap<String, String> {
+        ["linux", "osx", "windows"].iter()
+            .map(|os| (os.to_string(), format!("natives-{}-${{arch}}", os)))
+            .collect()
+    }
+
+    /// Classifiers with empty downloads, only their keys matter
+    fn classifiers(keys: &[&str]) -> HashMap<String, ArtifactDownload> {
+        keys.iter().map(|key| (key.to_string(), ArtifactDownload {
+            path: String::new(),
+            sha1: String::new(),
+            size: 0,
+            url: String::new(),
+        })).collect()
+    }
+
+    #[test]
+    fn library_path_of_a_modern_native() {
+        let library = get_path_libraries("org.lwjgl:lwjgl:3.3.1:natives-linux", None, None);
+        assert_eq!(library.path, "org/lwjgl/lwjgl/3.3.1");
+        assert_eq!(library.name, "lwjgl-3.3.1-natives-linux.jar");
+    }
+
+    #[test]
+    fn library_path_of_a_legacy_native() {
+        let library = get_path_libraries("org.lwjgl.lwjgl:lwjgl-platform:2.9.4-nightly-20150209", Some("-natives-linux"), None);
+        assert_eq!(library.path, "org/lwjgl/lwjgl/lwjgl-platform/2.9.4-nightly-20150209");
+        assert_eq!(library.name, "lwjgl-platform-2.9.4-nightly-20150209-natives-linux.jar");
+    }
+
+    #[test]
+    fn library_path_with_classifier_and_extension() {
+        let library = get_path_libraries("net.minecraftforge:forge:1.20.1-47.2.0:universal@zip", None, None);
+        assert_eq!(library.path, "net/minecraftforge/forge/1.20.1-47.2.0");
+        assert_eq!(library.name, "forge-1.20.1-47.2.0-universal.zip");
+    }
+
+    #[test]
+    fn maven_coordinate_without_optional_parts() {
+        assert_eq!(parse_maven_coordinate("com.mojang:brigadier:1.1.8"), coordinate("com.mojang", "brigadier", "1.1.8", None, None));
+    }
+
+    #[test]
+    fn maven_coordinate_with_classifier() {
+        assert_eq!(
+            parse_maven_coordinate("org.lwjgl:lwjgl:3.3.1:natives-windows"),
+            coordinate("org.lwjgl", "lwjgl", "3.3.1", Some("natives-windows"), None),
+        );
+    }
+
+    #[test]
+    fn maven_coordinate_with_extension_and_no_classifier() {
+        assert_eq!(
+            parse_maven_coordinate("de.oceanlabs.mcp:mcp_config:1.20.1-20230612.114412@zip"),
+            coordinate("de.oceanlabs.mcp", "mcp_config", "1.20.1-20230612.114412", None, Some("zip")),
+        );
+        let library = get_path_libraries("de.oceanlabs.mcp:mcp_config:1.20.1-20230612.114412@zip", None, None);
+        assert_eq!(library.name, "mcp_config-1.20.1-20230612.114412.zip");
+    }
+
+    #[test]
+    fn maven_coordinate_with_classifier_and_extension() {
+        assert_eq!(
+            parse_maven_coordinate("net.minecraftforge:forge:1.20.1-47.2.0:universal@jar"),
+            coordinate("net.minecraftforge", "forge", "1.20.1-47.2.0", Some("universal"), Some("jar")),
+        );
+        let library = get_path_libraries("net.minecraftforge:forge:1.20.1-47.2.0:universal@jar", None, None);
+        assert_eq!(library.path, "net/minecraftforge/forge/1.20.1-47.2.0");
+        assert_eq!(library.name, "forge-1.20.1-47.2.0-universal.jar");
+    }
+
+    #[test]
+    fn forced_extension_applies_only_without_one() {
+        assert_eq!(get_path_libraries("com.mojang:brigadier:1.1.8", None, Some(".zip")).name, "brigadier-1.1.8.zip");
+        assert_eq!(get_path_libraries("com.mojang:brigadier:1.1.8@jar", None, Some(".zip")).name, "brigadier-1.1.8.jar");
+    }
+
+    #[test]
+    fn native_classifier_with_the_arch_substituted() {
+        let expected = format!("natives-{}-{}", get_os_name(), get_arch_name());
+        let classifiers = classifiers(&[&expected, &format!("natives-{}", get_os_name())]);
+        assert_eq!(get_native_classifier(&legacy_natives(), Some(&classifiers)), Some(expected));
+    }
+
+    #[test]
+    fn native_classifier_falls_back_to_the_bare_key() {
+        let expected = format!("natives-{}", get_os_name());
+        let classifiers = classifiers(&[&expected]);
+        assert_eq!(get_native_classifier(&legacy_natives(), Some(&classifiers)), Some(expected));
+    }
+
+    #[test]
+    fn native_classifier_without_classifiers() {
+        let expected = format!("natives-{}-{}", get_os_name(), get_arch_name());
+        assert_eq!(get_native_classifier(&legacy_natives(), None), Some(expected));
+    }
+
+    #[test]
+    fn native_classifier_missing_from_classifiers() {
+        let classifiers = classifiers(&["natives-solaris"]);
+        assert_eq!(get_native_classifier(&legacy_natives(), Some(&classifiers)), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use crate::launch::downloader::file_exists_and_matches_sha1;
+use crate::launch::endpoints::Endpoints;
+use crate::launch::minecraft::assets::AssetsManifest;
+use crate::launch::minecraft::json::PackageInfo;
+use crate::launch::minecraft::libraries::get_libraries;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    /// Files linked or copied into the instance
+    pub imported: usize,
+    /// Files missing or failing verification, left to the next download
+    pub skipped: usize,
+}
+
+/// Links `source` to `target`, copying it when both aren't on the same volume
+async fn link_or_copy(source: &Path, target: &Path) -> std::io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    if fs::hard_link(source, target).await.is_ok() {
+        return Ok(());
+    }
+    fs::copy(source, target).await.map(|_| ())
+}
+
+async fn import_file(dotminecraft_path: &Path, instance_path: &Path, relative: &str, sha1: &str, report: &mut ImportReport) -> bool {
+    let source = dotminecraft_path.join(relative);
+    let target = instance_path.join(relative);
+    if file_exists_and_matches_sha1(&target, sha1).await {
+        return true;
+    }
+    if !file_exists_and_matches_sha1(&source, sha1).await {
+        report.skipped += 1;
+        return false;
+    }
+
+    // A corrupted copy in the instance would prevent the link
+    let _ = fs::remove_file(&target).await;
+    match link_or_copy(&source, &target).await {
+        Ok(_) => {
+            report.imported += 1;
+            true
+        },
+        Err(e) => {
+            warn!("Unable to import {:?}: {}", source, e);
+            report.skipped += 1;
+            false
+        },
+    }
+}
+
+async fn get_vanilla_packages(dotminecraft_path: &Path) -> Result<Vec<PackageInfo>, Box<dyn Error>> {
+    let mut packages = Vec::new();
+    let mut versions = fs::read_dir(dotminecraft_path.join("versions")).await?;
+    while let Some(entry) = versions.next_entry().await? {
+        let id = entry.file_name().to_string_lossy().to_string();
+        let json_path: PathBuf = entry.path().join(format!("{}.json", id));
+        let content = match fs::read(&json_path).await {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        // Loader profiles only hold a partial package and are skipped
+        match serde_json::from_slice::<PackageInfo>(&content) {
+            Ok(package) => packages.push(package),
+            Err(e) => debug!("Skipping version {}: {}", id, e),
+        }
+    }
+    Ok(packages)
+}
+
+/// Reuses the verified libraries, client jars and assets of an existing `.minecraft` folder,
+/// files failing verification are skipped and downloaded again on the next launch
+pub async fn import_vanilla(dotminecraft_path: &Path, instance_path: &Path) -> Result<ImportReport, Box<dyn Error>> {
+    let mut report = ImportReport::default();
+    let mut seen = HashSet::new();
+    let endpoints = Endpoints::default();
+
+    let packages = get_vanilla_packages(dotminecraft_path).await?;
+    for package in packages {
+        info!("Importing version {} from {:?}", package.id, dotminecraft_path);
+        let libraries = get_libraries(&package, &endpoints, true, &HashMap::new()).await?;
+        for file in libraries {
+            let sha1 = match &file.sha1 {
+                Some(sha1) => sha1,
+                None => continue,
+            };
+            if seen.insert(file.path.clone()) {
+                import_file(dotminecraft_path, instance_path, &file.path, sha1, &mut report).await;
+            }
+        }
+
+        let index_path = format!("assets/indexes/{}.json", package.asset_index.id);
+        if !seen.insert(index_path.clone()) {
+            continue;
+        }
+        if !import_file(dotminecraft_path, instance_path, &index_path, &package.asset_index.sha1, &mut report).await {
+            continue;
+        }
+        let manifest: AssetsManifest = serde_json::from_slice(&fs::read(instance_path.join(&index_path)).await?)?;
+        for (name, detail) in &manifest.objects {
+            let object_path = match detail.get_object_key() {
+                Some(key) => format!("assets/objects/{}", key),
+                None => {
+                    warn!("Skipping asset {}: malformed hash {:?}", name, detail.hash);
+                    report.skipped += 1;
+                    continue;
+                },
+            };
+            if seen.insert(object_path.clone()) {
+                import_file(dotminecraft_path, instance_path, &object_path, &detail.hash, &mut report).await;
+            }
+        }
+    }
+
+    info!("{} files imported, {} skipped", report.imported, report.skipped);
+    Ok(report)
+}
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sha1::{Digest, Sha1};
+    use super::*;
+
+    fn sha1(bytes: &[u8]) -> String {
+        format!("{:x}", Sha1::digest(bytes))
+    }
+
+    fn write(root: &Path, relative: &str, content: &[u8]) {
+        let path = root.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    /// `.minecraft` holding 1.20.1 with a library, its client jar and an asset, plus a missing library and a malformed asset
+    fn dotminecraft(root: &Path) {
+        let library = |name: &str, path: &str, content: &[u8]| json!({
+            "downloads": { "artifact": { "path": path, "sha1": sha1(content), "size": content.len(), "url": format!("https://libraries.minecraft.net/{}", path) } },
+            "name": name,
+        });
+        let index = serde_json::to_vec(&json!({
+            "objects": {
+                "minecraft/lang/fr_fr.json": { "hash": sha1(b"fr_fr"), "size": 5 },
+                "minecraft/lang/broken.json": { "hash": "../../options.txt", "size": 5 },
+            },
+        })).unwrap();
+        let package = json!({
+            "arguments": { "game": [], "jvm": [] },
+            "assetIndex": { "id": "5", "sha1": sha1(&index), "size": index.len(), "totalSize": 5, "url": "" },
+            "assets": "5",
+            "downloads": { "client": { "sha1": sha1(b"client"), "size": 6, "url": "https://piston-data.mojang.com/client.jar" } },
+            "id": "1.20.1",
+            "libraries": [
+                library("com.mojang:brigadier:1.1.8", "com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar", b"brigadier"),
+                library("com.mojang:datafixerupper:6.0.8", "com/mojang/datafixerupper/6.0.8/datafixerupper-6.0.8.jar", b"dfu"),
+            ],
+            "mainClass": "net.minecraft.client.main.Main",
+            "minimumLauncherVersion": 21,
+            "time": "2023-06-12T13:25:51+00:00",
+            "type": "release",
+        });
+
+        write(root, "versions/1.20.1/1.20.1.json", &serde_json::to_vec(&package).unwrap());
+        write(root, "versions/1.20.1/1.20.1.jar", b"client");
+        write(root, "libraries/com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar", b"brigadier");
+        write(root, "assets/indexes/5.json", &index);
+        write(root, &format!("assets/objects/{}/{}", &sha1(b"fr_fr")[..2], sha1(b"fr_fr")), b"fr_fr");
+        write(root, "options.txt", b"lang:fr_fr");
+    }
+
+    #[tokio::test]
+    async fn verified_files_are_imported() {
+        let source = tempfile::tempdir().unwrap();
+        let instance = tempfile::tempdir().unwrap();
+        dotminecraft(source.path());
+
+        let report = import_vanilla(source.path(), instance.path()).await.unwrap();
+        assert_eq!((report.imported, report.skipped), (4, 2));
+        assert_eq!(std::fs::read(instance.path().join("libraries/com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar")).unwrap(), b"brigadier");
+        assert!(instance.path().join("versions/1.20.1/1.20.1.jar").exists());
+        assert!(instance.path().join(format!("assets/objects/{}/{}", &sha1(b"fr_fr")[..2], sha1(b"fr_fr"))).exists());
+        assert!(!instance.path().join("options.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn corrupted_files_are_left_to_the_download() {
+        let source = tempfile::tempdir().unwrap();
+        let instance = tempfile::tempdir().unwrap();
+        dotminecraft(source.path());
+        write(source.path(), "versions/1.20.1/1.20.1.jar", b"corrupted");
+
+        let report = import_vanilla(source.path(), instance.path()).await.unwrap();
+        assert_eq!((report.imported, report.skipped), (3, 3));
+        assert!(!instance.path().join("versions/1.20.1/1.20.1.jar").exists());
+    }
+}
@@ -0,0 +1 @@
+pub mod launch;
\ No newline at end of file
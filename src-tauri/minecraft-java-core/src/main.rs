@@ -1,21 +1,67 @@
-use crate::launch::{LaunchMetadata, Memory};
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::Parser;
+use minecraft_java_core::launch::{self, LaunchMetadata, Loader, Memory};
 
-mod launch;
+/// Installs and launches a Minecraft instance
+#[derive(Debug, Parser)]
+#[command(disable_version_flag = true)]
+struct Args {
+    /// Version to launch, `latest_release` and `latest_snapshot` are resolved from the manifest
+    #[arg(long, default_value = "1.20.1")]
+    version: String,
+    /// Initial heap
+    #[arg(long, default_value = "2G")]
+    xms: String,
+    /// Maximum heap
+    #[arg(long, default_value = "4G")]
+    xmx: String,
+    /// Loader as `<type>[:<build>]`, e.g. `fabric` or `neoforge:47.1.79`, the latest build when omitted
+    #[arg(long)]
+    loader: Option<String>,
+    /// Instance name, the version when unset
+    #[arg(long)]
+    instance: Option<String>,
+    /// Install folder, `./instances` when unset
+    #[arg(long)]
+    dir: Option<PathBuf>,
+}
+
+fn parse_loader(loader: &str) -> Loader {
+    let (type_, build) = loader.split_once(':').unwrap_or((loader, "latest"));
+    Loader {
+        type_: type_.to_string(),
+        // Replaced by the launched version
+        version: String::new(),
+        build: build.to_string(),
+        path: None,
+        enable: Some(true),
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let path = std::env::current_dir().unwrap();
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+    let path = args.dir.unwrap_or_else(|| std::env::current_dir().unwrap().join("instances"));
 
-    launch::launch_minecraft(Some(LaunchMetadata {
+    let instance = launch::launch_minecraft(Some(LaunchMetadata {
         path,
-        version: "1.20.1".to_owned(),
-        instance_name: None,
-        loader: None,
+        version: args.version,
+        instance_name: args.instance,
+        loader: args.loader.as_deref().map(parse_loader),
         java: None,
         screen: None,
         memory: Memory {
-            min: Some("2G".to_owned()),
-            max: Some("4G".to_owned()),
+            min: Some(args.xms),
+            max: Some(args.xmx),
         },
-    })).await;
+        #[cfg(feature = "progress-bar")]
+        on_progress: Some(launch::progress_bar()),
+        ..Default::default()
+    })).await.unwrap();
+
+    while launch::is_running(&instance.name) {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
 }
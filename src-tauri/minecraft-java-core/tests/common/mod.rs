@@ -0,0 +1,203 @@
+//! Canned Mojang metadata and files served by a local mock server, reached through `Endpoints::with_base`
+
+// Compiled into every test file, each one uses part of it
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use minecraft_java_core::launch::{Endpoints, LaunchMetadata};
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+pub const VERSION: &str = "1.20.1";
+pub const JAVA_VERSION: &str = "17.0.8";
+pub const LIBRARY_PATH: &str = "com/mojang/brigadier/1.1.8/brigadier-1.1.8.jar";
+pub const ASSET_NAME: &str = "minecraft/lang/fr_fr.json";
+/// Sent with every file, 1445412480 seconds after the epoch
+pub const LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+pub const CLIENT_JAR: &[u8] = b"PK\x03\x04 fake client jar";
+pub const SERVER_JAR: &[u8] = b"PK\x03\x04 fake server jar";
+pub const LIBRARY_JAR: &[u8] = b"PK\x03\x04 fake brigadier jar";
+pub const JAVA_BINARY: &[u8] = b"#!/bin/sh\necho fake java";
+pub const ASSET_OBJECT: &[u8] = br#"{"language.name":"Francais"}"#;
+/// Executables of the fake runtime, one per platform layout
+pub const JAVA_FILES: [&str; 3] = ["bin/java", "bin/java.exe", "bin/javaw.exe"];
+
+pub fn sha1(bytes: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(bytes))
+}
+
+pub fn asset_index() -> Vec<u8> {
+    serde_json::to_vec(&json!({
+        "objects": {
+            ASSET_NAME: { "hash": sha1(ASSET_OBJECT), "size": ASSET_OBJECT.len() },
+        },
+    })).unwrap()
+}
+
+/// Bytes downloaded by a fresh install, as counted by `estimate_download_size`
+pub fn download_size() -> u64 {
+    (CLIENT_JAR.len() + LIBRARY_JAR.len() + JAVA_BINARY.len() * JAVA_FILES.len() + asset_index().len() + ASSET_OBJECT.len()) as u64
+}
+
+fn version_manifest() -> Value {
+    let version = |id: &str, type_: &str, time: &str| json!({
+        "id": id,
+        "type": type_,
+        "url": format!("https://piston-meta.mojang.com/v1/packages/{}/{}.json", sha1(id.as_bytes()), id),
+        "time": time,
+        "releaseTime": time,
+        "sha1": sha1(id.as_bytes()),
+        "complianceLevel": 1,
+    });
+    json!({
+        "latest": { "release": VERSION, "snapshot": "23w31a" },
+        "versions": [
+            version("23w31a", "snapshot", "2023-08-01T12:00:00+00:00"),
+            version(VERSION, "release", "2023-06-12T13:25:51+00:00"),
+            version("1.20", "release", "2023-06-02T08:36:17+00:00"),
+        ],
+    })
+}
+
+fn package() -> Value {
+    let asset_index = asset_index();
+    json!({
+        "arguments": {
+            "game": ["--username", "${auth_player_name}", "--version", "${version_name}", "--gameDir", "${game_directory}"],
+            "jvm": ["-cp", "${classpath}"],
+        },
+        "assetIndex": {
+            "id": "5",
+            "sha1": sha1(&asset_index),
+            "size": asset_index.len(),
+            "totalSize": ASSET_OBJECT.len(),
+            "url": format!("https://piston-meta.mojang.com/v1/packages/{}/5.json", sha1(&asset_index)),
+        },
+        "assets": "5",
+        "downloads": {
+            "client": {
+                "sha1": sha1(CLIENT_JAR),
+                "size": CLIENT_JAR.len(),
+                "url": format!("https://piston-data.mojang.com/v1/objects/{}/client.jar", sha1(CLIENT_JAR)),
+            },
+            "server": {
+                "sha1": sha1(SERVER_JAR),
+                "size": SERVER_JAR.len(),
+                "url": format!("https://piston-data.mojang.com/v1/objects/{}/server.jar", sha1(SERVER_JAR)),
+            },
+        },
+        "id": VERSION,
+        "javaVersion": { "component": "java-runtime-gamma", "majorVersion": 17 },
+        "libraries": [{
+            "downloads": {
+                "artifact": {
+                    "path": LIBRARY_PATH,
+                    "sha1": sha1(LIBRARY_JAR),
+                    "size": LIBRARY_JAR.len(),
+                    "url": format!("https://libraries.minecraft.net/{}", LIBRARY_PATH),
+                },
+            },
+            "name": "com.mojang:brigadier:1.1.8",
+        }],
+        "mainClass": "net.minecraft.client.main.Main",
+        "minimumLauncherVersion": 21,
+        "releaseTime": "2023-06-12T13:25:51+00:00",
+        "time": "2023-06-12T13:25:51+00:00",
+        "type": "release",
+    })
+}
+
+fn java_manifest() -> Value {
+    let mut files = serde_json::Map::new();
+    files.insert("bin".to_string(), json!({ "type": "directory" }));
+    for file in JAVA_FILES {
+        files.insert(file.to_string(), json!({
+            "type": "file",
+            "executable": true,
+            "downloads": {
+                "raw": {
+                    "sha1": sha1(JAVA_BINARY),
+                    "size": JAVA_BINARY.len(),
+                    "url": format!("https://piston-data.mojang.com/v1/objects/{}/java", sha1(JAVA_BINARY)),
+                },
+            },
+        }));
+    }
+    json!({ "files": files })
+}
+
+/// Same runtime for every platform, the tests don't depend on the host
+fn java_runtimes(java_manifest: &[u8]) -> Value {
+    let runtime = json!([{
+        "availability": { "group": 1, "progress": 100 },
+        "manifest": {
+            "sha1": sha1(java_manifest),
+            "size": java_manifest.len(),
+            "url": format!("https://piston-meta.mojang.com/v1/packages/{}/manifest.json", sha1(java_manifest)),
+        },
+        "version": { "name": JAVA_VERSION, "released": "2023-07-18T00:00:00+00:00" },
+    }]);
+    let platform = json!({
+        "java-runtime-gamma": runtime,
+        "java-runtime-delta": runtime,
+        "java-runtime-alpha": [],
+        "java-runtime-beta": [],
+        "java-runtime-gamma-snapshot": [],
+        "jre-legacy": [],
+        "minecraft-java-exe": [],
+    });
+    let platforms = ["gamecore", "linux", "linux-i386", "mac-os", "mac-os-arm64", "windows-arm64", "windows-x64", "windows-x86"];
+    Value::Object(platforms.iter().map(|name| (name.to_string(), platform.clone())).collect())
+}
+
+pub struct MojangMock {
+    pub server: MockServer,
+}
+
+impl MojangMock {
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+        let java_manifest = serde_json::to_vec(&java_manifest()).unwrap();
+        let asset_index = asset_index();
+        let asset_hash = sha1(ASSET_OBJECT);
+
+        let routes: Vec<(String, Vec<u8>)> = vec![
+            ("/mc/game/version_manifest_v2.json".to_string(), serde_json::to_vec(&version_manifest()).unwrap()),
+            (format!("/v1/packages/{}/{}.json", sha1(VERSION.as_bytes()), VERSION), serde_json::to_vec(&package()).unwrap()),
+            ("/v1/products/java-runtime/all.json".to_string(), serde_json::to_vec(&java_runtimes(&java_manifest)).unwrap()),
+            (format!("/v1/packages/{}/manifest.json", sha1(&java_manifest)), java_manifest.clone()),
+            (format!("/v1/objects/{}/java", sha1(JAVA_BINARY)), JAVA_BINARY.to_vec()),
+            (format!("/v1/objects/{}/client.jar", sha1(CLIENT_JAR)), CLIENT_JAR.to_vec()),
+            (format!("/v1/objects/{}/server.jar", sha1(SERVER_JAR)), SERVER_JAR.to_vec()),
+            (format!("/{}", LIBRARY_PATH), LIBRARY_JAR.to_vec()),
+            (format!("/v1/packages/{}/5.json", sha1(&asset_index)), asset_index.clone()),
+            (format!("/{}/{}", &asset_hash[..2], asset_hash), ASSET_OBJECT.to_vec()),
+            ("/assets_manifest.json".to_string(), serde_json::to_vec(&json!({ "id": VERSION, "data": [] })).unwrap()),
+        ];
+        for (route, body) in routes {
+            Mock::given(method("GET"))
+                .and(path(route))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(body).insert_header("Last-Modified", LAST_MODIFIED))
+                .mount(&server)
+                .await;
+        }
+        Self { server }
+    }
+
+    pub fn endpoints(&self) -> Endpoints {
+        Endpoints::with_base(&self.server.uri())
+    }
+
+    pub fn launch_options(&self, path: &Path) -> LaunchMetadata {
+        LaunchMetadata {
+            path: path.to_path_buf(),
+            version: VERSION.to_string(),
+            endpoints: self.endpoints(),
+            ..Default::default()
+        }
+    }
+}
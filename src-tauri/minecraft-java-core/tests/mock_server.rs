@@ -0,0 +1,369 @@
+mod common;
+
+use std::time::{Duration, UNIX_EPOCH};
+use common::{MojangMock, ASSET_OBJECT, CLIENT_JAR, JAVA_VERSION, LIBRARY_JAR, LIBRARY_PATH, SERVER_JAR, VERSION};
+use minecraft_java_core::launch::{self, ArtifactDownload, CorruptClientJar, DownloadFailed, InstanceKind, JavaRuntimeUnavailable, LaunchMetadata, LoaderError, MissingDownloadManifest, RuntimeScope, VersionNotFound};
+use wiremock::matchers::{self, method};
+use wiremock::{Mock, ResponseTemplate};
+
+#[tokio::test]
+async fn download_size_is_estimated_from_the_metadata() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+
+    let size = launch::estimate_download_size(VERSION, Some(mock.launch_options(directory.path()))).await.unwrap();
+    assert_eq!(size, common::download_size());
+}
+
+#[tokio::test]
+async fn latest_release_alias_is_resolved() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+
+    let alias = launch::estimate_download_size("latest_release", Some(mock.launch_options(directory.path()))).await.unwrap();
+    assert_eq!(alias, common::download_size());
+}
+
+#[tokio::test]
+async fn unknown_version_suggests_the_closest_ones() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+
+    let error = launch::estimate_download_size("1.20.2", Some(mock.launch_options(directory.path()))).await.unwrap_err();
+    let not_found = error.downcast_ref::<VersionNotFound>().expect("version not found error");
+    assert_eq!(not_found.suggestions, vec!["1.20.1", "1.20"]);
+    assert_eq!(not_found.latest_release, VERSION);
+    assert_eq!(not_found.latest_snapshot, "23w31a");
+}
+
+#[tokio::test]
+async fn missing_java_runtime_index_is_reported() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let mut options = mock.launch_options(directory.path());
+    options.endpoints.java_runtime_manifest = format!("{}/v1/products/java-runtime/rotated/all.json", options.endpoints.meta);
+
+    let error = launch::estimate_download_size(VERSION, Some(options)).await.unwrap_err();
+    let unavailable = error.downcast_ref::<JavaRuntimeUnavailable>().expect("java runtime unavailable error");
+    assert!(unavailable.url.ends_with("/rotated/all.json"));
+}
+
+#[tokio::test]
+async fn java_component_is_checked_against_the_runtimes_index() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let with_component = |component: &str| LaunchMetadata {
+        java: Some(launch::Java {
+            path: None,
+            version: Some(component.to_string()),
+            type_: None,
+        }),
+        ..mock.launch_options(directory.path())
+    };
+
+    // Not known when this crate was written, but listed by the index
+    let size = launch::estimate_download_size(VERSION, Some(with_component("java-runtime-delta"))).await.unwrap();
+    assert_eq!(size, common::download_size());
+
+    let error = launch::estimate_download_size(VERSION, Some(with_component("java-runtime-omega"))).await.unwrap_err();
+    assert!(error.to_string().contains("java-runtime-delta"));
+}
+
+#[tokio::test]
+async fn install_downloads_every_file() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+
+    launch::install_minecraft(Some(mock.launch_options(path))).await.unwrap();
+
+    assert_eq!(std::fs::read(path.join("versions/1.20.1/1.20.1.jar")).unwrap(), CLIENT_JAR);
+    assert_eq!(std::fs::read(path.join("libraries").join(LIBRARY_PATH)).unwrap(), LIBRARY_JAR);
+    let asset_hash = common::sha1(ASSET_OBJECT);
+    assert_eq!(std::fs::read(path.join(format!("assets/objects/{}/{}", &asset_hash[..2], asset_hash))).unwrap(), ASSET_OBJECT);
+}
+
+#[tokio::test]
+async fn missing_library_fails_the_install() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    Mock::given(method("GET"))
+        .and(matchers::path(format!("/{}", LIBRARY_PATH)))
+        .respond_with(ResponseTemplate::new(404))
+        .with_priority(1)
+        .mount(&mock.server)
+        .await;
+
+    let error = launch::install_minecraft(Some(mock.launch_options(directory.path()))).await.unwrap_err();
+    let failed = error.downcast_ref::<DownloadFailed>().expect("download failed error");
+    assert_eq!(failed.files, vec![format!("libraries/{}", LIBRARY_PATH)]);
+}
+
+#[tokio::test]
+async fn server_instance_installs_the_dedicated_server() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    let options = LaunchMetadata { kind: InstanceKind::Server, ..mock.launch_options(path) };
+
+    let size = launch::estimate_download_size(VERSION, Some(options.clone())).await.unwrap();
+    assert_eq!(size, (SERVER_JAR.len() + common::JAVA_BINARY.len() * common::JAVA_FILES.len()) as u64);
+    launch::install_minecraft(Some(options)).await.unwrap();
+
+    assert_eq!(std::fs::read(path.join("versions/1.20.1/1.20.1-server.jar")).unwrap(), SERVER_JAR);
+    assert!(!path.join("versions/1.20.1/1.20.1.jar").exists());
+    assert!(!path.join("libraries").exists());
+    assert!(!path.join("assets").exists());
+}
+
+#[tokio::test]
+async fn server_timestamps_are_preserved() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    let client_jar = path.join("versions/1.20.1/1.20.1.jar");
+
+    launch::install_minecraft(Some(mock.launch_options(path))).await.unwrap();
+    assert!(std::fs::metadata(&client_jar).unwrap().modified().unwrap() > UNIX_EPOCH + Duration::from_secs(1445412480));
+
+    std::fs::remove_file(&client_jar).unwrap();
+    launch::install_minecraft(Some(LaunchMetadata {
+        preserve_timestamps: true,
+        ..mock.launch_options(path)
+    })).await.unwrap();
+    assert_eq!(std::fs::metadata(&client_jar).unwrap().modified().unwrap(), UNIX_EPOCH + Duration::from_secs(1445412480));
+}
+
+#[tokio::test]
+async fn artifact_override_replaces_the_library() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    let patched: &[u8] = b"PK\x03\x04 patched brigadier jar";
+    Mock::given(method("GET"))
+        .and(matchers::path("/patched/brigadier.jar"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(patched))
+        .mount(&mock.server)
+        .await;
+
+    let mut options = mock.launch_options(path);
+    options.artifact_overrides.insert("com.mojang:brigadier:1.1.8".to_string(), ArtifactDownload {
+        path: String::new(),
+        sha1: common::sha1(patched),
+        size: patched.len() as u64,
+        url: format!("{}/patched/brigadier.jar", mock.server.uri()),
+    });
+    launch::install_minecraft(Some(options)).await.unwrap();
+
+    assert_eq!(std::fs::read(path.join("libraries").join(LIBRARY_PATH)).unwrap(), patched);
+}
+
+#[tokio::test]
+async fn lazy_assets_are_downloaded_on_demand() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    let asset_hash = common::sha1(ASSET_OBJECT);
+    let object_path = path.join(format!("assets/objects/{}/{}", &asset_hash[..2], asset_hash));
+
+    launch::install_minecraft(Some(LaunchMetadata {
+        lazy_assets: true,
+        ..mock.launch_options(path)
+    })).await.unwrap();
+    assert!(path.join("assets/indexes/5.json").exists());
+    assert!(!object_path.exists());
+
+    launch::download_assets(|name| name.starts_with("minecraft/sounds/"), Some(mock.launch_options(path))).await.unwrap();
+    assert!(!object_path.exists());
+    launch::download_assets(|name| name.starts_with("minecraft/lang/"), Some(mock.launch_options(path))).await.unwrap();
+    assert_eq!(std::fs::read(object_path).unwrap(), ASSET_OBJECT);
+}
+
+#[tokio::test]
+async fn unverified_assets_are_trusted_on_presence() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    let asset_hash = common::sha1(ASSET_OBJECT);
+    let object_path = path.join(format!("assets/objects/{}/{}", &asset_hash[..2], asset_hash));
+
+    launch::install_minecraft(Some(mock.launch_options(path))).await.unwrap();
+    let tampered = vec![b'x'; ASSET_OBJECT.len()];
+    std::fs::write(&object_path, &tampered).unwrap();
+    // The index would otherwise skip the objects altogether
+    std::fs::remove_file(path.join("assets/indexes/5.json")).unwrap();
+
+    launch::install_minecraft(Some(LaunchMetadata {
+        verify_assets: false,
+        ..mock.launch_options(path)
+    })).await.unwrap();
+    assert_eq!(std::fs::read(&object_path).unwrap(), tampered);
+
+    std::fs::remove_file(path.join("assets/indexes/5.json")).unwrap();
+    launch::install_minecraft(Some(mock.launch_options(path))).await.unwrap();
+    assert_eq!(std::fs::read(&object_path).unwrap(), ASSET_OBJECT);
+}
+
+#[tokio::test]
+async fn instance_runtime_is_kept_apart() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+
+    launch::install_minecraft(Some(LaunchMetadata {
+        instance_name: Some("modpack".to_string()),
+        runtime_scope: RuntimeScope::Instance,
+        ..mock.launch_options(path)
+    })).await.unwrap();
+
+    let runtimes: Vec<String> = std::fs::read_dir(path.join("runtime/instances/modpack")).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(runtimes.len(), 1);
+    assert!(runtimes[0].starts_with(&format!("jre-{}-", JAVA_VERSION)));
+    assert!(!path.join("runtime").join(&runtimes[0]).exists());
+}
+
+#[tokio::test]
+async fn installed_version_is_detected() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    assert!(!launch::is_version_installed(path, VERSION, false).await);
+
+    launch::install_minecraft(Some(mock.launch_options(path))).await.unwrap();
+    assert!(launch::is_version_installed(path, VERSION, true).await);
+
+    std::fs::write(path.join("versions/1.20.1/1.20.1.jar"), b"corrupted").unwrap();
+    assert!(launch::is_version_installed(path, VERSION, false).await);
+    assert!(!launch::is_version_installed(path, VERSION, true).await);
+}
+
+#[tokio::test]
+async fn uninstalled_loader_leaves_vanilla_files() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    launch::install_minecraft(Some(LaunchMetadata { export_manifest: true, ..mock.launch_options(path) })).await.unwrap();
+
+    // What a fabric install leaves behind: its profile and a library listed in the manifest
+    let manifest_path = path.join("versions/1.20.1/download_manifest.json");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+    manifest["files"].as_array_mut().unwrap().push(serde_json::json!({
+        "type": "Libraries",
+        "path": "libraries/net/fabricmc/fabric-loader/0.14.21/fabric-loader-0.14.21.jar",
+        "executable": false,
+        "sha1": null,
+        "size": null,
+        "url": null,
+    }));
+    std::fs::write(&manifest_path, serde_json::to_vec(&manifest).unwrap()).unwrap();
+    let loader_library = path.join("libraries/net/fabricmc/fabric-loader/0.14.21/fabric-loader-0.14.21.jar");
+    std::fs::create_dir_all(loader_library.parent().unwrap()).unwrap();
+    std::fs::write(&loader_library, b"fabric").unwrap();
+    let profile_path = path.join("versions/fabric-loader-0.14.21-1.20.1");
+    std::fs::create_dir_all(&profile_path).unwrap();
+    std::fs::write(profile_path.join("fabric-loader-0.14.21-1.20.1.json"), br#"{"inheritsFrom": "1.20.1"}"#).unwrap();
+
+    let removed = launch::uninstall_loader(path, VERSION).await.unwrap();
+    assert_eq!(removed, vec![loader_library.clone(), profile_path.clone()]);
+    assert!(!loader_library.exists());
+    assert!(!profile_path.exists());
+    assert!(path.join("libraries").join(LIBRARY_PATH).exists());
+    assert!(launch::is_version_installed(path, VERSION, true).await);
+    let manifest = std::fs::read_to_string(&manifest_path).unwrap();
+    assert!(!manifest.contains("fabric-loader"));
+}
+
+#[tokio::test]
+async fn loader_is_not_uninstalled_without_a_manifest() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    launch::install_minecraft(Some(mock.launch_options(path))).await.unwrap();
+    assert!(!path.join("versions/1.20.1/download_manifest.json").exists());
+
+    let error = launch::uninstall_loader(path, VERSION).await.unwrap_err();
+    assert!(error.downcast_ref::<MissingDownloadManifest>().is_some());
+}
+
+#[tokio::test]
+async fn corrupt_client_jar_is_pinpointed() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    let client_jar = path.join("versions/1.20.1/1.20.1.jar");
+
+    launch::install_minecraft(Some(mock.launch_options(path))).await.unwrap();
+    launch::verify_client_jar(path, VERSION).await.unwrap();
+
+    std::fs::write(&client_jar, b"corrupted").unwrap();
+    let error = launch::verify_client_jar(path, VERSION).await.unwrap_err();
+    let corrupt = error.downcast_ref::<CorruptClientJar>().expect("corrupt client jar error");
+    assert_eq!(corrupt.expected_sha1, common::sha1(CLIENT_JAR));
+    assert!(!corrupt.missing);
+
+    std::fs::remove_file(&client_jar).unwrap();
+    let error = launch::verify_client_jar(path, VERSION).await.unwrap_err();
+    assert!(error.downcast_ref::<CorruptClientJar>().expect("corrupt client jar error").missing);
+}
+
+#[tokio::test]
+async fn installed_runtimes_are_listed() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path();
+    assert!(launch::list_installed_runtimes(path).is_empty());
+
+    launch::install_minecraft(Some(mock.launch_options(path))).await.unwrap();
+    std::fs::create_dir_all(path.join("runtime/jre-21.0.3-linux")).unwrap();
+
+    let runtimes = launch::list_installed_runtimes(path);
+    assert_eq!(runtimes.len(), 1);
+    assert_eq!(runtimes[0].version, JAVA_VERSION);
+    assert!(runtimes[0].java.is_file());
+}
+
+#[tokio::test]
+async fn launch_command_is_built_without_side_effects() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().to_path_buf();
+
+    let (java, arguments) = launch::build_launch_command(&path, VERSION, Some(mock.launch_options(&path))).await.unwrap();
+    assert!(java.starts_with(&path));
+    assert!(arguments.contains(&"net.minecraft.client.main.Main".to_string()));
+    assert_eq!(std::fs::read_dir(&path).unwrap().count(), 0);
+
+    // A loader that isn't installed yet isn't installed either
+    let error = launch::build_launch_command(&path, VERSION, Some(LaunchMetadata {
+        loader: Some(launch::Loader {
+            type_: "fabric".to_string(),
+            version: String::new(),
+            build: "latest".to_string(),
+            path: None,
+            enable: Some(true),
+        }),
+        ..mock.launch_options(&path)
+    })).await.unwrap_err();
+    assert!(matches!(error.downcast_ref::<LoaderError>(), Some(LoaderError::NotInstalled { .. })));
+    assert_eq!(std::fs::read_dir(&path).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn server_is_launched_from_its_jar() {
+    let mock = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().to_path_buf();
+
+    let (_, arguments) = launch::build_launch_command(&path, VERSION, Some(LaunchMetadata {
+        kind: InstanceKind::Server,
+        jvm_arguments: Some("-Xmx4G -Dlog4j2.formatMsgNoLookups=true".to_string()),
+        authenticator: Some(launch::Authenticator::offline("Player")),
+        ..mock.launch_options(&path)
+    })).await.unwrap();
+    let server_jar = path.join("versions/1.20.1/1.20.1-server.jar").to_string_lossy().to_string();
+    assert_eq!(arguments[arguments.len() - 3..], ["-jar".to_string(), server_jar, "nogui".to_string()]);
+    assert_eq!(arguments.iter().filter(|argument| argument.starts_with("-Xmx")).collect::<Vec<_>>(), ["-Xmx4G"]);
+    assert!(!arguments.iter().any(|argument| argument == "Player" || argument == "net.minecraft.client.main.Main"));
+}
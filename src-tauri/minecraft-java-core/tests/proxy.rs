@@ -0,0 +1,32 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{MojangMock, VERSION};
+use minecraft_java_core::launch::{self, Endpoints, HttpConfig, LauncherConfig, RetryPolicy};
+
+// The http config is shared by the whole process, this file must hold a single test
+#[tokio::test]
+async fn metadata_fetches_go_through_the_configured_proxy() {
+    // The mock answers by path, whatever host the proxied request targets
+    let proxy = MojangMock::start().await;
+    let directory = tempfile::tempdir().unwrap();
+    let config = LauncherConfig {
+        base_path: directory.path().to_path_buf(),
+        // Unresolvable, only reachable through the proxy
+        endpoints: Endpoints::with_base("http://mojang.invalid"),
+        http: HttpConfig {
+            proxy: Some(proxy.server.uri()),
+            retry: RetryPolicy { max_retries: 0, initial_backoff: Duration::ZERO, max_backoff: Duration::ZERO },
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let options = config.launch_metadata(VERSION).unwrap();
+
+    let size = launch::estimate_download_size(VERSION, Some(options)).await.unwrap();
+    assert_eq!(size, common::download_size());
+
+    let requests = proxy.server.received_requests().await.unwrap();
+    assert!(requests.iter().all(|request| request.url.host_str() == Some("mojang.invalid")), "{:?}", requests);
+}
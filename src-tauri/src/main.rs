@@ -1,22 +1,38 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use tauri::Manager;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use minecraft_java_core::launch::{self, LaunchMetadata, VersionInfo};
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
+
+/// Gives up on the game window after this long, a modded first start can be slow
+const SPLASHSCREEN_TIMEOUT: Duration = Duration::from_secs(180);
+
+#[derive(Clone, Serialize)]
+struct DownloadProgress {
+    downloaded: u64,
+    total: u64,
+}
 
 fn main() {
     tauri::Builder::default()
-        .setup(|app|{
-            let main_window = app.get_window("main").unwrap();
-            main_window.set_skip_taskbar(true)?;
-        //     let splashscreen_window = tauri::WindowBuilder::new(
-        //         app,
-        //         "splashscreen",
-        //         tauri::WindowUrl::default(),
-        //     ).build()?;
+        .setup(|_app|{
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
           my_custom_command,
+          set_skip_taskbar,
+          list_versions,
+          install_version,
+          launch_version,
+          kill_instance,
+          stop_instance,
+          is_instance_running,
         ])
         // .plugin()
         .run(tauri::generate_context!())
@@ -29,13 +45,103 @@ fn my_custom_command(version: String) {
     // File::create("test.txt").unwrap();
 }
 
-// #[tauri::command]
-// async fn window_create(handle: AppHandle) {
-//     println!("Minimizing window");
-//     let splashscreen_window = tauri::WindowBuilder::new(
-//         &handle,
-//         "splashscreen",
-//         tauri::WindowUrl::default(),
-//     ).build();
-//     // tauri::Window::get_window(&(), "").unwrap().minimize().expect("TODO: panic message");
-// }
+#[tauri::command]
+fn set_skip_taskbar(window: tauri::Window, skip: bool) -> Result<(), String> {
+    window.set_skip_taskbar(skip).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn list_versions(types: Option<Vec<String>>) -> Result<Vec<VersionInfo>, String> {
+    launch::get_version_list(types, None).await.map_err(|e| e.to_string())
+}
+
+/// Instances folder picked by the user, the default one otherwise
+fn get_base_path(base_path: Option<PathBuf>) -> PathBuf {
+    base_path.unwrap_or_else(|| LaunchMetadata::default().path)
+}
+
+#[tauri::command]
+async fn install_version(version: String, base_path: Option<PathBuf>) -> Result<(), String> {
+    launch::install_minecraft(Some(LaunchMetadata {
+        path: get_base_path(base_path),
+        version,
+        ..Default::default()
+    })).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn launch_version(app: AppHandle, window: tauri::Window, version: String, instance_name: Option<String>, base_path: Option<PathBuf>) -> Result<u32, String> {
+    let splashscreen = match app.get_window("splashscreen") {
+        Some(splashscreen) => splashscreen,
+        None => tauri::WindowBuilder::new(
+            &app,
+            "splashscreen",
+            tauri::WindowUrl::App("splashscreen".into()),
+        )
+            .title("RustMine")
+            .inner_size(400.0, 200.0)
+            .center()
+            .decorations(false)
+            .build()
+            .map_err(|e| e.to_string())?,
+    };
+
+    let progress_splashscreen = splashscreen.clone();
+    let ready_splashscreen = splashscreen.clone();
+    let window_ready = Arc::new(AtomicBool::new(false));
+    let ready_flag = window_ready.clone();
+    let result = launch::launch_minecraft(Some(LaunchMetadata {
+        path: get_base_path(base_path),
+        version,
+        instance_name,
+        on_progress: Some(Arc::new(move |downloaded: u64, total: u64| {
+            let _ = progress_splashscreen.emit("download-progress", DownloadProgress { downloaded, total });
+        })),
+        on_window_ready: Some(Arc::new(move |name: &str| {
+            ready_flag.store(true, Ordering::SeqCst);
+            let _ = window.emit("game-window-ready", name.to_string());
+            let _ = ready_splashscreen.close();
+        })),
+        ..Default::default()
+    })).await;
+
+    match result {
+        Ok(instance) => {
+            close_splashscreen_on_exit(splashscreen, instance.name, window_ready);
+            Ok(instance.pid)
+        },
+        Err(e) => {
+            let _ = splashscreen.close();
+            Err(e.to_string())
+        },
+    }
+}
+
+/// The game can exit or hang before its window shows up, the splashscreen must not outlive it
+fn close_splashscreen_on_exit(splashscreen: tauri::Window, name: String, window_ready: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let started = Instant::now();
+        while !window_ready.load(Ordering::SeqCst) {
+            if !launch::is_running(&name) || started.elapsed() > SPLASHSCREEN_TIMEOUT {
+                let _ = splashscreen.close();
+                return;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+    });
+}
+
+#[tauri::command]
+fn kill_instance(name: String) -> Result<(), String> {
+    launch::kill_instance(&name).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn stop_instance(name: String) -> Result<(), String> {
+    launch::stop_instance(&name, None).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn is_instance_running(name: String) -> bool {
+    launch::is_running(&name)
+}